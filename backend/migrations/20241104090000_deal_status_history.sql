@@ -0,0 +1,17 @@
+-- Only new writes are checked, so rows with legacy statuses don't block the migration.
+ALTER TABLE deals
+    ADD CONSTRAINT deals_status_check
+    CHECK (status IN ('open', 'ready', 'awaitingresponse', 'closed')) NOT VALID;
+
+CREATE TABLE IF NOT EXISTS deal_status_history (
+    id SERIAL PRIMARY KEY,
+    deal_id int NOT NULL,
+    from_status VARCHAR NULL,
+    to_status VARCHAR NOT NULL,
+    changed_by int NULL,
+    changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_deal FOREIGN KEY (deal_id) REFERENCES deals (id) ON DELETE CASCADE,
+    CONSTRAINT fk_user FOREIGN KEY (changed_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS deal_status_history_deal_id_idx ON deal_status_history (deal_id);
@@ -0,0 +1,15 @@
+-- `closed` was always written as the literal 'closed'; closed_at and
+-- expected_close_date replace it, so new deals leave it empty.
+ALTER TABLE deals ALTER COLUMN closed DROP NOT NULL;
+
+ALTER TABLE deals
+    ADD COLUMN IF NOT EXISTS title VARCHAR(255) NULL,
+    ADD COLUMN IF NOT EXISTS description TEXT NULL,
+    ADD COLUMN IF NOT EXISTS expected_close_date DATE NULL,
+    ADD COLUMN IF NOT EXISTS closed_at TIMESTAMP WITH TIME ZONE NULL;
+
+ALTER TABLE deals
+    ADD CONSTRAINT deals_worth_check
+    CHECK (estimate_worth >= 0 AND (actual_worth IS NULL OR actual_worth >= 0)) NOT VALID;
+
+UPDATE deals SET closed_at = last_updated WHERE status = 'closed' AND closed_at IS NULL;
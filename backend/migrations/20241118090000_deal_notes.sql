@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS deal_notes (
+    id SERIAL PRIMARY KEY,
+    deal_id int NOT NULL,
+    author_id int NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_deal FOREIGN KEY (deal_id) REFERENCES deals (id) ON DELETE CASCADE,
+    CONSTRAINT fk_user FOREIGN KEY (author_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS deal_notes_deal_id_idx ON deal_notes (deal_id);
@@ -0,0 +1,4 @@
+CREATE INDEX IF NOT EXISTS deals_owner_id_created_at_idx ON deals (owner_id, created_at, id);
+CREATE INDEX IF NOT EXISTS deals_owner_id_last_updated_idx ON deals (owner_id, last_updated, id);
+CREATE INDEX IF NOT EXISTS deals_owner_id_status_idx ON deals (owner_id, status);
+CREATE INDEX IF NOT EXISTS deals_customer_id_idx ON deals (customer_id);
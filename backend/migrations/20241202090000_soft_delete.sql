@@ -0,0 +1,14 @@
+UPDATE deals SET is_archived = FALSE WHERE is_archived IS NULL;
+ALTER TABLE deals
+    ALTER COLUMN is_archived SET NOT NULL,
+    ADD COLUMN IF NOT EXISTS archived_at TIMESTAMP WITH TIME ZONE NULL,
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE NULL;
+
+UPDATE customers SET is_archived = FALSE WHERE is_archived IS NULL;
+ALTER TABLE customers
+    ALTER COLUMN is_archived SET NOT NULL,
+    ADD COLUMN IF NOT EXISTS archived_at TIMESTAMP WITH TIME ZONE NULL,
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE NULL;
+
+CREATE INDEX IF NOT EXISTS deals_deleted_at_idx ON deals (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS customers_deleted_at_idx ON customers (deleted_at) WHERE deleted_at IS NOT NULL;
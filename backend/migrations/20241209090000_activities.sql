@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS activities (
+    id SERIAL PRIMARY KEY,
+    owner_id int NOT NULL,
+    customer_id int NOT NULL,
+    deal_id int NULL,
+    kind VARCHAR NOT NULL CHECK (kind IN ('call', 'meeting', 'email', 'note', 'task')),
+    subject VARCHAR NOT NULL,
+    body TEXT NULL,
+    due_at TIMESTAMP WITH TIME ZONE NULL,
+    completed BOOLEAN NOT NULL DEFAULT FALSE,
+    completed_at TIMESTAMP WITH TIME ZONE NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE,
+    CONSTRAINT fk_customer FOREIGN KEY (customer_id) REFERENCES customers (id) ON DELETE CASCADE,
+    CONSTRAINT fk_deal FOREIGN KEY (deal_id) REFERENCES deals (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS activities_customer_id_idx ON activities (customer_id);
+CREATE INDEX IF NOT EXISTS activities_deal_id_idx ON activities (deal_id);
+CREATE INDEX IF NOT EXISTS activities_open_tasks_idx ON activities (owner_id, due_at) WHERE kind = 'task' AND NOT completed;
@@ -0,0 +1,56 @@
+CREATE TABLE IF NOT EXISTS custom_field_definitions (
+    id SERIAL PRIMARY KEY,
+    owner_id int NOT NULL,
+    entity VARCHAR NOT NULL CHECK (entity IN ('customer', 'deal')),
+    key VARCHAR NOT NULL,
+    label VARCHAR NOT NULL,
+    field_type VARCHAR NOT NULL CHECK (field_type IN ('text', 'number', 'date', 'select')),
+    options TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE,
+    CONSTRAINT custom_field_definitions_key_unique UNIQUE (owner_id, entity, key)
+);
+
+CREATE TABLE IF NOT EXISTS customer_field_values (
+    customer_id int NOT NULL,
+    definition_id int NOT NULL,
+    value JSONB NOT NULL,
+    PRIMARY KEY (customer_id, definition_id),
+    CONSTRAINT fk_customer FOREIGN KEY (customer_id) REFERENCES customers (id) ON DELETE CASCADE,
+    CONSTRAINT fk_definition FOREIGN KEY (definition_id) REFERENCES custom_field_definitions (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS deal_field_values (
+    deal_id int NOT NULL,
+    definition_id int NOT NULL,
+    value JSONB NOT NULL,
+    PRIMARY KEY (deal_id, definition_id),
+    CONSTRAINT fk_deal FOREIGN KEY (deal_id) REFERENCES deals (id) ON DELETE CASCADE,
+    CONSTRAINT fk_definition FOREIGN KEY (definition_id) REFERENCES custom_field_definitions (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS customer_field_values_definition_idx ON customer_field_values (definition_id);
+CREATE INDEX IF NOT EXISTS deal_field_values_definition_idx ON deal_field_values (definition_id);
+
+-- Tags are stored lowercased so filtering doesn't depend on how they were typed.
+ALTER TABLE customers ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE deals ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS customers_tags_idx ON customers USING GIN (tags);
+CREATE INDEX IF NOT EXISTS deals_tags_idx ON deals USING GIN (tags);
+
+-- A record's custom field values as one `{key: value}` object, so list and
+-- detail queries can select them alongside the record's own columns.
+CREATE OR REPLACE FUNCTION customer_custom_fields(customer int) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_object_agg(f.key, v.value), '{}'::jsonb)
+    FROM customer_field_values v
+    JOIN custom_field_definitions f ON f.id = v.definition_id
+    WHERE v.customer_id = customer
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION deal_custom_fields(deal int) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_object_agg(f.key, v.value), '{}'::jsonb)
+    FROM deal_field_values v
+    JOIN custom_field_definitions f ON f.id = v.definition_id
+    WHERE v.deal_id = deal
+$$ LANGUAGE SQL STABLE;
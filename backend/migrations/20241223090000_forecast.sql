@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS forecast_probabilities (
+    owner_id int NOT NULL,
+    status VARCHAR NOT NULL CHECK (status IN ('open', 'ready', 'awaitingresponse')),
+    probability DOUBLE PRECISION NOT NULL CHECK (probability >= 0 AND probability <= 1),
+    PRIMARY KEY (owner_id, status),
+    CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- The weighted forecast for a month as it stood before the month began, so it
+-- can later be compared with what actually closed.
+CREATE TABLE IF NOT EXISTS forecast_snapshots (
+    owner_id int NOT NULL,
+    period DATE NOT NULL,
+    pipeline_value BIGINT NOT NULL,
+    weighted_value DOUBLE PRECISION NOT NULL,
+    taken_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (owner_id, period),
+    CONSTRAINT fk_owner FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,58 @@
+CREATE TABLE IF NOT EXISTS organizations (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS teams (
+    id SERIAL PRIMARY KEY,
+    organization_id int NOT NULL,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_organization FOREIGN KEY (organization_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    CONSTRAINT teams_name_unique UNIQUE (organization_id, name)
+);
+
+-- A user belongs to at most one organization. `visibility` decides whose
+-- customers and deals they can see: only their own, their team's, or the
+-- whole organization's.
+CREATE TABLE IF NOT EXISTS organization_members (
+    user_id int PRIMARY KEY,
+    organization_id int NOT NULL,
+    team_id int NULL,
+    role VARCHAR NOT NULL CHECK (role IN ('owner', 'admin', 'member')),
+    visibility VARCHAR NOT NULL CHECK (visibility IN ('own', 'team', 'org')),
+    joined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    CONSTRAINT fk_organization FOREIGN KEY (organization_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    CONSTRAINT fk_team FOREIGN KEY (team_id) REFERENCES teams (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS organization_members_organization_idx ON organization_members (organization_id);
+
+-- Records of a user in an organization belong to the organization; owner_id
+-- is the rep they are assigned to. Records without an organization stay
+-- private to their owner.
+ALTER TABLE customers ADD COLUMN IF NOT EXISTS organization_id int NULL REFERENCES organizations (id) ON DELETE SET NULL;
+ALTER TABLE deals ADD COLUMN IF NOT EXISTS organization_id int NULL REFERENCES organizations (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS customers_organization_idx ON customers (organization_id);
+CREATE INDEX IF NOT EXISTS deals_organization_idx ON deals (organization_id);
+
+-- Whether `viewer` may see a record assigned to `owner` in `org`.
+CREATE OR REPLACE FUNCTION visible_to(viewer int, owner int, org int) RETURNS boolean AS $$
+    SELECT CASE
+        WHEN org IS NULL THEN owner = viewer
+        ELSE EXISTS (
+            SELECT 1 FROM organization_members me
+            WHERE me.user_id = viewer AND me.organization_id = org AND (
+                owner = viewer
+                OR me.visibility = 'org'
+                OR (me.visibility = 'team' AND me.team_id IS NOT NULL AND EXISTS (
+                    SELECT 1 FROM organization_members rep
+                    WHERE rep.user_id = owner AND rep.organization_id = org AND rep.team_id = me.team_id
+                ))
+            )
+        )
+    END
+$$ LANGUAGE SQL STABLE;
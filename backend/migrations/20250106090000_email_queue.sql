@@ -0,0 +1,20 @@
+-- Outgoing emails and mailing list changes, sent by a background worker.
+-- Failed jobs are retried with exponential backoff until they run out of
+-- attempts or fail permanently, at which point they are parked as 'dead'
+-- until someone retries them.
+CREATE TABLE IF NOT EXISTS email_queue (
+    id SERIAL PRIMARY KEY,
+    kind VARCHAR NOT NULL CHECK (kind IN ('email', 'list_member')),
+    payload JSONB NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sending', 'sent', 'dead')),
+    attempts int NOT NULL DEFAULT 0,
+    max_attempts int NOT NULL DEFAULT 8,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_at TIMESTAMP WITH TIME ZONE NULL,
+    last_error TEXT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    sent_at TIMESTAMP WITH TIME ZONE NULL
+);
+
+CREATE INDEX IF NOT EXISTS email_queue_due_idx ON email_queue (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS email_queue_status_idx ON email_queue (status, created_at DESC);
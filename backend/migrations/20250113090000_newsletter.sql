@@ -0,0 +1,30 @@
+-- Newsletter subscriptions use double opt-in: an address stays 'pending'
+-- until its owner opens the confirmation link, and only then is it added to
+-- the mailing list. Only a hash of the confirmation token is stored.
+CREATE TABLE IF NOT EXISTS newsletter_subscriptions (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR NOT NULL,
+    status VARCHAR NOT NULL CHECK (status IN ('pending', 'subscribed', 'unsubscribed')),
+    token_hash VARCHAR NULL,
+    token_expires_at TIMESTAMP WITH TIME ZONE NULL,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    confirmed_at TIMESTAMP WITH TIME ZONE NULL,
+    unsubscribed_at TIMESTAMP WITH TIME ZONE NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS newsletter_subscriptions_email_idx ON newsletter_subscriptions (lower(email));
+CREATE UNIQUE INDEX IF NOT EXISTS newsletter_subscriptions_token_idx ON newsletter_subscriptions (token_hash);
+
+-- Append-only record of every consent change, with where it came from, so
+-- consent can be proven later. Rows are never updated or deleted.
+CREATE TABLE IF NOT EXISTS newsletter_consent_events (
+    id SERIAL PRIMARY KEY,
+    subscription_id int NOT NULL,
+    event VARCHAR NOT NULL CHECK (event IN ('requested', 'confirmed', 'unsubscribed')),
+    ip_address VARCHAR NULL,
+    user_agent VARCHAR NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_subscription FOREIGN KEY (subscription_id) REFERENCES newsletter_subscriptions (id)
+);
+
+CREATE INDEX IF NOT EXISTS newsletter_consent_events_subscription_idx ON newsletter_consent_events (subscription_id, created_at);
@@ -0,0 +1,21 @@
+-- Emails received through the Mailgun inbound route. Messages whose sender
+-- matches a customer are logged as email activities on that customer; the
+-- rest wait in triage until an admin assigns or dismisses them.
+CREATE TABLE IF NOT EXISTS inbound_emails (
+    id SERIAL PRIMARY KEY,
+    message_id VARCHAR NULL,
+    sender VARCHAR NOT NULL,
+    recipient VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    body TEXT NULL,
+    -- [{ "filename", "content_type", "size" }]
+    attachments JSONB NOT NULL DEFAULT '[]',
+    status VARCHAR NOT NULL CHECK (status IN ('matched', 'triage', 'dismissed')),
+    customer_ids int[] NOT NULL DEFAULT '{}',
+    received_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Mailgun retries deliveries it thinks failed, so the same message can
+-- arrive twice.
+CREATE UNIQUE INDEX IF NOT EXISTS inbound_emails_message_id_idx ON inbound_emails (message_id);
+CREATE INDEX IF NOT EXISTS inbound_emails_triage_idx ON inbound_emails (received_at DESC) WHERE status = 'triage';
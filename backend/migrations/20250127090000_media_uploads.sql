@@ -0,0 +1,14 @@
+-- Files uploaded for profiles through the streaming upload endpoint.
+-- Profiles reference them by URL, and the MIME type recorded here is what
+-- decides whether a cover is shown as an image or a video.
+CREATE TABLE IF NOT EXISTS media_uploads (
+    id SERIAL PRIMARY KEY,
+    url VARCHAR NOT NULL UNIQUE,
+    username VARCHAR NOT NULL,
+    purpose VARCHAR NOT NULL CHECK (purpose IN ('profile', 'cover')),
+    mime_type VARCHAR NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS media_uploads_username_idx ON media_uploads (username);
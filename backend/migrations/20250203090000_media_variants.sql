@@ -0,0 +1,3 @@
+-- Images are stored as resized variants in several formats. The upload's
+-- URL is its full-size JPEG, and this lists every variant.
+ALTER TABLE media_uploads ADD COLUMN IF NOT EXISTS variants JSONB;
@@ -0,0 +1,3 @@
+-- Video covers are probed when they are uploaded. This holds their
+-- duration, size, codec and poster frame.
+ALTER TABLE media_uploads ADD COLUMN IF NOT EXISTS video JSONB;
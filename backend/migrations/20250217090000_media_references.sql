@@ -0,0 +1,6 @@
+-- When the last profile stopped linking to an upload, or NULL while one
+-- still does. Uploads that were never linked count from when they were
+-- made, and are removed once this is older than the grace period.
+ALTER TABLE media_uploads ADD COLUMN IF NOT EXISTS unreferenced_since TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS media_uploads_unreferenced_since_idx ON media_uploads (unreferenced_since);
@@ -0,0 +1,3 @@
+-- Files can now be uploaded for profile galleries too.
+ALTER TABLE media_uploads DROP CONSTRAINT IF EXISTS media_uploads_purpose_check;
+ALTER TABLE media_uploads ADD CONSTRAINT media_uploads_purpose_check CHECK (purpose IN ('profile', 'cover', 'gallery'));
@@ -0,0 +1,9 @@
+-- Whether `editor` may change, archive, trash, restore or merge a record
+-- assigned to `owner` in `org`: its rep, or an owner or admin of the
+-- organization. Other members who can see it only read it.
+CREATE OR REPLACE FUNCTION editable_by(editor int, owner int, org int) RETURNS boolean AS $$
+    SELECT owner = editor OR (org IS NOT NULL AND EXISTS (
+        SELECT 1 FROM organization_members me
+        WHERE me.user_id = editor AND me.organization_id = org AND me.role IN ('owner', 'admin')
+    ))
+$$ LANGUAGE SQL STABLE;
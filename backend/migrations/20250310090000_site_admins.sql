@@ -0,0 +1,8 @@
+-- Site-wide admins, who can manage the email queue, the inbound email triage
+-- inbox and the media orphan report. No endpoint grants this, so it is only
+-- ever inserted directly in the database. `users.role` can be set at sign-up
+-- and isn't trusted for it.
+CREATE TABLE IF NOT EXISTS site_admins (
+    user_id int PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    granted_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
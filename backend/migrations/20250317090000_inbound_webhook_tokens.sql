@@ -0,0 +1,10 @@
+-- Tokens from signed Mailgun webhook requests we have already accepted. A
+-- request reusing one is a replay and is refused. Tokens only need to be
+-- kept while their signature is recent enough to be accepted, so the purge
+-- task clears out older ones.
+CREATE TABLE IF NOT EXISTS inbound_webhook_tokens (
+    token VARCHAR PRIMARY KEY,
+    seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS inbound_webhook_tokens_seen_at_idx ON inbound_webhook_tokens (seen_at);
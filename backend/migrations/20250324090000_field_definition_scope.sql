@@ -0,0 +1,78 @@
+-- Custom fields follow their records: definitions of a user in an
+-- organization belong to the organization, and only private records use their
+-- owner's own definitions. Keys are unique within each of those scopes.
+ALTER TABLE custom_field_definitions ADD COLUMN IF NOT EXISTS organization_id int NULL REFERENCES organizations (id) ON DELETE SET NULL;
+ALTER TABLE custom_field_definitions DROP CONSTRAINT IF EXISTS custom_field_definitions_key_unique;
+
+-- Moves `member`'s private definitions into `org`. Where the organization
+-- already has a field with the same key, values move to its definition,
+-- without replacing values a record already has, and the private one goes.
+CREATE OR REPLACE FUNCTION move_field_definitions(member int, org int) RETURNS void AS $$
+    INSERT INTO customer_field_values (customer_id, definition_id, value)
+    SELECT v.customer_id, o.id, v.value
+    FROM customer_field_values v
+    JOIN custom_field_definitions p ON p.id = v.definition_id
+    JOIN custom_field_definitions o ON o.organization_id = org AND o.entity = p.entity AND o.key = p.key
+    WHERE p.owner_id = member AND p.organization_id IS NULL
+    ON CONFLICT (customer_id, definition_id) DO NOTHING;
+
+    INSERT INTO deal_field_values (deal_id, definition_id, value)
+    SELECT v.deal_id, o.id, v.value
+    FROM deal_field_values v
+    JOIN custom_field_definitions p ON p.id = v.definition_id
+    JOIN custom_field_definitions o ON o.organization_id = org AND o.entity = p.entity AND o.key = p.key
+    WHERE p.owner_id = member AND p.organization_id IS NULL
+    ON CONFLICT (deal_id, definition_id) DO NOTHING;
+
+    DELETE FROM custom_field_definitions p
+    WHERE p.owner_id = member AND p.organization_id IS NULL AND EXISTS (
+        SELECT 1 FROM custom_field_definitions o
+        WHERE o.organization_id = org AND o.entity = p.entity AND o.key = p.key
+    );
+
+    UPDATE custom_field_definitions SET organization_id = org
+    WHERE owner_id = member AND organization_id IS NULL;
+$$ LANGUAGE SQL;
+
+-- Earlier members' definitions win when two members used the same key.
+DO $$
+DECLARE
+    m record;
+BEGIN
+    FOR m IN SELECT user_id, organization_id FROM organization_members ORDER BY joined_at, user_id LOOP
+        PERFORM move_field_definitions(m.user_id, m.organization_id);
+    END LOOP;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS custom_field_definitions_private_key_unique
+    ON custom_field_definitions (owner_id, entity, key) WHERE organization_id IS NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS custom_field_definitions_organization_key_unique
+    ON custom_field_definitions (organization_id, entity, key) WHERE organization_id IS NOT NULL;
+
+-- Whether a definition created by `def_owner` in `def_org` applies to a
+-- record assigned to `owner` in `org`.
+CREATE OR REPLACE FUNCTION field_defined_for(def_owner int, def_org int, owner int, org int) RETURNS boolean AS $$
+    SELECT CASE
+        WHEN org IS NULL THEN def_org IS NULL AND def_owner = owner
+        ELSE def_org = org
+    END
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION customer_custom_fields(customer int) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_object_agg(f.key, v.value), '{}'::jsonb)
+    FROM customer_field_values v
+    JOIN custom_field_definitions f ON f.id = v.definition_id
+    JOIN customers c ON c.id = v.customer_id
+    WHERE v.customer_id = customer
+        AND field_defined_for(f.owner_id, f.organization_id, c.owner_id, c.organization_id)
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION deal_custom_fields(deal int) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_object_agg(f.key, v.value), '{}'::jsonb)
+    FROM deal_field_values v
+    JOIN custom_field_definitions f ON f.id = v.definition_id
+    JOIN deals d ON d.id = v.deal_id
+    WHERE v.deal_id = deal
+        AND field_defined_for(f.owner_id, f.organization_id, d.owner_id, d.organization_id)
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,420 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::auth::SessionUser;
+use crate::organizations::present;
+use crate::AppState;
+
+const ACTIVITY_COLUMNS: &str = "a.id, a.kind, a.subject, a.body, a.customer_id, a.deal_id, a.due_at, a.completed, a.completed_at, a.created_at, a.updated_at, u.email AS owner";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    Call,
+    Meeting,
+    Email,
+    Note,
+    Task,
+}
+
+impl ActivityKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivityKind::Call => "call",
+            ActivityKind::Meeting => "meeting",
+            ActivityKind::Email => "email",
+            ActivityKind::Note => "note",
+            ActivityKind::Task => "task",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct Activity {
+    pub id: i32,
+    pub kind: String,
+    pub subject: String,
+    pub body: Option<String>,
+    pub customer_id: i32,
+    pub deal_id: Option<i32>,
+    pub due_at: Option<DateTime<Utc>>,
+    pub completed: bool,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewActivity {
+    pub kind: ActivityKind,
+    pub subject: String,
+    pub body: Option<String>,
+    pub customer_id: i32,
+    pub deal_id: Option<i32>,
+    pub due_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub completed: bool,
+}
+
+/// Partial update of an activity. Fields left out of the request are
+/// unchanged; `null` clears the body, the deal or the due date.
+#[derive(Deserialize)]
+pub struct ActivityUpdate {
+    pub kind: Option<ActivityKind>,
+    pub subject: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub body: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub deal_id: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub due_at: Option<Option<DateTime<Utc>>>,
+    pub completed: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ActivityFilter {
+    pub customer_id: Option<i32>,
+    pub deal_id: Option<i32>,
+    pub kind: Option<ActivityKind>,
+    pub completed: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct UpcomingQuery {
+    /// How far ahead to look. Overdue tasks are always included.
+    #[serde(default = "default_upcoming_days")]
+    pub days: i32,
+}
+
+fn default_upcoming_days() -> i32 {
+    7
+}
+
+pub async fn get_activities(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(filter): Query<ActivityFilter>,
+) -> Result<Json<Vec<Activity>>, impl IntoResponse> {
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        "SELECT {ACTIVITY_COLUMNS} FROM activities a LEFT JOIN users u ON u.id = a.owner_id WHERE a.owner_id = "
+    ));
+    query.push_bind(user.id);
+    if let Some(customer_id) = filter.customer_id {
+        query.push(" AND a.customer_id = ");
+        query.push_bind(customer_id);
+    }
+    if let Some(deal_id) = filter.deal_id {
+        query.push(" AND a.deal_id = ");
+        query.push_bind(deal_id);
+    }
+    if let Some(kind) = filter.kind {
+        query.push(" AND a.kind = ");
+        query.push_bind(kind.as_str());
+    }
+    if let Some(completed) = filter.completed {
+        query.push(" AND a.completed = ");
+        query.push_bind(completed);
+    }
+    query.push(" ORDER BY a.created_at DESC, a.id DESC");
+
+    match query
+        .build_query_as::<Activity>()
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Open tasks for the session user that are overdue or due within `days`.
+pub async fn get_upcoming_tasks(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<UpcomingQuery>,
+) -> Result<Json<Vec<Activity>>, impl IntoResponse> {
+    match sqlx::query_as::<_, Activity>(&format!(
+        "SELECT {ACTIVITY_COLUMNS} FROM activities a LEFT JOIN users u ON u.id = a.owner_id
+        WHERE a.owner_id = $1 AND a.kind = 'task' AND NOT a.completed
+        AND a.due_at IS NOT NULL AND a.due_at <= NOW() + make_interval(days => $2)
+        ORDER BY a.due_at, a.id"
+    ))
+    .bind(user.id)
+    .bind(query.days.clamp(0, 365))
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn get_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<Json<Activity>, impl IntoResponse> {
+    match fetch_activity(&state.postgres, user.id, id).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Activity not found".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn create_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<NewActivity>,
+) -> Result<(StatusCode, Json<Activity>), impl IntoResponse> {
+    if req.subject.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "subject must not be empty".to_string(),
+        ));
+    }
+    check_links(&state, user.id, req.customer_id, req.deal_id).await?;
+
+    let id = match sqlx::query_scalar::<_, i32>("INSERT INTO activities (owner_id, customer_id, deal_id, kind, subject, body, due_at, completed, completed_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, CASE WHEN $8 THEN NOW() ELSE NULL END) RETURNING id")
+        .bind(user.id)
+        .bind(req.customer_id)
+        .bind(req.deal_id)
+        .bind(req.kind.as_str())
+        .bind(req.subject.trim())
+        .bind(&req.body)
+        .bind(req.due_at)
+        .bind(req.completed)
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(id) => id,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match fetch_activity(&state.postgres, user.id, id).await {
+        Ok(Some(res)) => Ok((StatusCode::CREATED, Json(res))),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Activity not found".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn update_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<ActivityUpdate>,
+) -> Result<Json<Activity>, impl IntoResponse> {
+    if req.subject.as_deref().is_some_and(|s| s.trim().is_empty()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "subject must not be empty".to_string(),
+        ));
+    }
+
+    let customer_id = match sqlx::query_scalar::<_, i32>(
+        "SELECT customer_id FROM activities WHERE owner_id = $1 AND id = $2",
+    )
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(Some(customer_id)) => customer_id,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Activity not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    if let Some(Some(deal_id)) = req.deal_id {
+        check_links(&state, user.id, customer_id, Some(deal_id)).await?;
+    }
+
+    let mut update = QueryBuilder::<Postgres>::new("UPDATE activities SET updated_at = NOW()");
+    if let Some(kind) = req.kind {
+        update.push(", kind = ");
+        update.push_bind(kind.as_str());
+    }
+    if let Some(subject) = &req.subject {
+        update.push(", subject = ");
+        update.push_bind(subject.trim().to_string());
+    }
+    if let Some(body) = &req.body {
+        update.push(", body = ");
+        update.push_bind(body.clone());
+    }
+    if let Some(deal_id) = req.deal_id {
+        update.push(", deal_id = ");
+        update.push_bind(deal_id);
+    }
+    if let Some(due_at) = req.due_at {
+        update.push(", due_at = ");
+        update.push_bind(due_at);
+    }
+    if let Some(completed) = req.completed {
+        update.push(", completed_at = CASE WHEN ");
+        update.push_bind(completed);
+        update.push(" AND NOT completed THEN NOW() WHEN NOT ");
+        update.push_bind(completed);
+        update.push(" THEN NULL ELSE completed_at END, completed = ");
+        update.push_bind(completed);
+    }
+    update.push(" WHERE owner_id = ");
+    update.push_bind(user.id);
+    update.push(" AND id = ");
+    update.push_bind(id);
+
+    if let Err(err) = update.build().execute(&state.postgres).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match fetch_activity(&state.postgres, user.id, id).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Activity not found".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn destroy_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    match sqlx::query("DELETE FROM activities WHERE owner_id = $1 AND id = $2")
+        .bind(user.id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            Err((StatusCode::NOT_FOUND, "Activity not found".to_string()))
+        }
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Activities attached to a deal, newest first.
+pub async fn fetch_deal_activities(
+    postgres: &sqlx::PgPool,
+    deal_id: i32,
+) -> Result<Vec<Activity>, sqlx::Error> {
+    sqlx::query_as::<_, Activity>(&format!(
+        "SELECT {ACTIVITY_COLUMNS} FROM activities a LEFT JOIN users u ON u.id = a.owner_id
+        WHERE a.deal_id = $1
+        ORDER BY a.created_at DESC, a.id DESC"
+    ))
+    .bind(deal_id)
+    .fetch_all(postgres)
+    .await
+}
+
+async fn fetch_activity(
+    postgres: &sqlx::PgPool,
+    owner_id: i32,
+    id: i32,
+) -> Result<Option<Activity>, sqlx::Error> {
+    sqlx::query_as::<_, Activity>(&format!(
+        "SELECT {ACTIVITY_COLUMNS} FROM activities a LEFT JOIN users u ON u.id = a.owner_id WHERE a.owner_id = $1 AND a.id = $2"
+    ))
+    .bind(owner_id)
+    .bind(id)
+    .fetch_optional(postgres)
+    .await
+}
+
+/// Checks that the customer, and the deal if given, belong to the user and
+/// that the deal is one of that customer's.
+async fn check_links(
+    state: &AppState,
+    owner_id: i32,
+    customer_id: i32,
+    deal_id: Option<i32>,
+) -> Result<(), (StatusCode, String)> {
+    match sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM customers WHERE id = $1 AND visible_to($2, owner_id, organization_id) AND deleted_at IS NULL)")
+        .bind(customer_id)
+        .bind(owner_id)
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err((StatusCode::BAD_REQUEST, "Customer not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    let Some(deal_id) = deal_id else {
+        return Ok(());
+    };
+    match sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM deals WHERE id = $1 AND visible_to($2, owner_id, organization_id) AND customer_id = $3 AND deleted_at IS NULL)")
+        .bind(deal_id)
+        .bind(owner_id)
+        .bind(customer_id)
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::BAD_REQUEST,
+            "Deal not found for this customer".to_string(),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_serialize_as_stored() {
+        for kind in [
+            ActivityKind::Call,
+            ActivityKind::Meeting,
+            ActivityKind::Email,
+            ActivityKind::Note,
+            ActivityKind::Task,
+        ] {
+            assert_eq!(serde_json::to_value(kind).unwrap(), kind.as_str());
+            let parsed: ActivityKind = serde_json::from_value(kind.as_str().into()).unwrap();
+            assert_eq!(parsed, kind);
+        }
+        assert!(serde_json::from_str::<ActivityKind>(r#""fax""#).is_err());
+    }
+
+    #[test]
+    fn upcoming_looks_a_week_ahead_by_default() {
+        let query: UpcomingQuery = serde_json::from_str("{}").unwrap();
+        assert_eq!(query.days, 7);
+        let query: UpcomingQuery = serde_json::from_str(r#"{"days": 30}"#).unwrap();
+        assert_eq!(query.days, 30);
+    }
+
+    #[test]
+    fn new_activities_start_open() {
+        let activity: NewActivity =
+            serde_json::from_str(r#"{"kind": "call", "subject": "Follow up", "customer_id": 3}"#)
+                .unwrap();
+        assert_eq!(activity.kind, ActivityKind::Call);
+        assert!(!activity.completed);
+        assert_eq!(activity.deal_id, None);
+    }
+
+    #[test]
+    fn updates_tell_a_cleared_field_from_a_missing_one() {
+        let update: ActivityUpdate =
+            serde_json::from_str(r#"{"body": null, "deal_id": null, "due_at": null}"#).unwrap();
+        assert_eq!(update.body, Some(None));
+        assert_eq!(update.deal_id, Some(None));
+        assert_eq!(update.due_at, Some(None));
+
+        let update: ActivityUpdate =
+            serde_json::from_str(r#"{"deal_id": 9, "subject": "Call back"}"#).unwrap();
+        assert_eq!(update.deal_id, Some(Some(9)));
+        assert_eq!(update.body, None);
+        assert_eq!(update.due_at, None);
+    }
+}
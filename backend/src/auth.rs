@@ -7,16 +7,16 @@ use axum::{
 };
 use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
 use serde::Deserialize;
-use sqlx::Row;
+use sqlx::{PgPool, Row};
 use time::Duration;
 
+use crate::email_queue::enqueue_email;
 use crate::AppState;
 
 #[derive(Deserialize)]
 pub struct RegisterDetails {
     email: String,
     password: String,
-    role: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -25,26 +25,28 @@ pub struct LoginDetails {
     password: String,
 }
 
+/// The user behind the request's session cookie. Added to the request
+/// extensions by `validate_session`, so handlers behind it can extract it.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionUser {
+    pub id: i32,
+}
+
 pub async fn register(
     State(state): State<AppState>,
     Json(newuser): Json<RegisterDetails>,
 ) -> impl IntoResponse {
     let hashed_password = bcrypt::hash(newuser.password, 10).unwrap();
-    let query = match newuser.role {
-        Some(role) => {
-            sqlx::query("INSERT INTO users (email, password, role) VALUES ($1, $2, $3)")
-                .bind(newuser.email)
-                .bind(hashed_password)
-                .bind(role) // Bind the role parameter
-                .execute(&state.postgres)
-        }
-        None => sqlx::query("INSERT INTO users (email, password) VALUES ($1, $2)")
-            .bind(newuser.email)
-            .bind(hashed_password)
-            .execute(&state.postgres),
-    };
+    let query = sqlx::query("INSERT INTO users (email, password) VALUES ($1, $2)")
+        .bind(&newuser.email)
+        .bind(hashed_password)
+        .execute(&state.postgres);
     match query.await {
-        Ok(_) => (StatusCode::CREATED, "Account created!".to_string()).into_response(),
+        Ok(_) => {
+            enqueue_email(&state.postgres, state.mailer.welcome(&newuser.email)).await;
+
+            (StatusCode::CREATED, "Account created!".to_string()).into_response()
+        }
         Err(e) => (
             StatusCode::BAD_REQUEST,
             format!("Something went wrong: {e}"),
@@ -72,7 +74,7 @@ pub async fn login(
 
             let session_id = rand::random::<u64>().to_string();
 
-            sqlx::query("INSERT INTO sessions (session_id, user_id) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET session_id = EXCLUDED.session_id")
+            sqlx::query("INSERT INTO sessions (session_id, user_id, expires) VALUES ($1, $2, NOW() + INTERVAL '7 days') ON CONFLICT (user_id) DO UPDATE SET session_id = EXCLUDED.session_id, expires = EXCLUDED.expires")
                 .bind(&session_id)
                 .bind(res.get::<i32, _>("id"))
                 .execute(&state.postgres)
@@ -98,7 +100,7 @@ pub async fn logout(
     State(state): State<AppState>,
     jar: PrivateCookieJar,
 ) -> Result<PrivateCookieJar, StatusCode> {
-    let Some(cookie) = jar.get("sessionid").map(|cookie| cookie.value().to_owned()) else {
+    let Some(cookie) = jar.get("foo").map(|cookie| cookie.value().to_owned()) else {
         return Ok(jar);
     };
 
@@ -112,10 +114,26 @@ pub async fn logout(
     }
 }
 
+/// Lets the request through only if the user is a site admin. Admins are
+/// granted in the `site_admins` table, never through the API.
+pub async fn require_admin(postgres: &PgPool, user_id: i32) -> Result<(), (StatusCode, String)> {
+    match sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM site_admins WHERE user_id = $1)",
+    )
+    .bind(user_id)
+    .fetch_one(postgres)
+    .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::FORBIDDEN, "Forbidden!".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
 pub async fn validate_session(
     jar: PrivateCookieJar,
     State(state): State<AppState>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> (PrivateCookieJar, Response) {
     let Some(cookie) = jar.get("foo").map(|cookie| cookie.value().to_owned()) else {
@@ -126,15 +144,19 @@ pub async fn validate_session(
         );
     };
 
-    let find_session =
-        sqlx::query("SELECT * FROM sessions WHERE session_id = $1 AND expires > CURRENT_TIMESTAMP")
-            .bind(cookie)
-            .execute(&state.postgres)
-            .await;
+    let find_session = sqlx::query_scalar::<_, i32>(
+        "SELECT user_id FROM sessions WHERE session_id = $1 AND expires > CURRENT_TIMESTAMP",
+    )
+    .bind(cookie)
+    .fetch_optional(&state.postgres)
+    .await;
 
     match find_session {
-        Ok(_) => (jar, next.run(request).await),
-        Err(_) => (
+        Ok(Some(id)) => {
+            request.extensions_mut().insert(SessionUser { id });
+            (jar, next.run(request).await)
+        }
+        _ => (
             jar,
             (StatusCode::FORBIDDEN, "Forbidden!".to_string()).into_response(),
         ),
@@ -0,0 +1,848 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream, TryStreamExt};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use crate::auth::SessionUser;
+use crate::customers::{find_existing_customer, validate_customer};
+use crate::deals::DealStatus;
+use crate::duplicates::{normalize_email, normalize_phone};
+use crate::AppState;
+
+const CUSTOMER_FIELDS: &[&str] = &["firstName", "lastName", "email", "phone", "priority"];
+const DEAL_FIELDS: &[&str] = &[
+    "customer_email",
+    "title",
+    "description",
+    "status",
+    "estimate_worth",
+    "actual_worth",
+    "expected_close_date",
+];
+
+/// A CSV upload. `mapping` maps our field names to the column headers in the
+/// file; fields that aren't mapped are looked up by their own name.
+#[derive(Deserialize)]
+pub struct ImportRequest {
+    pub csv: String,
+    #[serde(default)]
+    pub mapping: HashMap<String, String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Customer imports only: also import rows with the same email or phone
+    /// as another customer or row.
+    #[serde(default)]
+    pub allow_duplicate: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total_rows: usize,
+    pub valid_rows: usize,
+    pub imported: usize,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+struct CustomerRow {
+    first_name: String,
+    last_name: String,
+    email: String,
+    phone: String,
+    priority: i16,
+}
+
+struct DealRow {
+    customer_id: i32,
+    title: Option<String>,
+    description: Option<String>,
+    status: DealStatus,
+    estimate_worth: i32,
+    actual_worth: Option<i32>,
+    expected_close_date: Option<NaiveDate>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct CustomerExportRow {
+    id: i32,
+    #[serde(rename = "firstName", serialize_with = "cell")]
+    firstname: String,
+    #[serde(rename = "lastName", serialize_with = "cell")]
+    lastname: String,
+    #[serde(serialize_with = "cell")]
+    email: String,
+    #[serde(serialize_with = "cell")]
+    phone: String,
+    priority: i16,
+    is_archived: bool,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct DealExportRow {
+    id: i32,
+    #[serde(serialize_with = "optional_cell")]
+    customer_email: Option<String>,
+    #[serde(serialize_with = "optional_cell")]
+    title: Option<String>,
+    #[serde(serialize_with = "optional_cell")]
+    description: Option<String>,
+    status: String,
+    estimate_worth: i32,
+    actual_worth: Option<i32>,
+    expected_close_date: Option<NaiveDate>,
+    is_archived: bool,
+    created_at: Option<DateTime<Utc>>,
+    closed_at: Option<DateTime<Utc>>,
+}
+
+/// Emails and phones of the rows read so far, so a file can't add the same
+/// customer twice.
+#[derive(Default)]
+struct SeenCustomers {
+    emails: HashMap<String, u64>,
+    phones: HashMap<String, u64>,
+}
+
+impl SeenCustomers {
+    /// Records the row on `line`, unless an earlier row has the same email
+    /// or phone.
+    fn check(&mut self, line: u64, email: &str, phone: &str) -> Result<(), String> {
+        let email = normalize_email(email);
+        let phone = normalize_phone(phone).trim_start_matches('+').to_string();
+        let earlier = self
+            .emails
+            .get(&email)
+            .or_else(|| self.phones.get(&phone).filter(|_| !phone.is_empty()));
+        if let Some(earlier) = earlier {
+            return Err(format!("Same email or phone as line {earlier}"));
+        }
+        self.emails.insert(email, line);
+        if !phone.is_empty() {
+            self.phones.insert(phone, line);
+        }
+        Ok(())
+    }
+}
+
+/// Resolves which CSV column holds each field.
+struct Columns(HashMap<&'static str, usize>);
+
+impl Columns {
+    fn resolve(
+        headers: &csv::StringRecord,
+        fields: &[&'static str],
+        required: &[&str],
+        mapping: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        if let Some(unknown) = mapping.keys().find(|key| !fields.contains(&key.as_str())) {
+            return Err(format!("Unknown field in mapping: {unknown}"));
+        }
+
+        let mut columns = HashMap::new();
+        for field in fields {
+            let header_name = mapping.get(*field).map(String::as_str).unwrap_or(field);
+            if let Some(index) = headers
+                .iter()
+                .position(|header| header.eq_ignore_ascii_case(header_name))
+            {
+                columns.insert(*field, index);
+            } else if required.contains(field) {
+                return Err(format!("Missing column for {field}: {header_name}"));
+            }
+        }
+
+        Ok(Self(columns))
+    }
+
+    /// The trimmed value of `field`, or `None` when the column is absent or
+    /// the cell is empty.
+    fn get<'r>(&self, record: &'r csv::StringRecord, field: &str) -> Option<&'r str> {
+        self.0
+            .get(field)
+            .and_then(|index| record.get(*index))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+pub async fn import_customers(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<ImportRequest>,
+) -> Result<(StatusCode, Json<ImportReport>), impl IntoResponse> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(req.csv.as_bytes());
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+    };
+    let columns = match Columns::resolve(&headers, CUSTOMER_FIELDS, CUSTOMER_FIELDS, &req.mapping) {
+        Ok(columns) => columns,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen = SeenCustomers::default();
+    let mut total_rows = 0;
+    for record in reader.records() {
+        total_rows += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                errors.push(RowError {
+                    line: err.position().map(|p| p.line()).unwrap_or_default(),
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let row = match parse_customer(&columns, &record) {
+            Ok(row) => row,
+            Err(message) => {
+                errors.push(RowError { line, message });
+                continue;
+            }
+        };
+        if !req.allow_duplicate {
+            if let Err(message) = seen.check(line, &row.email, &row.phone) {
+                errors.push(RowError { line, message });
+                continue;
+            }
+            match find_existing_customer(&state.postgres, user.id, &row.email, &row.phone).await {
+                Ok(None) => {}
+                Ok(Some(existing)) => {
+                    errors.push(RowError {
+                        line,
+                        message: format!("Customer {existing} already has this email or phone"),
+                    });
+                    continue;
+                }
+                Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+            }
+        }
+        rows.push(row);
+    }
+
+    let mut report = ImportReport {
+        dry_run: req.dry_run,
+        total_rows,
+        valid_rows: rows.len(),
+        imported: 0,
+        errors,
+    };
+    if req.dry_run {
+        return Ok((StatusCode::OK, Json(report)));
+    }
+    if !report.errors.is_empty() {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    for row in &rows {
+        if let Err(err) = sqlx::query("INSERT INTO customers (firstname, lastname, email, phone, priority, owner_id, organization_id) VALUES ($1, $2, $3, $4, $5, $6, (SELECT organization_id FROM organization_members WHERE user_id = $6))")
+            .bind(&row.first_name)
+            .bind(&row.last_name)
+            .bind(&row.email)
+            .bind(&row.phone)
+            .bind(row.priority)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    report.imported = rows.len();
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn import_deals(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<ImportRequest>,
+) -> Result<(StatusCode, Json<ImportReport>), impl IntoResponse> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(req.csv.as_bytes());
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+    };
+    let columns = match Columns::resolve(
+        &headers,
+        DEAL_FIELDS,
+        &["customer_email", "estimate_worth"],
+        &req.mapping,
+    ) {
+        Ok(columns) => columns,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    let customers = match sqlx::query_as::<_, (String, i64, i32)>(
+        "SELECT lower(email), COUNT(*) OVER (PARTITION BY lower(email)), id FROM customers WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL",
+    )
+    .bind(user.id)
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|(email, matches, id)| (email, (matches, id)))
+            .collect::<HashMap<_, _>>(),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut total_rows = 0;
+    for record in reader.records() {
+        total_rows += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                errors.push(RowError {
+                    line: err.position().map(|p| p.line()).unwrap_or_default(),
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        match parse_deal(&columns, &record, &customers) {
+            Ok(row) => rows.push(row),
+            Err(message) => errors.push(RowError { line, message }),
+        }
+    }
+
+    let mut report = ImportReport {
+        dry_run: req.dry_run,
+        total_rows,
+        valid_rows: rows.len(),
+        imported: 0,
+        errors,
+    };
+    if req.dry_run {
+        return Ok((StatusCode::OK, Json(report)));
+    }
+    if !report.errors.is_empty() {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    for row in &rows {
+        let deal_id = match sqlx::query_scalar::<_, i32>("INSERT INTO deals (status, customer_id, owner_id, organization_id, estimate_worth, actual_worth, title, description, expected_close_date, closed_at) VALUES ($1, $2, $3, (SELECT organization_id FROM organization_members WHERE user_id = $3), $4, $5, $6, $7, $8, CASE WHEN $1 = 'closed' THEN NOW() ELSE NULL END) RETURNING id")
+            .bind(row.status.as_str())
+            .bind(row.customer_id)
+            .bind(user.id)
+            .bind(row.estimate_worth)
+            .bind(row.actual_worth)
+            .bind(&row.title)
+            .bind(&row.description)
+            .bind(row.expected_close_date)
+            .fetch_one(&mut *tx)
+            .await
+        {
+            Ok(id) => id,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        };
+        if let Err(err) = sqlx::query("INSERT INTO deal_status_history (deal_id, from_status, to_status, changed_by) VALUES ($1, NULL, $2, $3)")
+            .bind(deal_id)
+            .bind(row.status.as_str())
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    report.imported = rows.len();
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+fn parse_customer(columns: &Columns, record: &csv::StringRecord) -> Result<CustomerRow, String> {
+    let first_name = columns.get(record, "firstName").unwrap_or_default();
+    let last_name = columns.get(record, "lastName").unwrap_or_default();
+    let email = columns.get(record, "email").unwrap_or_default();
+    let phone = columns.get(record, "phone").unwrap_or_default();
+    let priority = columns
+        .get(record, "priority")
+        .unwrap_or_default()
+        .parse::<i32>()
+        .map_err(|_| "priority must be a whole number".to_string())?;
+
+    validate_customer(
+        Some(first_name),
+        Some(last_name),
+        Some(email),
+        Some(phone),
+        Some(priority),
+    )?;
+
+    Ok(CustomerRow {
+        first_name: first_name.to_string(),
+        last_name: last_name.to_string(),
+        email: email.to_string(),
+        phone: phone.to_string(),
+        priority: priority as i16,
+    })
+}
+
+fn parse_deal(
+    columns: &Columns,
+    record: &csv::StringRecord,
+    customers: &HashMap<String, (i64, i32)>,
+) -> Result<DealRow, String> {
+    let customer_email = columns
+        .get(record, "customer_email")
+        .ok_or("customer_email is required")?;
+    let customer_id = match customers.get(&customer_email.to_lowercase()) {
+        Some((1, id)) => *id,
+        Some(_) => return Err(format!("{customer_email} matches more than one customer")),
+        None => return Err(format!("No customer with email {customer_email}")),
+    };
+
+    let status = match columns.get(record, "status") {
+        Some(status) => status.parse::<DealStatus>()?,
+        None => DealStatus::Open,
+    };
+    let estimate_worth = parse_worth(columns.get(record, "estimate_worth"), "estimate_worth")?
+        .ok_or("estimate_worth is required")?;
+    let actual_worth = parse_worth(columns.get(record, "actual_worth"), "actual_worth")?;
+    if status == DealStatus::Closed && actual_worth.is_none() {
+        return Err("A closed deal must have an actual_worth".to_string());
+    }
+    let expected_close_date = match columns.get(record, "expected_close_date") {
+        Some(date) => Some(
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| "expected_close_date must be a YYYY-MM-DD date".to_string())?,
+        ),
+        None => None,
+    };
+    let title = columns.get(record, "title");
+    if title.is_some_and(|title| title.chars().count() > 255) {
+        return Err("title must be at most 255 characters".to_string());
+    }
+
+    Ok(DealRow {
+        customer_id,
+        title: title.map(str::to_string),
+        description: columns.get(record, "description").map(str::to_string),
+        status,
+        estimate_worth,
+        actual_worth,
+        expected_close_date,
+    })
+}
+
+fn parse_worth(value: Option<&str>, field: &str) -> Result<Option<i32>, String> {
+    match value.map(str::parse::<i32>) {
+        Some(Ok(worth)) if worth >= 0 => Ok(Some(worth)),
+        Some(_) => Err(format!("{field} must be a whole number of at least 0")),
+        None => Ok(None),
+    }
+}
+
+pub async fn export_customers(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> impl IntoResponse {
+    let headers = [
+        "id",
+        "firstName",
+        "lastName",
+        "email",
+        "phone",
+        "priority",
+        "archived",
+        "created_at",
+    ];
+    csv_response(
+        "customers.csv",
+        headers.as_slice(),
+        state.postgres,
+        move |postgres, tx| async move {
+            let mut rows = sqlx::query_as::<_, CustomerExportRow>("SELECT id, firstname, lastname, email, phone, priority, is_archived, created_at FROM customers WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL ORDER BY id")
+                .bind(user.id)
+                .fetch(&postgres);
+            while let Some(row) = rows.try_next().await? {
+                if !send_row(&tx, &row).await {
+                    break;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+pub async fn export_deals(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> impl IntoResponse {
+    let headers = [
+        "id",
+        "customer_email",
+        "title",
+        "description",
+        "status",
+        "estimate_worth",
+        "actual_worth",
+        "expected_close_date",
+        "archived",
+        "created_at",
+        "closed_at",
+    ];
+    csv_response(
+        "deals.csv",
+        headers.as_slice(),
+        state.postgres,
+        move |postgres, tx| async move {
+            let mut rows = sqlx::query_as::<_, DealExportRow>(
+                "SELECT
+                d.id,
+                c.email AS customer_email,
+                d.title,
+                d.description,
+                d.status,
+                COALESCE(d.estimate_worth, 0) AS estimate_worth,
+                d.actual_worth,
+                d.expected_close_date,
+                d.is_archived,
+                d.created_at,
+                d.closed_at
+                FROM deals d LEFT JOIN customers c ON d.customer_id = c.id
+                WHERE visible_to($1, d.owner_id, d.organization_id) AND d.deleted_at IS NULL
+                ORDER BY d.id",
+            )
+            .bind(user.id)
+            .fetch(&postgres);
+            while let Some(row) = rows.try_next().await? {
+                if !send_row(&tx, &row).await {
+                    break;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+type CsvChunk = Result<Vec<u8>, std::io::Error>;
+
+/// Streams a CSV download. `produce` runs in its own task and feeds rows
+/// through `send_row` as they come off the database, so large exports are
+/// never held in memory.
+fn csv_response<F, Fut>(
+    file_name: &str,
+    headers: &[&str],
+    postgres: PgPool,
+    produce: F,
+) -> impl IntoResponse
+where
+    F: FnOnce(PgPool, mpsc::Sender<CsvChunk>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<(), sqlx::Error>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<CsvChunk>(32);
+
+    let mut header_line = csv::Writer::from_writer(Vec::new());
+    let _ = header_line.write_record(headers);
+    let header_line = header_line.into_inner().unwrap_or_default();
+
+    tokio::spawn(async move {
+        if tx.send(Ok(header_line)).await.is_err() {
+            return;
+        }
+        if let Err(e) = produce(postgres, tx.clone()).await {
+            eprintln!("Error streaming CSV export: {:?}", e);
+            let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+        }
+    });
+
+    let body = Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        body,
+    )
+}
+
+/// Encodes one row and sends it down the stream. Returns false once the
+/// client has gone away.
+async fn send_row<T: Serialize>(tx: &mpsc::Sender<CsvChunk>, row: &T) -> bool {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    let chunk = writer
+        .serialize(row)
+        .map_err(|e| std::io::Error::other(e.to_string()))
+        .and_then(|_| {
+            writer
+                .into_inner()
+                .map_err(|e| std::io::Error::other(e.to_string()))
+        });
+    tx.send(chunk).await.is_ok()
+}
+
+/// Keeps spreadsheets from running text that looks like a formula, by
+/// prefixing it with a quote.
+fn escape_formula(value: &str) -> Cow<'_, str> {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{value}"))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn cell<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&escape_formula(value))
+}
+
+fn optional_cell<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => cell(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(fields.to_vec())
+    }
+
+    fn customer_columns() -> Columns {
+        let headers = record(&["firstName", "lastName", "email", "phone", "priority"]);
+        Columns::resolve(&headers, CUSTOMER_FIELDS, CUSTOMER_FIELDS, &HashMap::new()).unwrap()
+    }
+
+    fn deal_columns() -> Columns {
+        Columns::resolve(&record(DEAL_FIELDS), DEAL_FIELDS, &[], &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn columns_follow_the_mapping_and_ignore_header_case() {
+        let headers = record(&["E-Mail", "FIRSTNAME"]);
+        let mapping = HashMap::from([("email".to_string(), "e-mail".to_string())]);
+        let columns = Columns::resolve(&headers, CUSTOMER_FIELDS, &[], &mapping).unwrap();
+
+        let row = record(&[" ada@example.com ", "Ada"]);
+        assert_eq!(columns.get(&row, "email"), Some("ada@example.com"));
+        assert_eq!(columns.get(&row, "firstName"), Some("Ada"));
+        assert_eq!(columns.get(&row, "phone"), None);
+    }
+
+    #[test]
+    fn columns_reject_unknown_mappings_and_missing_required_fields() {
+        let mapping = HashMap::from([("nickname".to_string(), "nick".to_string())]);
+        assert!(Columns::resolve(&record(&["email"]), CUSTOMER_FIELDS, &[], &mapping).is_err());
+
+        let err = Columns::resolve(
+            &record(&["email"]),
+            CUSTOMER_FIELDS,
+            CUSTOMER_FIELDS,
+            &HashMap::new(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err, "Missing column for firstName: firstName");
+    }
+
+    #[test]
+    fn empty_cells_count_as_missing() {
+        let columns = customer_columns();
+        assert_eq!(
+            columns.get(&record(&["  ", "", "", "", ""]), "firstName"),
+            None
+        );
+    }
+
+    #[test]
+    fn customer_rows_are_validated() {
+        let columns = customer_columns();
+        let row = parse_customer(
+            &columns,
+            &record(&["Ada", "Lovelace", "ada@example.com", "555 0100", "2"]),
+        )
+        .unwrap();
+        assert_eq!(row.first_name, "Ada");
+        assert_eq!(row.priority, 2);
+
+        for fields in [
+            ["Ada", "Lovelace", "ada@example.com", "555 0100", "high"],
+            ["Ada", "Lovelace", "ada@example.com", "555 0100", "9"],
+            ["Ada", "Lovelace", "not an email", "555 0100", "2"],
+            ["", "Lovelace", "ada@example.com", "555 0100", "2"],
+        ] {
+            assert!(
+                parse_customer(&columns, &record(&fields)).is_err(),
+                "{fields:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn deal_rows_find_their_customer_by_email() {
+        let columns = deal_columns();
+        let customers = HashMap::from([
+            ("ada@example.com".to_string(), (1, 7)),
+            ("shared@example.com".to_string(), (2, 8)),
+        ]);
+
+        let row = parse_deal(
+            &columns,
+            &record(&[
+                "Ada@Example.com",
+                "Engines",
+                "",
+                "",
+                "500",
+                "",
+                "2025-03-01",
+            ]),
+            &customers,
+        )
+        .unwrap();
+        assert_eq!(row.customer_id, 7);
+        assert_eq!(row.status, DealStatus::Open);
+        assert_eq!(row.description, None);
+        assert_eq!(row.expected_close_date, NaiveDate::from_ymd_opt(2025, 3, 1));
+
+        let shared = record(&["shared@example.com", "", "", "", "500", "", ""]);
+        assert!(parse_deal(&columns, &shared, &customers).is_err());
+        let unknown = record(&["nobody@example.com", "", "", "", "500", "", ""]);
+        assert!(parse_deal(&columns, &unknown, &customers).is_err());
+    }
+
+    #[test]
+    fn deal_rows_are_validated() {
+        let columns = deal_columns();
+        let customers = HashMap::from([("ada@example.com".to_string(), (1, 7))]);
+
+        for fields in [
+            // Closed without an actual worth.
+            ["ada@example.com", "", "", "closed", "500", "", ""],
+            ["ada@example.com", "", "", "won", "500", "", ""],
+            ["ada@example.com", "", "", "", "", "", ""],
+            ["ada@example.com", "", "", "", "-5", "", ""],
+            ["ada@example.com", "", "", "", "500", "", "01/03/2025"],
+        ] {
+            assert!(
+                parse_deal(&columns, &record(&fields), &customers).is_err(),
+                "{fields:?}"
+            );
+        }
+
+        let closed = record(&["ada@example.com", "", "", "closed", "500", "450", ""]);
+        let row = parse_deal(&columns, &closed, &customers).unwrap();
+        assert_eq!(row.status, DealStatus::Closed);
+        assert_eq!(row.actual_worth, Some(450));
+    }
+
+    #[test]
+    fn worth_has_to_be_a_whole_number_of_at_least_zero() {
+        assert_eq!(parse_worth(Some("0"), "worth"), Ok(Some(0)));
+        assert_eq!(parse_worth(None, "worth"), Ok(None));
+        assert!(parse_worth(Some("-1"), "worth").is_err());
+        assert!(parse_worth(Some("12.50"), "worth").is_err());
+    }
+
+    #[test]
+    fn exported_cells_never_start_a_formula() {
+        for value in [
+            "=HYPERLINK(\"http://x\")",
+            "+1 555",
+            "-2",
+            "@SUM(A1)",
+            "\tx",
+            "\rx",
+        ] {
+            assert_eq!(escape_formula(value), format!("'{value}"));
+        }
+        for value in ["Ada", "ada@example.com", "555 = 555", ""] {
+            assert_eq!(escape_formula(value), value);
+        }
+    }
+
+    #[test]
+    fn export_rows_escape_text_but_not_empty_cells() {
+        let row = DealExportRow {
+            id: 1,
+            customer_email: Some("@evil".to_string()),
+            title: Some("=1+1".to_string()),
+            description: None,
+            status: "open".to_string(),
+            estimate_worth: -5,
+            actual_worth: None,
+            expected_close_date: None,
+            is_archived: false,
+            created_at: None,
+            closed_at: None,
+        };
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        writer.serialize(&row).unwrap();
+        let line = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(line, "1,'@evil,'=1+1,,open,-5,,,false,,\n");
+    }
+
+    #[test]
+    fn rows_repeating_an_earlier_email_or_phone_are_refused() {
+        let mut seen = SeenCustomers::default();
+        assert!(seen.check(2, "ada@example.com", "+44 20 7946 0958").is_ok());
+        assert!(seen.check(3, "grace@example.com", "").is_ok());
+        assert!(seen.check(4, "alan@example.com", "").is_ok());
+        assert_eq!(
+            seen.check(5, " ADA@example.com", "555"),
+            Err("Same email or phone as line 2".to_string())
+        );
+        assert_eq!(
+            seen.check(6, "ada.l@example.com", "44 (20) 7946-0958"),
+            Err("Same email or phone as line 2".to_string())
+        );
+        assert!(seen.check(7, "linus@example.com", "555").is_ok());
+    }
+}
@@ -0,0 +1,766 @@
+use std::collections::{BTreeSet, HashMap};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::auth::SessionUser;
+use crate::organizations::write_refused;
+use crate::AppState;
+
+const MAX_KEY_LEN: usize = 50;
+const MAX_TEXT_LEN: usize = 1000;
+const MAX_TAG_LEN: usize = 50;
+const MAX_TAGS: usize = 50;
+
+/// The kind of record a custom field belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldEntity {
+    Customer,
+    Deal,
+}
+
+impl FieldEntity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldEntity::Customer => "customer",
+            FieldEntity::Deal => "deal",
+        }
+    }
+
+    fn records_table(&self) -> &'static str {
+        match self {
+            FieldEntity::Customer => "customers",
+            FieldEntity::Deal => "deals",
+        }
+    }
+
+    /// The table values are stored in and its column pointing at the record.
+    fn values_table(&self) -> (&'static str, &'static str) {
+        match self {
+            FieldEntity::Customer => ("customer_field_values", "customer_id"),
+            FieldEntity::Deal => ("deal_field_values", "deal_id"),
+        }
+    }
+
+    /// Extra assignment for records that track when they were last changed.
+    fn touch(&self) -> &'static str {
+        match self {
+            FieldEntity::Customer => "",
+            FieldEntity::Deal => ", last_updated = NOW()",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Text,
+    Number,
+    Date,
+    Select,
+}
+
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Number => "number",
+            FieldType::Date => "date",
+            FieldType::Select => "select",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(FieldType::Text),
+            "number" => Some(FieldType::Number),
+            "date" => Some(FieldType::Date),
+            "select" => Some(FieldType::Select),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct FieldDefinition {
+    pub id: i32,
+    pub entity: String,
+    pub key: String,
+    pub label: String,
+    pub field_type: String,
+    pub options: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct NewFieldDefinition {
+    pub entity: FieldEntity,
+    pub key: String,
+    pub label: String,
+    pub field_type: FieldType,
+    /// The allowed values of a `select` field.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// The key and type of a definition are fixed once values exist; only the
+/// label and the options of a `select` field can be changed.
+#[derive(Deserialize)]
+pub struct FieldDefinitionUpdate {
+    pub label: Option<String>,
+    pub options: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct DefinitionFilter {
+    pub entity: Option<FieldEntity>,
+}
+
+#[derive(Deserialize)]
+pub struct TagsRequest {
+    pub tags: Vec<String>,
+}
+
+/// Tag and custom field filters shared by the customer and deal lists.
+///
+/// `tags` is a comma separated list and a record has to carry all of them.
+/// `fields` is a comma separated list of `key:value` pairs, each of which has
+/// to match the record's value exactly.
+#[derive(Default)]
+pub struct FieldFilters {
+    tags: Vec<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl FieldFilters {
+    pub fn parse(tags: Option<&str>, fields: Option<&str>) -> Result<Self, String> {
+        let tags = match tags {
+            Some(tags) => normalize_tags(tags.split(',').map(str::to_string).collect())?,
+            None => Vec::new(),
+        };
+
+        let mut pairs = Vec::new();
+        for pair in fields
+            .unwrap_or_default()
+            .split(',')
+            .filter(|p| !p.trim().is_empty())
+        {
+            match pair.split_once(':') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    pairs.push((key.trim().to_string(), value.trim().to_string()))
+                }
+                _ => return Err(format!("Invalid field filter: {pair}")),
+            }
+        }
+
+        Ok(FieldFilters {
+            tags,
+            fields: pairs,
+        })
+    }
+
+    /// Appends the filters for records of `entity` aliased as `alias`.
+    pub fn push(&self, query: &mut QueryBuilder<'_, Postgres>, entity: FieldEntity, alias: &str) {
+        if !self.tags.is_empty() {
+            query.push(format!(" AND {alias}.tags @> "));
+            query.push_bind(self.tags.clone());
+        }
+
+        let (table, column) = entity.values_table();
+        for (key, value) in &self.fields {
+            query.push(format!(
+                " AND EXISTS (SELECT 1 FROM {table} v JOIN custom_field_definitions f ON f.id = v.definition_id WHERE v.{column} = {alias}.id AND field_defined_for(f.owner_id, f.organization_id, {alias}.owner_id, {alias}.organization_id) AND f.key = "
+            ));
+            query.push_bind(key.clone());
+            query.push(" AND v.value #>> '{}' = ");
+            query.push_bind(value.clone());
+            query.push(")");
+        }
+    }
+}
+
+/// Trims and lowercases tags, dropping blanks and duplicates.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut normalized = BTreeSet::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!(
+                "Tags can't be longer than {MAX_TAG_LEN} characters"
+            ));
+        }
+        if tag.contains(',') {
+            return Err("Tags can't contain commas".to_string());
+        }
+        normalized.insert(tag);
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("A record can't have more than {MAX_TAGS} tags"));
+    }
+
+    Ok(normalized.into_iter().collect())
+}
+
+fn validate_key(key: &str) -> Result<(), String> {
+    let valid = key.len() <= MAX_KEY_LEN
+        && key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "key must start with a lowercase letter and contain only lowercase letters, digits and underscores, up to {MAX_KEY_LEN} characters"
+        ))
+    }
+}
+
+/// Options are only allowed, and required, for `select` fields.
+fn validate_options(field_type: FieldType, options: Vec<String>) -> Result<Vec<String>, String> {
+    let mut unique = Vec::new();
+    for option in options {
+        let option = option.trim().to_string();
+        if !option.is_empty() && !unique.contains(&option) {
+            unique.push(option);
+        }
+    }
+
+    match field_type {
+        FieldType::Select if unique.is_empty() => {
+            Err("A select field needs at least one option".to_string())
+        }
+        FieldType::Select => Ok(unique),
+        _ if unique.is_empty() => Ok(unique),
+        _ => Err("Only select fields can have options".to_string()),
+    }
+}
+
+/// Checks `value` against the definition and returns it in the form it is
+/// stored in: numbers as JSON numbers, dates as `YYYY-MM-DD` strings.
+pub fn coerce_value(definition: &FieldDefinition, value: &Value) -> Result<Value, String> {
+    let key = &definition.key;
+    let field_type = FieldType::parse(&definition.field_type)
+        .ok_or_else(|| format!("Field {key} has an unknown type"))?;
+
+    match (field_type, value) {
+        (FieldType::Text, Value::String(text)) => {
+            let text = text.trim();
+            if text.chars().count() > MAX_TEXT_LEN {
+                Err(format!(
+                    "Field {key} can't be longer than {MAX_TEXT_LEN} characters"
+                ))
+            } else {
+                Ok(Value::String(text.to_string()))
+            }
+        }
+        (FieldType::Number, Value::Number(number)) => Ok(Value::Number(number.clone())),
+        (FieldType::Number, Value::String(text)) => match text.trim().parse::<f64>() {
+            Ok(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => {
+                Ok(Value::Number(Number::from(number as i64)))
+            }
+            Ok(number) => Number::from_f64(number)
+                .map(Value::Number)
+                .ok_or_else(|| format!("Field {key} expects a number")),
+            Err(_) => Err(format!("Field {key} expects a number")),
+        },
+        (FieldType::Date, Value::String(text)) => {
+            match NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d") {
+                Ok(date) => Ok(Value::String(date.to_string())),
+                Err(_) => Err(format!("Field {key} expects a date like 2024-12-31")),
+            }
+        }
+        (FieldType::Select, Value::String(text)) => {
+            let text = text.trim();
+            if definition.options.iter().any(|option| option == text) {
+                Ok(Value::String(text.to_string()))
+            } else {
+                Err(format!(
+                    "Field {key} must be one of: {}",
+                    definition.options.join(", ")
+                ))
+            }
+        }
+        (field_type, _) => Err(format!(
+            "Field {key} expects a {} value",
+            field_type.as_str()
+        )),
+    }
+}
+
+pub async fn get_field_definitions(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(filter): Query<DefinitionFilter>,
+) -> Result<Json<Vec<FieldDefinition>>, impl IntoResponse> {
+    match sqlx::query_as::<_, FieldDefinition>("SELECT id, entity, key, label, field_type, options, created_at FROM custom_field_definitions WHERE field_defined_for(owner_id, organization_id, $1, (SELECT organization_id FROM organization_members WHERE user_id = $1)) AND ($2::varchar IS NULL OR entity = $2) ORDER BY entity, label, id")
+        .bind(user.id)
+        .bind(filter.entity.map(|entity| entity.as_str()))
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn create_field_definition(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<NewFieldDefinition>,
+) -> Result<(StatusCode, Json<FieldDefinition>), impl IntoResponse> {
+    let key = req.key.trim();
+    if let Err(err) = validate_key(key) {
+        return Err((StatusCode::BAD_REQUEST, err));
+    }
+    if req.label.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "label must not be empty".to_string(),
+        ));
+    }
+    let options = match validate_options(req.field_type, req.options) {
+        Ok(options) => options,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    match sqlx::query_as::<_, FieldDefinition>("INSERT INTO custom_field_definitions (owner_id, organization_id, entity, key, label, field_type, options) VALUES ($1, (SELECT organization_id FROM organization_members WHERE user_id = $1), $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING RETURNING id, entity, key, label, field_type, options, created_at")
+        .bind(user.id)
+        .bind(req.entity.as_str())
+        .bind(key)
+        .bind(req.label.trim())
+        .bind(req.field_type.as_str())
+        .bind(options)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(res)) => Ok((StatusCode::CREATED, Json(res))),
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            format!("A {} field called {key} already exists", req.entity.as_str()),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn update_field_definition(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<FieldDefinitionUpdate>,
+) -> Result<Json<FieldDefinition>, impl IntoResponse> {
+    if req.label.as_deref().is_some_and(|l| l.trim().is_empty()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "label must not be empty".to_string(),
+        ));
+    }
+
+    let definition = match sqlx::query_as::<_, FieldDefinition>("SELECT id, entity, key, label, field_type, options, created_at FROM custom_field_definitions WHERE editable_by($1, owner_id, organization_id) AND id = $2")
+        .bind(user.id)
+        .bind(id)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(res)) => res,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Custom field not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let options = match req.options {
+        Some(options) => {
+            let field_type = FieldType::parse(&definition.field_type).unwrap_or(FieldType::Text);
+            match validate_options(field_type, options) {
+                Ok(options) => Some(options),
+                Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+            }
+        }
+        None => None,
+    };
+
+    match sqlx::query_as::<_, FieldDefinition>("UPDATE custom_field_definitions SET label = COALESCE($1, label), options = COALESCE($2, options) WHERE editable_by($3, owner_id, organization_id) AND id = $4 RETURNING id, entity, key, label, field_type, options, created_at")
+        .bind(req.label.as_deref().map(str::trim))
+        .bind(options)
+        .bind(user.id)
+        .bind(id)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Custom field not found".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Deletes a definition along with every value stored for it.
+pub async fn destroy_field_definition(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    match sqlx::query("DELETE FROM custom_field_definitions WHERE editable_by($1, owner_id, organization_id) AND id = $2")
+        .bind(user.id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            Err((StatusCode::NOT_FOUND, "Custom field not found".to_string()))
+        }
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn set_customer_fields(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<HashMap<String, Value>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    set_fields(&state, user, FieldEntity::Customer, id, req).await
+}
+
+pub async fn set_deal_fields(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<HashMap<String, Value>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    set_fields(&state, user, FieldEntity::Deal, id, req).await
+}
+
+pub async fn set_customer_tags(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<TagsRequest>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    set_tags(&state, user, FieldEntity::Customer, id, req.tags).await
+}
+
+pub async fn set_deal_tags(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<TagsRequest>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    set_tags(&state, user, FieldEntity::Deal, id, req.tags).await
+}
+
+/// Sets the given custom field values on a record and returns all of its
+/// values. A `null` value clears the field; fields left out are unchanged.
+async fn set_fields(
+    state: &AppState,
+    user: SessionUser,
+    entity: FieldEntity,
+    id: i32,
+    values: HashMap<String, Value>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let records = entity.records_table();
+    let (owner_id, organization_id) = match sqlx::query_as::<_, (i32, Option<i32>)>(&format!(
+        "SELECT owner_id, organization_id FROM {records} WHERE editable_by($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL FOR UPDATE"
+    ))
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                records,
+                user.id,
+                id,
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("{} not found", entity_name(entity)),
+                ),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    // The record's own fields, which may not be the editor's when an
+    // organization admin changes a rep's record.
+    let definitions = match sqlx::query_as::<_, FieldDefinition>("SELECT id, entity, key, label, field_type, options, created_at FROM custom_field_definitions WHERE field_defined_for(owner_id, organization_id, $1, $2) AND entity = $3")
+        .bind(owner_id)
+        .bind(organization_id)
+        .bind(entity.as_str())
+        .fetch_all(&mut *tx)
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let mut changes = Vec::new();
+    for (key, value) in &values {
+        let Some(definition) = definitions.iter().find(|d| &d.key == key) else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown custom field: {key}"),
+            ));
+        };
+        let value = match value {
+            Value::Null => None,
+            value => match coerce_value(definition, value) {
+                Ok(value) => Some(value),
+                Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+            },
+        };
+        changes.push((definition.id, value));
+    }
+
+    if entity == FieldEntity::Deal {
+        if let Err(err) = sqlx::query("UPDATE deals SET last_updated = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    let (table, column) = entity.values_table();
+    for (definition_id, value) in changes {
+        let result = match value {
+            Some(value) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {table} ({column}, definition_id, value) VALUES ($1, $2, $3)
+                    ON CONFLICT ({column}, definition_id) DO UPDATE SET value = EXCLUDED.value"
+                ))
+                .bind(id)
+                .bind(definition_id)
+                .bind(value)
+                .execute(&mut *tx)
+                .await
+            }
+            None => {
+                sqlx::query(&format!(
+                    "DELETE FROM {table} WHERE {column} = $1 AND definition_id = $2"
+                ))
+                .bind(id)
+                .bind(definition_id)
+                .execute(&mut *tx)
+                .await
+            }
+        };
+        if let Err(err) = result {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    let fields = match sqlx::query_scalar::<_, Value>(&format!(
+        "SELECT {}_custom_fields($1)",
+        entity.as_str()
+    ))
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match tx.commit().await {
+        Ok(_) => Ok(Json(fields)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Replaces a record's tags.
+async fn set_tags(
+    state: &AppState,
+    user: SessionUser,
+    entity: FieldEntity,
+    id: i32,
+    tags: Vec<String>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let tags = match normalize_tags(tags) {
+        Ok(tags) => tags,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    match sqlx::query_scalar::<_, Vec<String>>(&format!(
+        "UPDATE {} SET tags = $1{} WHERE editable_by($2, owner_id, organization_id) AND id = $3 AND deleted_at IS NULL RETURNING tags",
+        entity.records_table(),
+        entity.touch()
+    ))
+    .bind(tags)
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(Some(tags)) => Ok(Json(tags)),
+        Ok(None) => Err(write_refused(
+            &state.postgres,
+            entity.records_table(),
+            user.id,
+            id,
+            (
+                StatusCode::NOT_FOUND,
+                format!("{} not found", entity_name(entity)),
+            ),
+        )
+        .await),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+fn entity_name(entity: FieldEntity) -> &'static str {
+    match entity {
+        FieldEntity::Customer => "Customer",
+        FieldEntity::Deal => "Deal",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn definition(field_type: FieldType, options: &[&str]) -> FieldDefinition {
+        FieldDefinition {
+            id: 1,
+            entity: FieldEntity::Customer.as_str().to_string(),
+            key: "plan".to_string(),
+            label: "Plan".to_string(),
+            field_type: field_type.as_str().to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn text_is_trimmed_and_length_checked() {
+        let text = definition(FieldType::Text, &[]);
+        assert_eq!(coerce_value(&text, &json!("  gold ")), Ok(json!("gold")));
+        assert!(coerce_value(&text, &json!("x".repeat(MAX_TEXT_LEN + 1))).is_err());
+        assert!(coerce_value(&text, &json!(5)).is_err());
+    }
+
+    #[test]
+    fn numbers_are_stored_as_json_numbers() {
+        let number = definition(FieldType::Number, &[]);
+        assert_eq!(coerce_value(&number, &json!(12)), Ok(json!(12)));
+        assert_eq!(coerce_value(&number, &json!(" 12 ")), Ok(json!(12)));
+        assert_eq!(coerce_value(&number, &json!("2.5")), Ok(json!(2.5)));
+        assert!(coerce_value(&number, &json!("twelve")).is_err());
+        assert!(coerce_value(&number, &json!("1e400")).is_err());
+        assert!(coerce_value(&number, &json!(true)).is_err());
+    }
+
+    #[test]
+    fn dates_are_stored_as_iso_strings() {
+        let date = definition(FieldType::Date, &[]);
+        assert_eq!(
+            coerce_value(&date, &json!("2024-12-31")),
+            Ok(json!("2024-12-31"))
+        );
+        assert!(coerce_value(&date, &json!("31/12/2024")).is_err());
+        assert!(coerce_value(&date, &json!("2024-02-30")).is_err());
+    }
+
+    #[test]
+    fn selects_only_take_their_options() {
+        let select = definition(FieldType::Select, &["gold", "silver"]);
+        assert_eq!(
+            coerce_value(&select, &json!("silver ")),
+            Ok(json!("silver"))
+        );
+        assert!(coerce_value(&select, &json!("Gold")).is_err());
+    }
+
+    #[test]
+    fn unknown_types_are_refused() {
+        let mut broken = definition(FieldType::Text, &[]);
+        broken.field_type = "colour".to_string();
+        assert!(coerce_value(&broken, &json!("red")).is_err());
+    }
+
+    #[test]
+    fn filters_parse_tags_and_key_value_pairs() {
+        let filters =
+            FieldFilters::parse(Some("VIP, ,vip,west"), Some("plan:gold, region : emea")).unwrap();
+        assert_eq!(filters.tags, ["vip", "west"]);
+        assert_eq!(
+            filters.fields,
+            [
+                ("plan".to_string(), "gold".to_string()),
+                ("region".to_string(), "emea".to_string())
+            ]
+        );
+
+        let empty = FieldFilters::parse(None, Some(",")).unwrap();
+        assert!(empty.tags.is_empty() && empty.fields.is_empty());
+    }
+
+    #[test]
+    fn field_filters_only_match_the_records_own_fields() {
+        let filters = FieldFilters::parse(None, Some("plan:gold")).unwrap();
+        let mut query = QueryBuilder::<Postgres>::new("SELECT c.id FROM customers c WHERE TRUE");
+        filters.push(&mut query, FieldEntity::Customer, "c");
+        let sql = query.sql();
+        assert!(sql.contains("FROM customer_field_values v"));
+        assert!(sql.contains(
+            "field_defined_for(f.owner_id, f.organization_id, c.owner_id, c.organization_id)"
+        ));
+    }
+
+    #[test]
+    fn filters_need_a_key_for_every_pair() {
+        assert!(FieldFilters::parse(None, Some("gold")).is_err());
+        assert!(FieldFilters::parse(None, Some(":gold")).is_err());
+    }
+
+    #[test]
+    fn tags_are_limited_in_length_and_number() {
+        assert!(normalize_tags(vec!["x".repeat(MAX_TAG_LEN + 1)]).is_err());
+        let many = (0..=MAX_TAGS).map(|n| format!("tag{n}")).collect();
+        assert!(normalize_tags(many).is_err());
+    }
+
+    #[test]
+    fn keys_are_lowercase_identifiers() {
+        assert!(validate_key("plan_2").is_ok());
+        for key in ["Plan", "2plan", "plan-type", ""] {
+            assert!(validate_key(key).is_err(), "{key}");
+        }
+    }
+
+    #[test]
+    fn only_select_fields_have_options() {
+        assert_eq!(
+            validate_options(FieldType::Select, vec![" a".into(), "a".into(), "".into()]),
+            Ok(vec!["a".to_string()])
+        );
+        assert!(validate_options(FieldType::Select, Vec::new()).is_err());
+        assert!(validate_options(FieldType::Text, vec!["a".into()]).is_err());
+        assert_eq!(
+            validate_options(FieldType::Text, vec![" ".into()]),
+            Ok(Vec::new())
+        );
+    }
+}
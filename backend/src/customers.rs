@@ -3,48 +3,84 @@ use axum::response::IntoResponse;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    Json,
+    Extension, Json,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::collections::BTreeMap;
 
-use crate::dashboard::UserRequest;
+use crate::auth::SessionUser;
+use crate::custom_fields::{FieldEntity, FieldFilters};
+use crate::deals::{ArchivedFilter, Deal, DealStatus};
+use crate::duplicates::{normalize_email, normalize_phone};
+use crate::organizations::write_refused;
+use crate::retention::RESTORE_WINDOW_DAYS;
 use crate::AppState;
 
-#[derive(Deserialize)]
-pub struct UsersRequest {
-    pub email: Option<String>,
+#[derive(Deserialize, sqlx::FromRow, Serialize)]
+pub struct Customer {
+    pub id: i32,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub phone: String,
+    pub priority: i16,
+    pub is_archived: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    /// The rep the customer is assigned to.
+    pub owner_id: i32,
+    pub organization_id: Option<i32>,
+    pub tags: Vec<String>,
+    /// Custom field values keyed by the field's key.
+    pub custom_fields: serde_json::Value,
 }
 
-#[derive(Deserialize, sqlx::FromRow, Serialize)]
-pub struct User {
+/// A customer together with their deals and the numbers the customer page
+/// shows at the top.
+#[derive(Deserialize, Serialize)]
+pub struct CustomerDetail {
+    #[serde(flatten)]
+    pub customer: Customer,
+    pub deals_by_status: BTreeMap<String, Vec<Deal>>,
+    /// Actual worth of closed deals, falling back to the estimate.
+    pub closed_value: i64,
+    /// Estimated worth of deals that are neither closed nor archived.
+    pub open_pipeline_value: i64,
+    pub last_activity_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Deserialize, sqlx::FromRow, Serialize)]
+pub struct CustomerSummary {
     pub id: i32,
-    pub external_id: Option<String>,
+    pub firstname: String,
+    pub lastname: String,
     pub email: String,
-    pub role: Option<String>,
+    pub phone: String,
+    pub priority: i16,
 }
 
 #[derive(Deserialize, sqlx::FromRow, Serialize)]
-pub struct PersonalInformation {
-    pub fistname: Option<String>,
-    pub lastname: Option<String>,
-    pub dob: Option<String>,
-    pub sex: Option<String>,
+pub struct CustomerName {
+    pub id: i32,
+    pub customer_name: String,
 }
 
-#[derive(Deserialize)]
-pub struct Order {
-    pub external_id: Option<String>,
-    pub amount: Option<String>,
-    pub extra_data: Option<String>,
-    pub status: Option<String>,
-    // pub created_at: Option<String>,
+#[derive(Deserialize, Default)]
+pub struct CustomerFilter {
+    pub q: Option<String>,
+    pub priority: Option<i16>,
+    #[serde(default)]
+    pub archived: ArchivedFilter,
+    pub tags: Option<String>,
+    pub fields: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct ChangeRequest {
-    pub columnname: String,
-    pub new_value: String,
-    pub email: String,
+#[derive(Deserialize, Default)]
+pub struct CreateOptions {
+    /// Create the customer even if one with the same email or phone exists.
+    #[serde(default)]
+    pub allow_duplicate: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -55,120 +91,593 @@ pub struct NewCustomer {
     pub email: String,
     pub phone: String,
     pub priority: i32,
-    pub userEmail: String,
-}
-
-// pub async fn get_all_customers(
-//     State(state): State<AppState>,
-//     Json(req): Json<UserRequest>,
-// ) -> Result<Json<Vec<Customer>>, impl IntoResponse> {
-//     match sqlx::query_as::<_, Customer>("SELECT id, firstName, lastName, email, phone, priority FROM customers WHERE owner_id = (SELECT id FROM users WHERE email = $1)")
-// 					.bind(req.email)
-// 					.fetch_all(&state.postgres)
-// 					.await {
-//         Ok(res) => Ok(Json(res)),
-//         Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
-// 					}
-// }
-
-// pub async fn get_customers(
-//     State(state): State<AppState>,
-//     Path(id): Path<String>,
-//     Query(query): Query<UsersRequest>,
-// ) -> Result<Json<Vec<User>>, impl IntoResponse> {
-//     match query.email {
-//         Some(email) => {
-//             match sqlx::query_as::<_, (User, PersonalInformation, Vec<Order>)>(
-//                 r#"
-//                 SELECT u.*, pi.*, o.*
-//                 FROM users u
-//                 LEFT JOIN personal_information pi ON u.id = pi.user_id
-//                 LEFT JOIN orders o ON u.id = o.user_id
-//                 WHERE u.email = $1
-//                 "#)
-//                 .bind(email)
-//                 .fetch_one(&state.postgres)
-//                 .await {
-//                     Ok(res) => Ok(Json(res)),
-//                     Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()),
-//                 }
-//         }
-//         None => {
-//             match sqlx::query_as::<_, User>("SELECT * FROM users")
-//                 .fetch_all(&state.postgres)
-//                 .await
-//             {
-//                 Ok(res) => Ok(Json(res)),
-//                 Err(err) => {
-//                     Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
-//                 }
-//             }
-//         }
-//     }
-// }
+}
+
+/// Partial update of a customer. Only these fields can be changed; anything
+/// left out of the request is unchanged.
+#[derive(Deserialize)]
+pub struct CustomerUpdate {
+    #[serde(rename = "firstName")]
+    pub first_name: Option<String>,
+    #[serde(rename = "lastName")]
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub priority: Option<i32>,
+}
+
+pub async fn get_all_customers(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(filter): Query<CustomerFilter>,
+) -> Result<Json<Vec<Customer>>, impl IntoResponse> {
+    let field_filters = match FieldFilters::parse(filter.tags.as_deref(), filter.fields.as_deref())
+    {
+        Ok(filters) => filters,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new(
+        "SELECT c.id, c.firstname, c.lastname, c.email, c.phone, c.priority, c.is_archived, c.created_at, c.owner_id, c.organization_id, c.tags, customer_custom_fields(c.id) AS custom_fields FROM customers c WHERE c.deleted_at IS NULL AND visible_to(",
+    );
+    query.push_bind(user.id);
+    query.push(", c.owner_id, c.organization_id)");
+    query.push(filter.archived.condition("c"));
+    if let Some(priority) = filter.priority {
+        query.push(" AND c.priority = ");
+        query.push_bind(priority);
+    }
+    if let Some(q) = filter.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", crate::deals::escape_like(q));
+        query.push(" AND (concat(c.firstname, ' ', c.lastname) ILIKE ");
+        query.push_bind(pattern.clone());
+        query.push(" OR c.email ILIKE ");
+        query.push_bind(pattern);
+        query.push(")");
+    }
+    field_filters.push(&mut query, FieldEntity::Customer, "c");
+    query.push(" ORDER BY c.lastname, c.firstname, c.id");
+
+    match query
+        .build_query_as::<Customer>()
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn get_one_customer(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<Json<CustomerDetail>, impl IntoResponse> {
+    let customer = match sqlx::query_as::<_, Customer>("SELECT id, firstname, lastname, email, phone, priority, is_archived, created_at, owner_id, organization_id, tags, customer_custom_fields(id) AS custom_fields FROM customers WHERE visible_to($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL")
+        .bind(user.id)
+        .bind(id)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(res)) => res,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Customer not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let deals = match sqlx::query_as::<_, Deal>(
+        "SELECT 
+        d.id, 
+        d.title, 
+        COALESCE(d.estimate_worth, 0) AS estimate_worth, 
+        d.actual_worth, 
+        d.status, 
+        d.closed, 
+        d.expected_close_date, 
+        d.created_at, 
+        d.last_updated, 
+        d.customer_id, 
+        d.is_archived, 
+        d.owner_id, 
+        d.tags, 
+        deal_custom_fields(d.id) AS custom_fields, 
+        concat(c.firstname, ' ', c.lastname) AS customer_name
+        FROM deals d JOIN customers c ON d.customer_id = c.id
+        WHERE d.customer_id = $1 AND visible_to($2, d.owner_id, d.organization_id) AND d.deleted_at IS NULL
+        ORDER BY d.last_updated DESC, d.id DESC",
+    )
+    .bind(id)
+    .bind(user.id)
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let last_activity_at = match sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+        "SELECT MAX(at) FROM (
+            SELECT d.last_updated AS at FROM deals d
+            WHERE d.customer_id = $1 AND visible_to($2, d.owner_id, d.organization_id) AND d.deleted_at IS NULL
+            UNION ALL
+            SELECT n.created_at FROM deal_notes n JOIN deals d ON d.id = n.deal_id
+            WHERE d.customer_id = $1 AND visible_to($2, d.owner_id, d.organization_id) AND d.deleted_at IS NULL
+            UNION ALL
+            SELECT COALESCE(a.completed_at, a.created_at) FROM activities a
+            WHERE a.customer_id = $1 AND a.owner_id = $2
+        ) activity",
+    )
+    .bind(id)
+    .bind(user.id)
+    .fetch_one(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let closed_value = closed_value(&deals);
+    let open_pipeline_value = open_pipeline_value(&deals);
+    let deals_by_status = group_by_status(deals);
+
+    Ok(Json(CustomerDetail {
+        customer,
+        deals_by_status,
+        closed_value,
+        open_pipeline_value,
+        last_activity_at,
+    }))
+}
+
+/// Actual worth of the closed deals, falling back to the estimate.
+fn closed_value(deals: &[Deal]) -> i64 {
+    let closed = DealStatus::Closed.as_str();
+    deals
+        .iter()
+        .filter(|deal| deal.status == closed)
+        .map(|deal| i64::from(deal.actual_worth.unwrap_or(deal.estimate_worth)))
+        .sum()
+}
+
+/// Estimated worth of the deals that are neither closed nor archived.
+fn open_pipeline_value(deals: &[Deal]) -> i64 {
+    let closed = DealStatus::Closed.as_str();
+    deals
+        .iter()
+        .filter(|deal| deal.status != closed && !deal.is_archived)
+        .map(|deal| i64::from(deal.estimate_worth))
+        .sum()
+}
+
+/// Groups deals by status, keeping their order within each status.
+fn group_by_status(deals: Vec<Deal>) -> BTreeMap<String, Vec<Deal>> {
+    let mut deals_by_status: BTreeMap<String, Vec<Deal>> = BTreeMap::new();
+    for deal in deals {
+        deals_by_status
+            .entry(deal.status.clone())
+            .or_default()
+            .push(deal);
+    }
+    deals_by_status
+}
+
+pub async fn get_customer_names(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<Vec<CustomerName>>, impl IntoResponse> {
+    match sqlx::query_as::<_, CustomerName>("SELECT id, CONCAT(firstName, ' ', lastName) AS customer_name FROM customers WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL AND NOT is_archived ORDER BY lastname, firstname")
+					.bind(user.id)
+					.fetch_all(&state.postgres)
+					.await {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+					}
+}
 
 pub async fn create_customer(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(options): Query<CreateOptions>,
     Json(req): Json<NewCustomer>,
-) -> Result<StatusCode, impl IntoResponse> {
-    match sqlx::query("INSERT INTO CUSTOMERS (firstname, lastname, email, phone, priority, owner_id) VALUES ($1, $2, $3, $4, $5, (SELECT id FROM users WHERE email = $6))")
-						.bind(req.firstName)
-						.bind(req.lastName)
-						.bind(req.email)
-						.bind(req.phone)
-						.bind(req.priority)
-						.bind(req.userEmail)
-						.execute(&state.postgres)
+) -> Result<(StatusCode, Json<Customer>), impl IntoResponse> {
+    if let Err(err) = validate_customer(
+        Some(&req.firstName),
+        Some(&req.lastName),
+        Some(&req.email),
+        Some(&req.phone),
+        Some(req.priority),
+    ) {
+        return Err((StatusCode::BAD_REQUEST, err));
+    }
+
+    if !options.allow_duplicate {
+        match find_existing_customer(&state.postgres, user.id, &req.email, &req.phone).await {
+            Ok(None) => {}
+            Ok(Some(existing)) => {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!("Customer {existing} already has this email or phone"),
+                ))
+            }
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        }
+    }
+
+    match sqlx::query_as::<_, Customer>("INSERT INTO CUSTOMERS (firstname, lastname, email, phone, priority, owner_id, organization_id) VALUES ($1, $2, $3, $4, $5, $6, (SELECT organization_id FROM organization_members WHERE user_id = $6)) RETURNING id, firstname, lastname, email, phone, priority, is_archived, created_at, owner_id, organization_id, tags, customer_custom_fields(id) AS custom_fields")
+						.bind(req.firstName.trim())
+						.bind(req.lastName.trim())
+						.bind(req.email.trim())
+						.bind(req.phone.trim())
+						.bind(req.priority as i16)
+						.bind(user.id)
+						.fetch_one(&state.postgres)
 						.await  {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        Ok(customer) => Ok((StatusCode::CREATED, Json(customer))),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
 	}
 }
 
 pub async fn edit_customer(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-    Json(req): Json<ChangeRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let Ok(_) = sqlx::query("UPDATE customers SET $1 = $2 WHERE owner_id = (SELECT id FROM users WHERE email = $3) AND id = $4")
-					.bind(req.columnname)
-					.bind(req.new_value)
-					.bind(req.email)
-					.bind(id.parse::<i32>().unwrap())
-					.fetch_one(&state.postgres)
-					.await else {
-						return Err(StatusCode::INTERNAL_SERVER_ERROR)
-					};
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<CustomerUpdate>,
+) -> Result<Json<Customer>, impl IntoResponse> {
+    if let Err(err) = validate_customer(
+        req.first_name.as_deref(),
+        req.last_name.as_deref(),
+        req.email.as_deref(),
+        req.phone.as_deref(),
+        req.priority,
+    ) {
+        return Err((StatusCode::BAD_REQUEST, err));
+    }
 
-    Ok(StatusCode::OK)
+    match sqlx::query_as::<_, Customer>(
+        "UPDATE customers SET
+        firstname = COALESCE($1, firstname),
+        lastname = COALESCE($2, lastname),
+        email = COALESCE($3, email),
+        phone = COALESCE($4, phone),
+        priority = COALESCE($5, priority)
+        WHERE editable_by($6, owner_id, organization_id) AND id = $7 AND deleted_at IS NULL
+        RETURNING id, firstname, lastname, email, phone, priority, is_archived, created_at, owner_id, organization_id, tags, customer_custom_fields(id) AS custom_fields",
+    )
+    .bind(req.first_name.as_deref().map(str::trim))
+    .bind(req.last_name.as_deref().map(str::trim))
+    .bind(req.email.as_deref().map(str::trim))
+    .bind(req.phone.as_deref().map(str::trim))
+    .bind(req.priority.map(|p| p as i16))
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(Some(customer)) => Ok(Json(customer)),
+        Ok(None) => Err(write_refused(
+            &state.postgres,
+            "customers",
+            user.id,
+            id,
+            (StatusCode::NOT_FOUND, "Customer not found".to_string()),
+        )
+        .await),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
 }
 
+/// Moves a customer and their deals to the trash. Both can be restored until
+/// the restore window runs out.
 pub async fn destroy_customer(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-    Json(req): Json<UserRequest>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
 ) -> Result<StatusCode, impl IntoResponse> {
-    let id = id.trim().parse::<i32>().unwrap();
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
 
-    match sqlx::query("DELETE FROM customers WHERE owner_id = (SELECT id FROM users WHERE email = $1) AND id = $2")
-					.bind(req.email)
+    let deleted_at = match sqlx::query_scalar::<_, DateTime<Utc>>("UPDATE customers SET deleted_at = NOW() WHERE editable_by($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL RETURNING deleted_at")
+					.bind(user.id)
 					.bind(id)
-					.execute(&state.postgres)
+					.fetch_optional(&mut *tx)
 					.await {
+        Ok(Some(deleted_at)) => deleted_at,
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                "customers",
+                user.id,
+                id,
+                (StatusCode::NOT_FOUND, "Customer not found".to_string()),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+					};
+
+    if let Err(err) = sqlx::query(
+        "UPDATE deals SET deleted_at = $1 WHERE customer_id = $2 AND deleted_at IS NULL",
+    )
+    .bind(deleted_at)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
         Ok(_) => Ok(StatusCode::OK),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
-					}
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Brings a customer back from the trash while it is inside the restore
+/// window, along with the deals that were deleted together with them.
+pub async fn restore_customer(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let deleted_at = match sqlx::query_scalar::<_, DateTime<Utc>>("UPDATE customers c SET deleted_at = NULL FROM customers old WHERE old.id = c.id AND editable_by($1, c.owner_id, c.organization_id) AND c.id = $2 AND c.deleted_at > NOW() - make_interval(days => $3) RETURNING old.deleted_at")
+        .bind(user.id)
+        .bind(id)
+        .bind(RESTORE_WINDOW_DAYS)
+        .fetch_optional(&mut *tx)
+        .await
+    {
+        Ok(Some(deleted_at)) => deleted_at,
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                "customers",
+                user.id,
+                id,
+                (
+                    StatusCode::NOT_FOUND,
+                    "Customer not found in the trash".to_string(),
+                ),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Err(err) =
+        sqlx::query("UPDATE deals SET deleted_at = NULL WHERE customer_id = $1 AND deleted_at = $2")
+            .bind(id)
+            .bind(deleted_at)
+            .execute(&mut *tx)
+            .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
 }
 
-// pub async fn get_customer_names(
-//     State(state): State<AppState>,
-//     Json(req): Json<UserRequest>,
-// ) -> Result<Json<Vec<CustomerNames>>, impl IntoResponse> {
-//     match sqlx::query_as::<_, CustomerNames>("SELECT id, CONCAT(firstName, ' ', lastName) AS customer_name FROM customers WHERE owner_id = (SELECT id FROM users WHERE email = $1)")
-// 					.bind(req.email)
-// 					.fetch_all(&state.postgres)
-// 					.await {
-//         Ok(res) => Ok(Json(res)),
-//         Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
-// 					}
-// }
+pub async fn archive_customer(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    set_archived(&state, id, user.id, true).await
+}
+
+pub async fn unarchive_customer(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    set_archived(&state, id, user.id, false).await
+}
+
+async fn set_archived(
+    state: &AppState,
+    id: i32,
+    user_id: i32,
+    archived: bool,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match sqlx::query("UPDATE customers SET is_archived = $1, archived_at = CASE WHEN $1 THEN NOW() ELSE NULL END WHERE editable_by($2, owner_id, organization_id) AND id = $3 AND deleted_at IS NULL")
+        .bind(archived)
+        .bind(user_id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => Err(write_refused(
+            &state.postgres,
+            "customers",
+            user_id,
+            id,
+            (StatusCode::NOT_FOUND, "Customer not found".to_string()),
+        )
+        .await),
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// The first customer `user_id` can see with the same email or phone as the
+/// given ones, ignoring case and formatting.
+pub(crate) async fn find_existing_customer(
+    postgres: &PgPool,
+    user_id: i32,
+    email: &str,
+    phone: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    let phone = normalize_phone(phone);
+    sqlx::query_scalar::<_, i32>("SELECT id FROM customers WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL AND (lower(trim(email)) = $2 OR ($3 <> '' AND regexp_replace(phone, '[^0-9]', '', 'g') = $3)) ORDER BY id LIMIT 1")
+        .bind(user_id)
+        .bind(normalize_email(email))
+        .bind(phone.trim_start_matches('+'))
+        .fetch_optional(postgres)
+        .await
+}
+
+/// Checks customer fields against the `customers` table constraints. Fields
+/// passed as `None` are not being set and are skipped.
+pub(crate) fn validate_customer(
+    first_name: Option<&str>,
+    last_name: Option<&str>,
+    email: Option<&str>,
+    phone: Option<&str>,
+    priority: Option<i32>,
+) -> Result<(), String> {
+    if first_name.is_some_and(|name| name.trim().is_empty()) {
+        return Err("firstName must not be empty".to_string());
+    }
+    if last_name.is_some_and(|name| name.trim().is_empty()) {
+        return Err("lastName must not be empty".to_string());
+    }
+    if let Some(email) = email.map(str::trim) {
+        match email.split_once('@') {
+            Some((local, domain)) if !local.is_empty() && domain.contains('.') => {}
+            _ => return Err(format!("{email} is not a valid email address")),
+        }
+    }
+    if let Some(phone) = phone.map(str::trim) {
+        if phone.is_empty() || phone.chars().count() > 14 {
+            return Err("phone must be between 1 and 14 characters".to_string());
+        }
+    }
+    if priority.is_some_and(|p| !(1..=5).contains(&p)) {
+        return Err("priority must be between 1 and 5".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_new(
+        first_name: &str,
+        last_name: &str,
+        email: &str,
+        phone: &str,
+        priority: i32,
+    ) -> Result<(), String> {
+        validate_customer(
+            Some(first_name),
+            Some(last_name),
+            Some(email),
+            Some(phone),
+            Some(priority),
+        )
+    }
+
+    #[test]
+    fn complete_customers_pass() {
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "+44 20 7946", 1).is_ok());
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "12345678901234", 5).is_ok());
+    }
+
+    #[test]
+    fn fields_left_out_are_not_checked() {
+        assert!(validate_customer(None, None, None, None, None).is_ok());
+        assert!(validate_customer(None, None, None, None, Some(9)).is_err());
+    }
+
+    #[test]
+    fn names_must_not_be_blank() {
+        assert!(validate_new(" ", "Lovelace", "ada@example.com", "555", 1).is_err());
+        assert!(validate_new("Ada", "", "ada@example.com", "555", 1).is_err());
+    }
+
+    #[test]
+    fn emails_need_a_local_part_and_a_dotted_domain() {
+        for email in ["ada", "@example.com", "ada@localhost", ""] {
+            assert!(
+                validate_new("Ada", "Lovelace", email, "555", 1).is_err(),
+                "{email}"
+            );
+        }
+        assert!(validate_new("Ada", "Lovelace", " ada@example.com ", "555", 1).is_ok());
+    }
+
+    #[test]
+    fn phones_fit_the_column() {
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "  ", 1).is_err());
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "123456789012345", 1).is_err());
+    }
+
+    #[test]
+    fn priority_is_between_one_and_five() {
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "555", 0).is_err());
+        assert!(validate_new("Ada", "Lovelace", "ada@example.com", "555", 6).is_err());
+    }
+
+    fn deal(
+        id: i32,
+        status: DealStatus,
+        estimate: i32,
+        actual: Option<i32>,
+        archived: bool,
+    ) -> Deal {
+        Deal {
+            id,
+            title: None,
+            estimate_worth: estimate,
+            actual_worth: actual,
+            status: status.as_str().to_string(),
+            closed: None,
+            expected_close_date: None,
+            created_at: None,
+            last_updated: None,
+            customer_id: 1,
+            is_archived: archived,
+            owner_id: 1,
+            tags: Vec::new(),
+            custom_fields: serde_json::json!({}),
+            customer_name: "Ada Lovelace".to_string(),
+        }
+    }
+
+    #[test]
+    fn closed_value_prefers_the_actual_worth() {
+        let deals = [
+            deal(1, DealStatus::Closed, 100, Some(120), false),
+            deal(2, DealStatus::Closed, 50, None, true),
+            deal(3, DealStatus::Open, 1000, None, false),
+        ];
+        assert_eq!(closed_value(&deals), 170);
+    }
+
+    #[test]
+    fn open_pipeline_leaves_out_closed_and_archived_deals() {
+        let deals = [
+            deal(1, DealStatus::Open, 100, None, false),
+            deal(2, DealStatus::AwaitingResponse, 40, Some(999), false),
+            deal(3, DealStatus::Ready, 500, None, true),
+            deal(4, DealStatus::Closed, 70, None, false),
+        ];
+        assert_eq!(open_pipeline_value(&deals), 140);
+        assert_eq!(open_pipeline_value(&[]), 0);
+    }
+
+    #[test]
+    fn deals_are_grouped_by_status_in_order() {
+        let deals = vec![
+            deal(3, DealStatus::Open, 1, None, false),
+            deal(2, DealStatus::Closed, 1, None, false),
+            deal(1, DealStatus::Open, 1, None, false),
+        ];
+        let groups = group_by_status(deals);
+        assert_eq!(groups.len(), 2);
+        let ids = |status: DealStatus| -> Vec<i32> {
+            groups[status.as_str()].iter().map(|deal| deal.id).collect()
+        };
+        assert_eq!(ids(DealStatus::Open), [3, 1]);
+        assert_eq!(ids(DealStatus::Closed), [2]);
+    }
+}
@@ -1,13 +1,31 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 // use time::Date;
 
+use crate::auth::SessionUser;
 use crate::AppState;
 
+/// The longest range a time series can cover.
+const MAX_RANGE_DAYS: i64 = 5 * 366;
+
+const DEFAULT_WIDGET_LIMIT: i64 = 7;
+const MAX_WIDGET_LIMIT: i64 = 50;
+
 #[derive(Deserialize, Serialize)]
 pub struct DashboardData {
     sales_deals_info: SalesDealsInfo,
+    range: DashboardRange,
     sales_per_day_info: Vec<SalesPerDayInfo>,
+    /// Share of the deals created in the range that have since been closed.
+    conversion_rate: Option<f64>,
+    /// Average worth of the deals closed in the range.
+    average_deal_size: Option<f64>,
 }
 
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
@@ -19,10 +37,13 @@ pub struct SalesDealsInfo {
     total_amt_closed: Option<i64>,
 }
 
+/// Closed sales in one period of the series. `date` is the first day of the
+/// period; periods without sales are included with zero totals.
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
 pub struct SalesPerDayInfo {
     date: String,
     sales_total: i64,
+    deals_closed: i64,
 }
 
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
@@ -31,26 +52,92 @@ pub struct CustomerInfo {
     recordcount: i32,
 }
 
-#[derive(Deserialize)]
-pub struct UserRequest {
-    pub email: String,
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Granularity {
+    /// The unit name `date_trunc` and `interval` understand.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+            Granularity::Month => "month",
+        }
+    }
+
+    /// Start of the range when none is given: 30 days, 12 weeks or 12 months
+    /// back from `to`.
+    fn default_from(&self, to: NaiveDate) -> NaiveDate {
+        match self {
+            Granularity::Day => to - Duration::days(29),
+            Granularity::Week => to - Duration::weeks(11),
+            Granularity::Month => to.checked_sub_months(Months::new(11)).unwrap_or(to),
+        }
+    }
+}
+
+/// Query parameters of the dashboard. Both ends of the range are inclusive.
+#[derive(Deserialize, Default)]
+pub struct DashboardQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub granularity: Granularity,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DashboardRange {
+    from: NaiveDate,
+    to: NaiveDate,
+    granularity: Granularity,
+}
+
+#[derive(sqlx::FromRow)]
+struct ConversionCounts {
+    created: i64,
+    converted: i64,
 }
 
+/// Dashboard numbers for the session user, over the deals they can see.
 pub async fn get_dashboard_data(
     State(state): State<AppState>,
-    Json(req): Json<UserRequest>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<DashboardQuery>,
 ) -> Result<Json<DashboardData>, impl IntoResponse> {
+    let to = query.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = query
+        .from
+        .unwrap_or_else(|| query.granularity.default_from(to));
+    if from > to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "from must not be after to".to_string(),
+        ));
+    }
+    if (to - from).num_days() > MAX_RANGE_DAYS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The range can't be longer than {MAX_RANGE_DAYS} days"),
+        ));
+    }
+
     let sales_deals_info = match sqlx::query_as::<_, SalesDealsInfo>(
-        "SELECT 
+        "SELECT
         COUNT(*) FILTER (WHERE status = 'open') AS open,
         COUNT(*) FILTER (WHERE status = 'ready') AS ready,
         COUNT(*) FILTER (WHERE status = 'awaitingresponse') AS awaitingresponse,
         COUNT(*) FILTER (WHERE status = 'closed') AS closed,
-        SUM(estimate_worth) FILTER (where status = 'closed') AS total_amt_closed
+        SUM(COALESCE(actual_worth, estimate_worth)) FILTER (where status = 'closed') AS total_amt_closed
         FROM deals
-        WHERE owner_id = (SELECT id FROM users WHERE email = $1)",
+        WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL",
     )
-    .bind(&req.email.to_string())
+    .bind(user.id)
     .fetch_one(&state.postgres)
     .await
     {
@@ -58,16 +145,31 @@ pub async fn get_dashboard_data(
         Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
     };
 
+    // Every period in the range is generated up front and the deals joined
+    // onto it, so periods without sales come back as zeros.
     let sales_per_day_info = match sqlx::query_as::<_, SalesPerDayInfo>(
         "SELECT
-        TO_CHAR(DATE(deals.last_updated), 'yyyy-mm-dd') AS date, 
-        SUM(estimate_worth) AS sales_total
-        FROM deals
-        WHERE status = 'closed'
-        GROUP BY deals.last_updated
+        TO_CHAR(periods.period, 'yyyy-mm-dd') AS date,
+        COALESCE(SUM(COALESCE(d.actual_worth, d.estimate_worth)), 0)::bigint AS sales_total,
+        COUNT(d.id) AS deals_closed
+        FROM generate_series(
+            date_trunc($2, $3::timestamp),
+            date_trunc($2, $4::timestamp),
+            ('1 ' || $2)::interval
+        ) AS periods(period)
+        LEFT JOIN deals d ON visible_to($1, d.owner_id, d.organization_id)
+            AND d.status = 'closed'
+            AND d.deleted_at IS NULL
+            AND (d.closed_at AT TIME ZONE 'UTC')::date BETWEEN $3 AND $4
+            AND date_trunc($2, d.closed_at AT TIME ZONE 'UTC') = periods.period
+        GROUP BY periods.period
+        ORDER BY periods.period
         ",
     )
-    .bind(req.email)
+    .bind(user.id)
+    .bind(query.granularity.as_str())
+    .bind(from)
+    .bind(to)
     .fetch_all(&state.postgres)
     .await
     {
@@ -75,8 +177,344 @@ pub async fn get_dashboard_data(
         Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
     };
 
+    let conversion = match sqlx::query_as::<_, ConversionCounts>(
+        "SELECT
+        COUNT(*) AS created,
+        COUNT(*) FILTER (WHERE status = 'closed') AS converted
+        FROM deals
+        WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL
+        AND (created_at AT TIME ZONE 'UTC')::date BETWEEN $2 AND $3",
+    )
+    .bind(user.id)
+    .bind(from)
+    .bind(to)
+    .fetch_one(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let conversion_rate =
+        (conversion.created > 0).then(|| conversion.converted as f64 / conversion.created as f64);
+
+    let closed_count: i64 = sales_per_day_info.iter().map(|p| p.deals_closed).sum();
+    let closed_total: i64 = sales_per_day_info.iter().map(|p| p.sales_total).sum();
+    let average_deal_size = (closed_count > 0).then(|| closed_total as f64 / closed_count as f64);
+
     Ok(Json(DashboardData {
         sales_deals_info,
+        range: DashboardRange {
+            from,
+            to,
+            granularity: query.granularity,
+        },
         sales_per_day_info,
+        conversion_rate,
+        average_deal_size,
+    }))
+}
+
+/// Query parameters of the list widgets. The range is optional and inclusive.
+#[derive(Deserialize, Default)]
+pub struct WidgetQuery {
+    pub limit: Option<i64>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl WidgetQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_WIDGET_LIMIT)
+            .clamp(1, MAX_WIDGET_LIMIT)
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct TopCustomer {
+    pub id: i32,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub closed_value: i64,
+    pub deals_closed: i64,
+    pub last_closed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct RecentSale {
+    pub id: i32,
+    pub title: Option<String>,
+    pub customer_id: i32,
+    pub customer_name: Option<String>,
+    pub amount: i32,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricPeriod {
+    Week,
+    #[default]
+    Month,
+}
+
+#[derive(Deserialize, Default)]
+pub struct MetricsQuery {
+    #[serde(default)]
+    pub period: MetricPeriod,
+}
+
+/// A metric for the period so far next to the same stretch of the previous
+/// period.
+#[derive(Deserialize, Serialize)]
+pub struct MetricDelta {
+    pub current: i64,
+    pub previous: i64,
+    pub change: i64,
+    /// Relative change, left out when the previous value is zero.
+    pub change_pct: Option<f64>,
+}
+
+impl MetricDelta {
+    fn new(current: i64, previous: i64) -> Self {
+        MetricDelta {
+            current,
+            previous,
+            change: current - previous,
+            change_pct: (previous != 0)
+                .then(|| (current - previous) as f64 / previous as f64 * 100.0),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Metrics {
+    pub period: MetricPeriod,
+    pub current_start: DateTime<Utc>,
+    pub previous_start: DateTime<Utc>,
+    pub previous_end: DateTime<Utc>,
+    /// Customers added.
+    pub leads: MetricDelta,
+    pub deals_created: MetricDelta,
+    pub deals_closed: MetricDelta,
+    pub closed_value: MetricDelta,
+    /// Deals currently open, not compared with anything.
+    pub open_deals: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct MetricCounts {
+    leads: i64,
+    leads_previous: i64,
+    deals_created: i64,
+    deals_created_previous: i64,
+    deals_closed: i64,
+    deals_closed_previous: i64,
+    closed_value: i64,
+    closed_value_previous: i64,
+    open_deals: i64,
+}
+
+/// The session user's customers ranked by the value of their closed deals.
+pub async fn get_top_customers(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<WidgetQuery>,
+) -> Result<Json<Vec<TopCustomer>>, impl IntoResponse> {
+    match sqlx::query_as::<_, TopCustomer>(
+        "SELECT
+        c.id,
+        c.firstname,
+        c.lastname,
+        c.email,
+        COALESCE(SUM(COALESCE(d.actual_worth, d.estimate_worth)), 0)::bigint AS closed_value,
+        COUNT(*) AS deals_closed,
+        MAX(d.closed_at) AS last_closed_at
+        FROM deals d JOIN customers c ON c.id = d.customer_id
+        WHERE visible_to($1, d.owner_id, d.organization_id) AND d.status = 'closed' AND d.deleted_at IS NULL AND c.deleted_at IS NULL
+        AND ($2::date IS NULL OR (d.closed_at AT TIME ZONE 'UTC')::date >= $2)
+        AND ($3::date IS NULL OR (d.closed_at AT TIME ZONE 'UTC')::date <= $3)
+        GROUP BY c.id
+        ORDER BY closed_value DESC, c.id
+        LIMIT $4",
+    )
+    .bind(user.id)
+    .bind(query.from)
+    .bind(query.to)
+    .bind(query.limit())
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// The session user's most recently closed deals.
+pub async fn get_recent_sales(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<WidgetQuery>,
+) -> Result<Json<Vec<RecentSale>>, impl IntoResponse> {
+    match sqlx::query_as::<_, RecentSale>(
+        "SELECT
+        d.id,
+        d.title,
+        d.customer_id,
+        concat(c.firstname, ' ', c.lastname) AS customer_name,
+        COALESCE(d.actual_worth, d.estimate_worth, 0) AS amount,
+        d.closed_at
+        FROM deals d LEFT JOIN customers c ON c.id = d.customer_id
+        WHERE visible_to($1, d.owner_id, d.organization_id) AND d.status = 'closed' AND d.deleted_at IS NULL
+        AND ($2::date IS NULL OR (d.closed_at AT TIME ZONE 'UTC')::date >= $2)
+        AND ($3::date IS NULL OR (d.closed_at AT TIME ZONE 'UTC')::date <= $3)
+        ORDER BY d.closed_at DESC NULLS LAST, d.id DESC
+        LIMIT $4",
+    )
+    .bind(user.id)
+    .bind(query.from)
+    .bind(query.to)
+    .bind(query.limit())
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Headline numbers for the current week or month so far, each compared with
+/// the same stretch of the previous one. Weeks start on Monday.
+pub async fn get_metrics(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<MetricsQuery>,
+) -> Result<Json<Metrics>, impl IntoResponse> {
+    let now = Utc::now();
+    let today = now.date_naive();
+    let (current_date, previous_date) = match query.period {
+        MetricPeriod::Week => {
+            let start = today - Duration::days(i64::from(today.weekday().num_days_from_monday()));
+            (start, start - Duration::weeks(1))
+        }
+        MetricPeriod::Month => {
+            let start = today.with_day(1).unwrap_or(today);
+            (
+                start,
+                start.checked_sub_months(Months::new(1)).unwrap_or(start),
+            )
+        }
+    };
+    let current_start = current_date.and_time(Default::default()).and_utc();
+    let previous_start = previous_date.and_time(Default::default()).and_utc();
+    let previous_end = (previous_start + (now - current_start)).min(current_start);
+
+    let counts = match sqlx::query_as::<_, MetricCounts>(
+        "SELECT
+        (SELECT COUNT(*) FROM customers c WHERE visible_to($1, c.owner_id, c.organization_id) AND c.deleted_at IS NULL AND c.created_at >= $2 AND c.created_at < $3) AS leads,
+        (SELECT COUNT(*) FROM customers c WHERE visible_to($1, c.owner_id, c.organization_id) AND c.deleted_at IS NULL AND c.created_at >= $4 AND c.created_at < $5) AS leads_previous,
+        COUNT(*) FILTER (WHERE d.created_at >= $2 AND d.created_at < $3) AS deals_created,
+        COUNT(*) FILTER (WHERE d.created_at >= $4 AND d.created_at < $5) AS deals_created_previous,
+        COUNT(*) FILTER (WHERE d.status = 'closed' AND d.closed_at >= $2 AND d.closed_at < $3) AS deals_closed,
+        COUNT(*) FILTER (WHERE d.status = 'closed' AND d.closed_at >= $4 AND d.closed_at < $5) AS deals_closed_previous,
+        COALESCE(SUM(COALESCE(d.actual_worth, d.estimate_worth)) FILTER (WHERE d.status = 'closed' AND d.closed_at >= $2 AND d.closed_at < $3), 0)::bigint AS closed_value,
+        COALESCE(SUM(COALESCE(d.actual_worth, d.estimate_worth)) FILTER (WHERE d.status = 'closed' AND d.closed_at >= $4 AND d.closed_at < $5), 0)::bigint AS closed_value_previous,
+        COUNT(*) FILTER (WHERE d.status <> 'closed' AND NOT d.is_archived) AS open_deals
+        FROM deals d
+        WHERE visible_to($1, d.owner_id, d.organization_id) AND d.deleted_at IS NULL",
+    )
+    .bind(user.id)
+    .bind(current_start)
+    .bind(now)
+    .bind(previous_start)
+    .bind(previous_end)
+    .fetch_one(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    Ok(Json(Metrics {
+        period: query.period,
+        current_start,
+        previous_start,
+        previous_end,
+        leads: MetricDelta::new(counts.leads, counts.leads_previous),
+        deals_created: MetricDelta::new(counts.deals_created, counts.deals_created_previous),
+        deals_closed: MetricDelta::new(counts.deals_closed, counts.deals_closed_previous),
+        closed_value: MetricDelta::new(counts.closed_value, counts.closed_value_previous),
+        open_deals: counts.open_deals,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn default_ranges_cover_thirty_days_twelve_weeks_or_twelve_months() {
+        let to = date(2025, 3, 15);
+        assert_eq!(Granularity::Day.default_from(to), date(2025, 2, 14));
+        assert_eq!((to - Granularity::Day.default_from(to)).num_days() + 1, 30);
+        assert_eq!(Granularity::Week.default_from(to), date(2024, 12, 28));
+        assert_eq!(Granularity::Month.default_from(to), date(2024, 4, 15));
+    }
+
+    #[test]
+    fn monthly_ranges_clamp_to_the_end_of_shorter_months() {
+        assert_eq!(
+            Granularity::Month.default_from(date(2025, 3, 31)),
+            date(2024, 4, 30)
+        );
+    }
+
+    #[test]
+    fn granularities_are_units_postgres_understands() {
+        assert_eq!(Granularity::default().as_str(), "day");
+        assert_eq!(Granularity::Week.as_str(), "week");
+        assert_eq!(Granularity::Month.as_str(), "month");
+        let granularity: Granularity = serde_json::from_str(r#""month""#).unwrap();
+        assert!(granularity == Granularity::Month);
+    }
+
+    #[test]
+    fn widget_limits_default_to_seven_and_stay_between_one_and_fifty() {
+        let limit = |limit| {
+            WidgetQuery {
+                limit,
+                ..Default::default()
+            }
+            .limit()
+        };
+        assert_eq!(limit(None), 7);
+        assert_eq!(limit(Some(20)), 20);
+        assert_eq!(limit(Some(0)), 1);
+        assert_eq!(limit(Some(-3)), 1);
+        assert_eq!(limit(Some(500)), 50);
+    }
+
+    #[test]
+    fn deltas_compare_with_the_previous_period() {
+        let delta = MetricDelta::new(150, 100);
+        assert_eq!(delta.change, 50);
+        assert_eq!(delta.change_pct, Some(50.0));
+
+        let delta = MetricDelta::new(25, 100);
+        assert_eq!(delta.change, -75);
+        assert_eq!(delta.change_pct, Some(-75.0));
+    }
+
+    #[test]
+    fn deltas_from_zero_have_no_percentage() {
+        let delta = MetricDelta::new(4, 0);
+        assert_eq!(delta.change, 4);
+        assert_eq!(delta.change_pct, None);
+    }
+}
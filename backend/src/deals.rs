@@ -1,137 +1,1301 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
-    Json,
+    Extension, Json,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use std::fmt;
+use std::str::FromStr;
 
+use crate::activities::{fetch_deal_activities, Activity};
+use crate::auth::SessionUser;
+use crate::custom_fields::{FieldEntity, FieldFilters};
+use crate::customers::CustomerSummary;
+use crate::organizations::{present, write_refused};
+use crate::retention::RESTORE_WINDOW_DAYS;
 use crate::AppState;
 
+/// The pipeline stages a deal can be in. Stored in `deals.status` as the
+/// lowercase name, which is also what the dashboard counts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DealStatus {
+    Open,
+    Ready,
+    AwaitingResponse,
+    Closed,
+}
+
+impl DealStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DealStatus::Open => "open",
+            DealStatus::Ready => "ready",
+            DealStatus::AwaitingResponse => "awaitingresponse",
+            DealStatus::Closed => "closed",
+        }
+    }
+
+    /// Whether a deal may move from `self` to `next`. A closed deal can only
+    /// be reopened.
+    pub fn can_transition_to(&self, next: DealStatus) -> bool {
+        use DealStatus::*;
+        matches!(
+            (self, next),
+            (Open, Ready)
+                | (Open, AwaitingResponse)
+                | (Open, Closed)
+                | (Ready, Open)
+                | (Ready, AwaitingResponse)
+                | (Ready, Closed)
+                | (AwaitingResponse, Open)
+                | (AwaitingResponse, Ready)
+                | (AwaitingResponse, Closed)
+                | (Closed, Open)
+        )
+    }
+}
+
+impl fmt::Display for DealStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DealStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "open" => Ok(DealStatus::Open),
+            "ready" => Ok(DealStatus::Ready),
+            "awaitingresponse" => Ok(DealStatus::AwaitingResponse),
+            "closed" => Ok(DealStatus::Closed),
+            other => Err(format!("Unknown deal status: {other}")),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
 pub struct Deal {
     pub id: i32,
+    pub title: Option<String>,
     pub estimate_worth: i32,
+    pub actual_worth: Option<i32>,
     pub status: String,
-    pub closed: String,
+    pub closed: Option<String>,
+    pub expected_close_date: Option<NaiveDate>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+    pub customer_id: i32,
+    pub is_archived: bool,
+    /// The rep the deal is assigned to.
+    pub owner_id: i32,
+    pub tags: Vec<String>,
+    /// Custom field values keyed by the field's key.
+    pub custom_fields: serde_json::Value,
     pub customer_name: String,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct DealPage {
+    pub deals: Vec<Deal>,
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DealSort {
+    #[default]
+    CreatedAt,
+    LastUpdated,
+    EstimateWorth,
+    ActualWorth,
+    ExpectedCloseDate,
+    CustomerName,
+}
+
+impl DealSort {
+    /// The (never null) expression the list is ordered by, and the SQL type
+    /// a cursor value has to be cast back to.
+    fn expression(&self) -> (&'static str, &'static str) {
+        match self {
+            DealSort::CreatedAt => (
+                "COALESCE(d.created_at, 'epoch'::timestamptz)",
+                "timestamptz",
+            ),
+            DealSort::LastUpdated => (
+                "COALESCE(d.last_updated, 'epoch'::timestamptz)",
+                "timestamptz",
+            ),
+            DealSort::EstimateWorth => ("COALESCE(d.estimate_worth, 0)", "int"),
+            DealSort::ActualWorth => ("COALESCE(d.actual_worth, 0)", "int"),
+            DealSort::ExpectedCloseDate => {
+                ("COALESCE(d.expected_close_date, 'infinity'::date)", "date")
+            }
+            DealSort::CustomerName => ("lower(concat(c.firstname, ' ', c.lastname))", "text"),
+        }
+    }
+}
+
+/// Whether archived records show up in a list. They are left out unless asked for.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivedFilter {
+    #[default]
+    Exclude,
+    Include,
+    Only,
+}
+
+impl ArchivedFilter {
+    /// SQL condition for a table aliased as `alias`.
+    pub fn condition(&self, alias: &str) -> String {
+        match self {
+            ArchivedFilter::Exclude => format!(" AND NOT {alias}.is_archived"),
+            ArchivedFilter::Include => String::new(),
+            ArchivedFilter::Only => format!(" AND {alias}.is_archived"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query parameters accepted by the deal list.
+#[derive(Deserialize, Default)]
+pub struct DealFilter {
+    pub status: Option<DealStatus>,
+    pub customer_id: Option<i32>,
+    pub min_value: Option<i32>,
+    pub max_value: Option<i32>,
+    pub created_from: Option<NaiveDate>,
+    pub created_to: Option<NaiveDate>,
+    pub updated_from: Option<NaiveDate>,
+    pub updated_to: Option<NaiveDate>,
+    pub q: Option<String>,
+    #[serde(default)]
+    pub archived: ArchivedFilter,
+    #[serde(default)]
+    pub sort: DealSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub tags: Option<String>,
+    pub fields: Option<String>,
+}
+
+/// Position of the last row of a page: its sort value and id as a tiebreak.
+#[derive(Deserialize, Serialize)]
+struct DealCursor {
+    key: String,
+    id: i32,
+}
+
+impl DealCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DealListRow {
+    #[sqlx(flatten)]
+    deal: Deal,
+    sort_key: String,
+}
+
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
-pub struct DealDetailed {
+pub struct DealRecord {
     pub id: i32,
+    pub title: Option<String>,
+    pub description: Option<String>,
     pub estimate_worth: i32,
     pub actual_worth: Option<i32>,
     pub status: String,
-    pub closed: String,
-    pub customer_id: String,
+    pub expected_close_date: Option<NaiveDate>,
+    pub closed_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+    pub customer_id: i32,
+    pub is_archived: bool,
+    pub owner_id: i32,
+    pub organization_id: Option<i32>,
+    pub tags: Vec<String>,
+    pub custom_fields: serde_json::Value,
 }
 
-#[derive(Deserialize)]
-pub struct UserRequest {
-    pub email: String,
+/// Everything the single-deal page needs in one response.
+#[derive(Deserialize, Serialize)]
+pub struct DealDetailed {
+    #[serde(flatten)]
+    pub deal: DealRecord,
+    pub customer: Option<CustomerSummary>,
+    pub history: Vec<DealStatusChange>,
+    pub notes: Vec<DealNote>,
+    pub activities: Vec<Activity>,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct DealNote {
+    pub id: i32,
+    pub body: String,
+    pub author: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct DealStatusChange {
+    pub id: i32,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub changed_by: Option<String>,
+    pub changed_at: DateTime<Utc>,
 }
 
 #[derive(Deserialize)]
 pub struct NewDeal {
     pub estimatedworth: i32,
     pub cust_id: i32,
-    pub useremail: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub expected_close_date: Option<NaiveDate>,
+}
+
+/// Partial update of a deal. Fields left out of the request are unchanged,
+/// and nullable ones sent as `null` are cleared.
+#[derive(Deserialize)]
+pub struct DealUpdate {
+    #[serde(default, deserialize_with = "present")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    pub estimate_worth: Option<i32>,
+    #[serde(default, deserialize_with = "present")]
+    pub actual_worth: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub expected_close_date: Option<Option<NaiveDate>>,
+    pub customer_id: Option<i32>,
+    pub status: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct ChangeRequest {
     pub new_value: String,
-    pub email: String,
 }
 
+#[derive(Deserialize)]
+pub struct NewNote {
+    pub body: String,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
 pub async fn get_all_deals(
     State(state): State<AppState>,
-    Json(req): Json<UserRequest>,
-) -> Result<Json<Vec<Deal>>, impl IntoResponse> {
-    match sqlx::query_as::<_, Deal>("SELECT 
+    Extension(user): Extension<SessionUser>,
+    Query(filter): Query<DealFilter>,
+) -> Result<Json<DealPage>, impl IntoResponse> {
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let cursor = match filter.cursor.as_deref().map(DealCursor::decode) {
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return Err((StatusCode::BAD_REQUEST, "Invalid cursor".to_string())),
+        None => None,
+    };
+    let field_filters = match FieldFilters::parse(filter.tags.as_deref(), filter.fields.as_deref())
+    {
+        Ok(filters) => filters,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+    let (sort_expression, sort_type) = filter.sort.expression();
+
+    let mut count = QueryBuilder::<Postgres>::new(
+        "SELECT COUNT(*) FROM deals d LEFT JOIN customers c ON d.customer_id = c.id",
+    );
+    push_deal_filters(&mut count, user.id, &filter, &field_filters);
+    let total = match count
+        .build_query_scalar::<i64>()
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(total) => total,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new(
+        "SELECT 
         d.id, 
-        d.estimate_worth, 
+        d.title, 
+        COALESCE(d.estimate_worth, 0) AS estimate_worth, 
+        d.actual_worth, 
         d.status, 
         d.closed, 
-        (select concat(c.firstname, ' ', c.lastname) from customers WHERE id = d.customer_id) as customer_name
-        FROM deals d LEFT JOIN customers c ON d.customer_id = c.id WHERE c.owner_id = (SELECT id FROM users WHERE email = $1)")
-				.bind(req.email)
-				.fetch_all(&state.postgres)
-				.await {
-        Ok(res) => Ok(Json(res)),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        d.expected_close_date, 
+        d.created_at, 
+        d.last_updated, 
+        d.customer_id, 
+        d.is_archived, 
+        d.owner_id, 
+        d.tags, 
+        deal_custom_fields(d.id) AS custom_fields, 
+        concat(c.firstname, ' ', c.lastname) AS customer_name, 
+        ",
+    );
+    query.push(format!("({sort_expression})::text AS sort_key"));
+    query.push(" FROM deals d LEFT JOIN customers c ON d.customer_id = c.id");
+    push_deal_filters(&mut query, user.id, &filter, &field_filters);
+
+    let direction = match filter.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    if let Some(cursor) = cursor {
+        let comparison = match filter.order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        query.push(format!(
+            " AND ({sort_expression}, d.id) {comparison} (CAST("
+        ));
+        query.push_bind(cursor.key);
+        query.push(format!(" AS {sort_type}), "));
+        query.push_bind(cursor.id);
+        query.push(")");
+    }
+    query.push(format!(
+        " ORDER BY {sort_expression} {direction}, d.id {direction} LIMIT "
+    ));
+    query.push_bind(limit + 1);
 
+    let mut rows = match query
+        .build_query_as::<DealListRow>()
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(rows) => rows,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| {
+            DealCursor {
+                key: row.sort_key.clone(),
+                id: row.deal.id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    Ok(Json(DealPage {
+        deals: rows.into_iter().map(|row| row.deal).collect(),
+        total,
+        next_cursor,
+    }))
+}
+
+/// Appends the visibility scope and every filter in `filter` and `field_filters`
+/// as a WHERE clause.
+fn push_deal_filters(
+    query: &mut QueryBuilder<'_, Postgres>,
+    user_id: i32,
+    filter: &DealFilter,
+    field_filters: &FieldFilters,
+) {
+    query.push(" WHERE visible_to(");
+    query.push_bind(user_id);
+    query.push(", d.owner_id, d.organization_id) AND d.deleted_at IS NULL");
+    query.push(filter.archived.condition("d"));
+
+    if let Some(status) = filter.status {
+        query.push(" AND d.status = ");
+        query.push_bind(status.as_str());
+    }
+    if let Some(customer_id) = filter.customer_id {
+        query.push(" AND d.customer_id = ");
+        query.push_bind(customer_id);
+    }
+    if let Some(min_value) = filter.min_value {
+        query.push(" AND d.estimate_worth >= ");
+        query.push_bind(min_value);
+    }
+    if let Some(max_value) = filter.max_value {
+        query.push(" AND d.estimate_worth <= ");
+        query.push_bind(max_value);
     }
+    if let Some(from) = filter.created_from {
+        query.push(" AND d.created_at::date >= ");
+        query.push_bind(from);
+    }
+    if let Some(to) = filter.created_to {
+        query.push(" AND d.created_at::date <= ");
+        query.push_bind(to);
+    }
+    if let Some(from) = filter.updated_from {
+        query.push(" AND d.last_updated::date >= ");
+        query.push_bind(from);
+    }
+    if let Some(to) = filter.updated_to {
+        query.push(" AND d.last_updated::date <= ");
+        query.push_bind(to);
+    }
+    if let Some(q) = filter.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        query.push(" AND concat(c.firstname, ' ', c.lastname) ILIKE ");
+        query.push_bind(format!("%{}%", escape_like(q)));
+    }
+    field_filters.push(query, FieldEntity::Deal, "d");
+}
+
+pub(crate) fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
 pub async fn get_one_deal(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Path(id): Path<i32>,
-    Json(req): Json<UserRequest>,
-) -> Result<Json<DealDetailed>, StatusCode> {
-    match sqlx::query_as::<_, DealDetailed>(
+) -> Result<Json<DealDetailed>, impl IntoResponse> {
+    let deal = match sqlx::query_as::<_, DealRecord>(
         "SELECT 
         d.id, 
-        d.estimate_worth, 
+        d.title, 
+        d.description, 
+        COALESCE(d.estimate_worth, 0) AS estimate_worth, 
+        d.actual_worth, 
         d.status, 
-        d.closed, 
-        (select concat(c.firstname, ' ', c.lastname) from customers WHERE id = d.customer_id) as customer_name
-        FROM deals d LEFT JOIN customers c ON d.customer_id = c.id WHERE c.owner_id = (SELECT id FROM users WHERE email = $1) AND d.id = $2"
+        d.expected_close_date, 
+        d.closed_at, 
+        d.created_at, 
+        d.last_updated, 
+        d.customer_id, 
+        d.is_archived, 
+        d.owner_id, 
+        d.organization_id, 
+        d.tags, 
+        deal_custom_fields(d.id) AS custom_fields
+        FROM deals d WHERE visible_to($1, d.owner_id, d.organization_id) AND d.id = $2 AND d.deleted_at IS NULL",
     )
-       			.bind(req.email)
-					.bind(id)
-					.fetch_one(&state.postgres)
-					.await  {
-        Ok(res) => Ok(Json(res)),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR)
-					}
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(Some(deal)) => deal,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Deal not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let customer = match sqlx::query_as::<_, CustomerSummary>(
+        "SELECT id, firstname, lastname, email, phone, priority FROM customers WHERE id = $1",
+    )
+    .bind(deal.customer_id)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(customer) => customer,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let history = match fetch_history(&state.postgres, id).await {
+        Ok(history) => history,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let notes = match sqlx::query_as::<_, DealNote>(
+        "SELECT n.id, n.body, u.email AS author, n.created_at
+        FROM deal_notes n
+        LEFT JOIN users u ON u.id = n.author_id
+        WHERE n.deal_id = $1
+        ORDER BY n.created_at DESC, n.id DESC",
+    )
+    .bind(id)
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(notes) => notes,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let activities = match fetch_deal_activities(&state.postgres, id).await {
+        Ok(activities) => activities,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    Ok(Json(DealDetailed {
+        deal,
+        customer,
+        history,
+        notes,
+        activities,
+    }))
+}
+
+pub async fn add_deal_note(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<NewNote>,
+) -> Result<StatusCode, impl IntoResponse> {
+    let body = match note_body(&req.body) {
+        Ok(body) => body,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    match sqlx::query("INSERT INTO deal_notes (deal_id, author_id, body) SELECT d.id, $2, $1 FROM deals d WHERE visible_to($2, d.owner_id, d.organization_id) AND d.id = $3 AND d.deleted_at IS NULL")
+        .bind(body)
+        .bind(user.id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            Err((StatusCode::NOT_FOUND, "Deal not found".to_string()))
+        }
+        Ok(_) => Ok(StatusCode::CREATED),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
 }
 
 pub async fn create_deal(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Json(req): Json<NewDeal>,
-) -> Result<StatusCode, StatusCode> {
-    let Ok(_) = sqlx::query("INSERT INTO DEALS (status, closed, customer_id, owner_id, estimate_worth) VALUES ('open', 'closed', $1, (SELECT id FROM users WHERE email = $2), $3)")
+) -> Result<StatusCode, impl IntoResponse> {
+    if req.estimatedworth < 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "estimatedworth must not be negative".to_string(),
+        ));
+    }
+    if let Some(title) = &req.title {
+        if let Err(err) = validate_title(title) {
+            return Err((StatusCode::BAD_REQUEST, err));
+        }
+    }
+
+    match sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM customers WHERE id = $1 AND visible_to($2, owner_id, organization_id) AND deleted_at IS NULL)")
+        .bind(req.cust_id)
+        .bind(user.id)
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err((StatusCode::BAD_REQUEST, "Customer not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let deal_id = match sqlx::query_scalar::<_, i32>("INSERT INTO DEALS (status, customer_id, owner_id, organization_id, estimate_worth, title, description, expected_close_date) VALUES ($1, $2, $3, (SELECT organization_id FROM organization_members WHERE user_id = $3), $4, $5, $6, $7) RETURNING id")
+						.bind(DealStatus::Open.as_str())
 						.bind(req.cust_id)
-						.bind(req.useremail)
+						.bind(user.id)
                         .bind(req.estimatedworth)
-						.execute(&state.postgres)
-						.await else {
-		return Err(StatusCode::INTERNAL_SERVER_ERROR)
+                        .bind(req.title.as_deref().map(str::trim))
+                        .bind(&req.description)
+                        .bind(req.expected_close_date)
+						.fetch_one(&mut *tx)
+						.await {
+        Ok(id) => id,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
 	};
 
-    Ok(StatusCode::OK)
+    if let Err(err) = record_status_change(&mut tx, deal_id, None, DealStatus::Open, user.id).await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
 }
 
 pub async fn edit_deal(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Path(id): Path<i32>,
     Json(req): Json<ChangeRequest>,
 ) -> Result<StatusCode, impl IntoResponse> {
-    match sqlx::query("UPDATE deals SET status = $1, last_updated = NOW() WHERE owner_id = (SELECT id FROM users WHERE email = $2) AND id = $3")
-					.bind(req.new_value)
-					.bind(req.email)
-					.bind(id)
-					.execute(&state.postgres)
-					.await {
+    let new_status = match req.new_value.parse::<DealStatus>() {
+        Ok(status) => status,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+    };
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let current = match lock_deal(&mut tx, id, user.id).await {
+        Ok(Some(current)) => current,
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                "deals",
+                user.id,
+                id,
+                (StatusCode::NOT_FOUND, "Deal not found".to_string()),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    change_status(&mut tx, id, &current, new_status, user.id).await?;
+
+    match tx.commit().await {
         Ok(_) => Ok(StatusCode::OK),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
     }
 }
 
+pub async fn update_deal(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<DealUpdate>,
+) -> Result<StatusCode, impl IntoResponse> {
+    let new_status = match req.status.as_deref().map(str::parse::<DealStatus>) {
+        Some(Ok(status)) => Some(status),
+        Some(Err(err)) => return Err((StatusCode::BAD_REQUEST, err)),
+        None => None,
+    };
+    if let Some(Some(title)) = &req.title {
+        if let Err(err) = validate_title(title) {
+            return Err((StatusCode::BAD_REQUEST, err));
+        }
+    }
+    for (field, value) in [
+        ("estimate_worth", req.estimate_worth),
+        ("actual_worth", req.actual_worth.flatten()),
+    ] {
+        if value.is_some_and(|v| v < 0) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{field} must not be negative"),
+            ));
+        }
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let current = match lock_deal(&mut tx, id, user.id).await {
+        Ok(Some(current)) => current,
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                "deals",
+                user.id,
+                id,
+                (StatusCode::NOT_FOUND, "Deal not found".to_string()),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Some(Some(date)) = req.expected_close_date {
+        if current
+            .created_at
+            .is_some_and(|created_at| date < created_at.date_naive())
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "expected_close_date cannot be before the deal was created".to_string(),
+            ));
+        }
+    }
+
+    if let Some(customer_id) = req.customer_id {
+        match sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM customers WHERE id = $1 AND visible_to($2, owner_id, organization_id) AND deleted_at IS NULL)")
+            .bind(customer_id)
+            .bind(user.id)
+            .fetch_one(&mut *tx)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return Err((StatusCode::BAD_REQUEST, "Customer not found".to_string())),
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        }
+    }
+
+    let mut update = QueryBuilder::<Postgres>::new("UPDATE deals SET last_updated = NOW()");
+    if let Some(title) = &req.title {
+        update.push(", title = ");
+        update.push_bind(title.as_deref().map(str::trim).map(str::to_string));
+    }
+    if let Some(description) = &req.description {
+        update.push(", description = ");
+        update.push_bind(description.clone());
+    }
+    if let Some(estimate_worth) = req.estimate_worth {
+        update.push(", estimate_worth = ");
+        update.push_bind(estimate_worth);
+    }
+    if let Some(actual_worth) = req.actual_worth {
+        update.push(", actual_worth = ");
+        update.push_bind(actual_worth);
+    }
+    if let Some(expected_close_date) = req.expected_close_date {
+        update.push(", expected_close_date = ");
+        update.push_bind(expected_close_date);
+    }
+    if let Some(customer_id) = req.customer_id {
+        update.push(", customer_id = ");
+        update.push_bind(customer_id);
+    }
+    update.push(" WHERE id = ");
+    update.push_bind(id);
+    update.push(" RETURNING status, actual_worth, created_at");
+
+    let current = match update
+        .build_query_as::<LockedDeal>()
+        .fetch_one(&mut *tx)
+        .await
+    {
+        Ok(updated) => updated,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Some(new_status) = new_status {
+        change_status(&mut tx, id, &current, new_status, user.id).await?;
+    }
+    // Closed deals written before actual_worth was required are left as they
+    // are until someone touches their status or actual_worth.
+    let closed = new_status.map_or(current.status == DealStatus::Closed.as_str(), |status| {
+        status == DealStatus::Closed
+    });
+    if closed && req.actual_worth.is_some() && current.actual_worth.is_none() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "A closed deal must have an actual_worth".to_string(),
+        ));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn get_deal_history(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<DealStatusChange>>, impl IntoResponse> {
+    match sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM deals WHERE visible_to($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL)")
+        .bind(user.id)
+        .bind(id)
+        .fetch_one(&state.postgres)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err((StatusCode::NOT_FOUND, "Deal not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    match fetch_history(&state.postgres, id).await {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct DeletedDeal {
+    pub id: i32,
+    pub title: Option<String>,
+    pub estimate_worth: i32,
+    pub status: String,
+    pub customer_name: String,
+    pub deleted_at: DateTime<Utc>,
+    pub purge_at: DateTime<Utc>,
+}
+
+/// Moves a deal to the trash. It can be restored until the restore window
+/// runs out, after which the purge job removes it for good.
 pub async fn destroy_deal(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Path(id): Path<i32>,
-    Json(req): Json<UserRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let Ok(_) = sqlx::query("DELETE FROM deals WHERE owner_id = (SELECT user_id FROM users WHERE email = $1) AND id = $2")
-					.bind(req.email)
+) -> Result<StatusCode, impl IntoResponse> {
+    match sqlx::query("UPDATE deals SET deleted_at = NOW() WHERE editable_by($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL")
+					.bind(user.id)
 					.bind(id)
 					.execute(&state.postgres)
-					.await else {
-							return Err(StatusCode::INTERNAL_SERVER_ERROR)
-					};
+					.await {
+        Ok(res) if res.rows_affected() == 0 => Err(write_refused(
+            &state.postgres,
+            "deals",
+            user.id,
+            id,
+            (StatusCode::NOT_FOUND, "Deal not found".to_string()),
+        )
+        .await),
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+					}
+}
+
+/// Brings a deal back from the trash while it is inside the restore window.
+/// A deal whose customer is still in the trash has to wait for the customer
+/// to be restored first.
+pub async fn restore_deal(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match sqlx::query_scalar::<_, bool>("SELECT c.deleted_at IS NOT NULL FROM deals d JOIN customers c ON c.id = d.customer_id WHERE editable_by($1, d.owner_id, d.organization_id) AND d.id = $2 AND d.deleted_at > NOW() - make_interval(days => $3) FOR UPDATE OF d")
+        .bind(user.id)
+        .bind(id)
+        .bind(RESTORE_WINDOW_DAYS)
+        .fetch_optional(&mut *tx)
+        .await
+    {
+        Ok(Some(false)) => {}
+        Ok(Some(true)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "The deal's customer is in the trash; restore the customer first".to_string(),
+            ))
+        }
+        Ok(None) => {
+            return Err(write_refused(
+                &state.postgres,
+                "deals",
+                user.id,
+                id,
+                (
+                    StatusCode::NOT_FOUND,
+                    "Deal not found in the trash".to_string(),
+                ),
+            )
+            .await)
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    if let Err(err) = sqlx::query("UPDATE deals SET deleted_at = NULL WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn get_deleted_deals(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<Vec<DeletedDeal>>, impl IntoResponse> {
+    match sqlx::query_as::<_, DeletedDeal>(
+        "SELECT
+        d.id,
+        d.title,
+        COALESCE(d.estimate_worth, 0) AS estimate_worth,
+        d.status,
+        concat(c.firstname, ' ', c.lastname) AS customer_name,
+        d.deleted_at,
+        d.deleted_at + make_interval(days => $2) AS purge_at
+        FROM deals d LEFT JOIN customers c ON d.customer_id = c.id
+        WHERE visible_to($1, d.owner_id, d.organization_id) AND d.deleted_at IS NOT NULL
+        ORDER BY d.deleted_at DESC",
+    )
+    .bind(user.id)
+    .bind(RESTORE_WINDOW_DAYS)
+    .fetch_all(&state.postgres)
+    .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn archive_deal(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    set_archived(&state, id, user.id, true).await
+}
+
+pub async fn unarchive_deal(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, impl IntoResponse> {
+    set_archived(&state, id, user.id, false).await
+}
 
-    Ok(StatusCode::OK)
+async fn set_archived(
+    state: &AppState,
+    id: i32,
+    user_id: i32,
+    archived: bool,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match sqlx::query("UPDATE deals SET is_archived = $1, archived_at = CASE WHEN $1 THEN NOW() ELSE NULL END WHERE editable_by($2, owner_id, organization_id) AND id = $3 AND deleted_at IS NULL")
+        .bind(archived)
+        .bind(user_id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => Err(write_refused(
+            &state.postgres,
+            "deals",
+            user_id,
+            id,
+            (StatusCode::NOT_FOUND, "Deal not found".to_string()),
+        )
+        .await),
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+async fn fetch_history(
+    postgres: &sqlx::PgPool,
+    deal_id: i32,
+) -> Result<Vec<DealStatusChange>, sqlx::Error> {
+    sqlx::query_as::<_, DealStatusChange>(
+        "SELECT
+        h.id,
+        h.from_status,
+        h.to_status,
+        u.email AS changed_by,
+        h.changed_at
+        FROM deal_status_history h
+        LEFT JOIN users u ON u.id = h.changed_by
+        WHERE h.deal_id = $1
+        ORDER BY h.changed_at, h.id",
+    )
+    .bind(deal_id)
+    .fetch_all(postgres)
+    .await
+}
+
+#[derive(sqlx::FromRow)]
+struct LockedDeal {
+    status: String,
+    actual_worth: Option<i32>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// Locks the deal row for the rest of the transaction, if the user may change
+/// it.
+async fn lock_deal(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: i32,
+    user_id: i32,
+) -> Result<Option<LockedDeal>, sqlx::Error> {
+    sqlx::query_as::<_, LockedDeal>("SELECT status, actual_worth, created_at FROM deals WHERE editable_by($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL FOR UPDATE")
+        .bind(user_id)
+        .bind(id)
+        .fetch_optional(&mut **tx)
+        .await
+}
+
+/// Moves a locked deal to `new_status`, enforcing the allowed transitions and
+/// recording the change in `deal_status_history`.
+async fn change_status(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: i32,
+    current: &LockedDeal,
+    new_status: DealStatus,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    // Rows written before statuses were validated may hold anything; treat
+    // those as open so they can be moved back into the pipeline.
+    let current_status = current.status.parse::<DealStatus>().ok();
+    if current_status == Some(new_status) {
+        return Ok(());
+    }
+    if !current_status
+        .unwrap_or(DealStatus::Open)
+        .can_transition_to(new_status)
+    {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Cannot move a deal from {} to {new_status}", current.status),
+        ));
+    }
+    if new_status == DealStatus::Closed && current.actual_worth.is_none() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Set actual_worth before closing a deal".to_string(),
+        ));
+    }
+
+    if let Err(err) = sqlx::query(
+        "UPDATE deals SET
+        status = $1,
+        closed_at = CASE WHEN $1 = 'closed' THEN NOW() ELSE NULL END,
+        last_updated = NOW()
+        WHERE id = $2",
+    )
+    .bind(new_status.as_str())
+    .bind(id)
+    .execute(&mut **tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = record_status_change(tx, id, Some(&current.status), new_status, user_id).await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    Ok(())
+}
+
+async fn record_status_change(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    deal_id: i32,
+    from: Option<&str>,
+    to: DealStatus,
+    user_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO deal_status_history (deal_id, from_status, to_status, changed_by) VALUES ($1, $2, $3, $4)")
+        .bind(deal_id)
+        .bind(from)
+        .bind(to.as_str())
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+fn validate_title(title: &str) -> Result<(), String> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err("title must not be empty".to_string());
+    }
+    if title.chars().count() > 255 {
+        return Err("title must be at most 255 characters".to_string());
+    }
+    Ok(())
+}
+
+/// The note's text without surrounding whitespace.
+fn note_body(body: &str) -> Result<&str, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err("Note must not be empty".to_string());
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [DealStatus; 4] = [
+        DealStatus::Open,
+        DealStatus::Ready,
+        DealStatus::AwaitingResponse,
+        DealStatus::Closed,
+    ];
+
+    #[test]
+    fn open_stages_can_move_to_any_other_stage() {
+        for from in [
+            DealStatus::Open,
+            DealStatus::Ready,
+            DealStatus::AwaitingResponse,
+        ] {
+            for to in ALL {
+                assert_eq!(from.can_transition_to(to), from != to, "{from} -> {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn closed_deals_can_only_be_reopened() {
+        assert!(DealStatus::Closed.can_transition_to(DealStatus::Open));
+        assert!(!DealStatus::Closed.can_transition_to(DealStatus::Ready));
+        assert!(!DealStatus::Closed.can_transition_to(DealStatus::AwaitingResponse));
+        assert!(!DealStatus::Closed.can_transition_to(DealStatus::Closed));
+    }
+
+    #[test]
+    fn statuses_parse_back_from_their_stored_names() {
+        for status in ALL {
+            assert_eq!(status.as_str().parse::<DealStatus>(), Ok(status));
+        }
+        assert_eq!(" Closed ".parse::<DealStatus>(), Ok(DealStatus::Closed));
+        assert!("close".parse::<DealStatus>().is_err());
+        assert!("".parse::<DealStatus>().is_err());
+    }
+
+    #[test]
+    fn updates_tell_cleared_fields_from_missing_ones() {
+        let update: DealUpdate = serde_json::from_str(
+            r#"{"description": null, "actual_worth": 1200, "expected_close_date": null}"#,
+        )
+        .unwrap();
+        assert_eq!(update.description, Some(None));
+        assert_eq!(update.actual_worth, Some(Some(1200)));
+        assert_eq!(update.expected_close_date, Some(None));
+        assert_eq!(update.title, None);
+        assert_eq!(update.estimate_worth, None);
+    }
+
+    #[test]
+    fn titles_must_have_between_one_and_255_characters() {
+        assert!(validate_title("Renewal").is_ok());
+        assert!(validate_title(&"é".repeat(255)).is_ok());
+        assert!(validate_title(&format!(" {} ", "a".repeat(255))).is_ok());
+        assert!(validate_title("").is_err());
+        assert!(validate_title("   ").is_err());
+        assert!(validate_title(&"a".repeat(256)).is_err());
+    }
+
+    fn filter_sql(filter: &DealFilter) -> String {
+        let field_filters = FieldFilters::parse(None, None).unwrap();
+        let mut query = QueryBuilder::<Postgres>::new("SELECT d.id FROM deals d");
+        push_deal_filters(&mut query, 1, filter, &field_filters);
+        query.sql().to_string()
+    }
+
+    #[test]
+    fn cursors_survive_a_round_trip() {
+        let cursor = DealCursor {
+            key: "2025-01-31 10:00:00+00".to_string(),
+            id: 42,
+        }
+        .encode();
+        let decoded = DealCursor::decode(&cursor).unwrap();
+        assert_eq!(decoded.key, "2025-01-31 10:00:00+00");
+        assert_eq!(decoded.id, 42);
+    }
+
+    #[test]
+    fn tampered_cursors_are_rejected() {
+        assert!(DealCursor::decode("not a cursor!").is_none());
+        assert!(DealCursor::decode(&URL_SAFE_NO_PAD.encode(b"{}")).is_none());
+        assert!(DealCursor::decode("").is_none());
+    }
+
+    #[test]
+    fn like_wildcards_are_searched_for_literally() {
+        assert_eq!(escape_like("100%_off\\"), "100\\%\\_off\\\\");
+        assert_eq!(escape_like("Jane Doe"), "Jane Doe");
+    }
+
+    #[test]
+    fn only_the_filters_given_are_applied() {
+        let sql = filter_sql(&DealFilter::default());
+        assert!(sql.contains("visible_to($1, d.owner_id, d.organization_id)"));
+        assert!(sql.contains("d.deleted_at IS NULL"));
+        assert!(!sql.contains("d.status"));
+        assert!(!sql.contains("ILIKE"));
+
+        let sql = filter_sql(&DealFilter {
+            status: Some(DealStatus::Ready),
+            min_value: Some(100),
+            max_value: Some(500),
+            q: Some(" jane ".to_string()),
+            ..DealFilter::default()
+        });
+        assert!(sql.contains(" AND d.status = $2"));
+        assert!(sql.contains(" AND d.estimate_worth >= $3"));
+        assert!(sql.contains(" AND d.estimate_worth <= $4"));
+        assert!(sql.contains("ILIKE $5"));
+    }
+
+    #[test]
+    fn blank_searches_are_ignored() {
+        let sql = filter_sql(&DealFilter {
+            q: Some("   ".to_string()),
+            ..DealFilter::default()
+        });
+        assert!(!sql.contains("ILIKE"));
+    }
+
+    #[test]
+    fn sorts_parse_from_snake_case_and_default_to_newest_first() {
+        let sort: DealSort = serde_json::from_str(r#""expected_close_date""#).unwrap();
+        assert_eq!(sort.expression().1, "date");
+        assert_eq!(DealSort::default().expression().1, "timestamptz");
+        assert_eq!(DealSort::CustomerName.expression().1, "text");
+        assert!(SortOrder::default() == SortOrder::Desc);
+        assert!(serde_json::from_str::<DealSort>(r#""title""#).is_err());
+    }
+
+    #[test]
+    fn archived_records_are_hidden_unless_asked_for() {
+        assert_eq!(
+            ArchivedFilter::default().condition("d"),
+            " AND NOT d.is_archived"
+        );
+        assert_eq!(ArchivedFilter::Include.condition("d"), "");
+        assert_eq!(ArchivedFilter::Only.condition("c"), " AND c.is_archived");
+
+        let filter: ArchivedFilter = serde_json::from_str(r#""only""#).unwrap();
+        assert!(filter == ArchivedFilter::Only);
+    }
+
+    #[test]
+    fn archived_deals_are_listed_only_when_asked_for() {
+        assert!(filter_sql(&DealFilter::default()).contains(" AND NOT d.is_archived"));
+        let sql = filter_sql(&DealFilter {
+            archived: ArchivedFilter::Include,
+            ..DealFilter::default()
+        });
+        assert!(!sql.contains("is_archived"));
+    }
+
+    #[test]
+    fn notes_are_trimmed_and_must_not_be_blank() {
+        assert_eq!(note_body("  Called back.\n"), Ok("Called back."));
+        assert_eq!(
+            note_body("First line\nSecond line"),
+            Ok("First line\nSecond line")
+        );
+        assert!(note_body("").is_err());
+        assert!(note_body(" \n\t ").is_err());
+    }
 }
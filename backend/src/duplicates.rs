@@ -0,0 +1,307 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::SessionUser;
+use crate::customers::{Customer, CustomerSummary};
+use crate::organizations::write_refused;
+use crate::AppState;
+
+/// Names at least this similar (Jaro-Winkler) are flagged as likely dupes.
+const NAME_SIMILARITY_THRESHOLD: f64 = 0.92;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchReason {
+    Email,
+    Phone,
+    Name,
+}
+
+#[derive(Serialize)]
+pub struct DuplicatePair {
+    pub customer: CustomerSummary,
+    pub duplicate: CustomerSummary,
+    pub reasons: Vec<MatchReason>,
+    pub name_similarity: f64,
+}
+
+#[derive(Deserialize)]
+pub struct MergeRequest {
+    pub duplicate_id: i32,
+}
+
+/// Lowercased and trimmed, so `Jane@Example.com ` matches `jane@example.com`.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Digits only, keeping a leading `+`, so formatting differences don't hide
+/// a match. Empty when there are no digits at all, which is no phone to
+/// match on.
+pub fn normalize_phone(phone: &str) -> String {
+    let phone = phone.trim();
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    if phone.starts_with('+') && !digits.is_empty() {
+        format!("+{digits}")
+    } else {
+        digits
+    }
+}
+
+fn normalize_name(first: &str, last: &str) -> String {
+    format!("{} {}", first.trim(), last.trim()).to_lowercase()
+}
+
+pub async fn find_duplicates(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<Vec<DuplicatePair>>, impl IntoResponse> {
+    let customers = match sqlx::query_as::<_, CustomerSummary>("SELECT id, firstname, lastname, email, phone, priority FROM customers WHERE visible_to($1, owner_id, organization_id) AND deleted_at IS NULL ORDER BY id")
+        .bind(user.id)
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let emails: Vec<String> = customers
+        .iter()
+        .map(|c| normalize_email(&c.email))
+        .collect();
+    let phones: Vec<String> = customers
+        .iter()
+        .map(|c| normalize_phone(&c.phone))
+        .collect();
+    let names: Vec<String> = customers
+        .iter()
+        .map(|c| normalize_name(&c.firstname, &c.lastname))
+        .collect();
+
+    // Only compare customers that share a bucket: the same email, the same
+    // phone, or the same first letter of their last name for fuzzy matching.
+    let mut buckets: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, customer) in customers.iter().enumerate() {
+        buckets
+            .entry(format!("e:{}", emails[i]))
+            .or_default()
+            .push(i);
+        if !phones[i].is_empty() {
+            buckets
+                .entry(format!("p:{}", phones[i]))
+                .or_default()
+                .push(i);
+        }
+        let initial = customer
+            .lastname
+            .trim()
+            .chars()
+            .next()
+            .map(|c| c.to_lowercase().to_string())
+            .unwrap_or_default();
+        buckets.entry(format!("n:{initial}")).or_default().push(i);
+    }
+
+    let mut seen = HashSet::new();
+    let mut pairs = Vec::new();
+    for members in buckets.values() {
+        for (n, &a) in members.iter().enumerate() {
+            for &b in &members[n + 1..] {
+                let (a, b) = (a.min(b), a.max(b));
+                if !seen.insert((a, b)) {
+                    continue;
+                }
+
+                let name_similarity = strsim::jaro_winkler(&names[a], &names[b]);
+                let mut reasons = Vec::new();
+                if emails[a] == emails[b] {
+                    reasons.push(MatchReason::Email);
+                }
+                if !phones[a].is_empty() && phones[a] == phones[b] {
+                    reasons.push(MatchReason::Phone);
+                }
+                if name_similarity >= NAME_SIMILARITY_THRESHOLD {
+                    reasons.push(MatchReason::Name);
+                }
+                if !reasons.is_empty() {
+                    pairs.push((a, b, reasons, name_similarity));
+                }
+            }
+        }
+    }
+
+    // Strongest matches first: more reasons, then closer names.
+    pairs.sort_by(|x, y| {
+        y.2.len()
+            .cmp(&x.2.len())
+            .then(y.3.total_cmp(&x.3))
+            .then((x.0, x.1).cmp(&(y.0, y.1)))
+    });
+
+    let res = pairs
+        .into_iter()
+        .map(|(a, b, reasons, name_similarity)| DuplicatePair {
+            customer: customers[a].clone(),
+            duplicate: customers[b].clone(),
+            reasons,
+            name_similarity,
+        })
+        .collect();
+
+    Ok(Json(res))
+}
+
+/// Folds `duplicate_id` into the customer in the path. The duplicate's deals
+/// and activities are moved to the survivor, its tags and custom field values
+/// are merged in, and the duplicate is moved to the trash, all in one
+/// transaction. It can be restored from there until the restore window runs
+/// out.
+pub async fn merge_customers(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<MergeRequest>,
+) -> Result<Json<Customer>, impl IntoResponse> {
+    if id == req.duplicate_id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "A customer can't be merged into itself".to_string(),
+        ));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match sqlx::query_scalar::<_, i32>("SELECT id FROM customers WHERE editable_by($1, owner_id, organization_id) AND id IN ($2, $3) AND deleted_at IS NULL ORDER BY id FOR UPDATE")
+        .bind(user.id)
+        .bind(id)
+        .bind(req.duplicate_id)
+        .fetch_all(&mut *tx)
+        .await
+    {
+        Ok(found) if found.len() == 2 => {}
+        Ok(found) => {
+            let missing = if found.contains(&id) {
+                req.duplicate_id
+            } else {
+                id
+            };
+            return Err(write_refused(
+                &state.postgres,
+                "customers",
+                user.id,
+                missing,
+                (StatusCode::NOT_FOUND, "Customer not found".to_string()),
+            )
+            .await);
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    if let Err(err) = sqlx::query(
+        "UPDATE deals SET customer_id = $1, last_updated = NOW() WHERE customer_id = $2",
+    )
+    .bind(id)
+    .bind(req.duplicate_id)
+    .execute(&mut *tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = sqlx::query("UPDATE activities SET customer_id = $1 WHERE customer_id = $2")
+        .bind(id)
+        .bind(req.duplicate_id)
+        .execute(&mut *tx)
+        .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    // Values the survivor already has win over the duplicate's.
+    if let Err(err) = sqlx::query(
+        "INSERT INTO customer_field_values (customer_id, definition_id, value)
+        SELECT $1, definition_id, value FROM customer_field_values WHERE customer_id = $2
+        ON CONFLICT (customer_id, definition_id) DO NOTHING",
+    )
+    .bind(id)
+    .bind(req.duplicate_id)
+    .execute(&mut *tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = sqlx::query(
+        "UPDATE customers SET tags = ARRAY(
+            SELECT DISTINCT tag FROM unnest(tags || (SELECT tags FROM customers WHERE id = $2)) tag ORDER BY tag
+        ) WHERE id = $1",
+    )
+    .bind(id)
+    .bind(req.duplicate_id)
+    .execute(&mut *tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = sqlx::query("UPDATE customers SET deleted_at = NOW() WHERE id = $1")
+        .bind(req.duplicate_id)
+        .execute(&mut *tx)
+        .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    let survivor = match sqlx::query_as::<_, Customer>("SELECT id, firstname, lastname, email, phone, priority, is_archived, created_at, owner_id, organization_id, tags, customer_custom_fields(id) AS custom_fields FROM customers WHERE id = $1")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match tx.commit().await {
+        Ok(_) => Ok(Json(survivor)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails_ignore_case_and_surrounding_space() {
+        assert_eq!(normalize_email(" Jane@Example.COM "), "jane@example.com");
+    }
+
+    #[test]
+    fn phones_keep_only_digits_and_a_leading_plus() {
+        assert_eq!(normalize_phone("(555) 010-0199"), "5550100199");
+        assert_eq!(normalize_phone(" +44 20 7946 0958"), "+442079460958");
+        assert_eq!(normalize_phone("555+0100"), "5550100");
+    }
+
+    #[test]
+    fn phones_without_digits_are_no_phone() {
+        assert_eq!(normalize_phone("n/a"), "");
+        assert_eq!(normalize_phone("+"), "");
+        assert_eq!(normalize_phone(""), "");
+    }
+
+    #[test]
+    fn names_are_compared_lowercased_and_trimmed() {
+        assert_eq!(normalize_name(" Jane ", "DOE "), "jane doe");
+    }
+}
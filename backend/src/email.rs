@@ -0,0 +1,682 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+const LAYOUT_HTML: &str = include_str!("../templates/email/layout.html");
+const LAYOUT_TEXT: &str = include_str!("../templates/email/layout.txt");
+const WELCOME_HTML: &str = include_str!("../templates/email/welcome.html");
+const WELCOME_TEXT: &str = include_str!("../templates/email/welcome.txt");
+const VERIFICATION_HTML: &str = include_str!("../templates/email/verification.html");
+const VERIFICATION_TEXT: &str = include_str!("../templates/email/verification.txt");
+const RESET_HTML: &str = include_str!("../templates/email/reset.html");
+const RESET_TEXT: &str = include_str!("../templates/email/reset.txt");
+const RECEIPT_HTML: &str = include_str!("../templates/email/receipt.html");
+const RECEIPT_TEXT: &str = include_str!("../templates/email/receipt.txt");
+const NEWSLETTER_CONFIRMATION_HTML: &str =
+    include_str!("../templates/email/newsletter_confirmation.html");
+const NEWSLETTER_CONFIRMATION_TEXT: &str =
+    include_str!("../templates/email/newsletter_confirmation.txt");
+
+/// A rendered message, ready for a transport.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub enum EmailError {
+    /// The message couldn't be built, usually because of a bad address.
+    Invalid(String),
+    /// The provider couldn't be reached or the message couldn't be written.
+    Transport(String),
+    /// The provider answered, but didn't accept the message. `permanent`
+    /// is set when sending it again won't help.
+    Rejected {
+        status: u16,
+        body: String,
+        permanent: bool,
+    },
+    Config(String),
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Invalid(msg) => write!(f, "invalid email: {msg}"),
+            EmailError::Transport(msg) => write!(f, "couldn't send email: {msg}"),
+            EmailError::Rejected { status, body, .. } => {
+                write!(f, "email rejected with status {status}: {body}")
+            }
+            EmailError::Config(msg) => write!(f, "email is misconfigured: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for EmailError {}
+
+impl EmailError {
+    /// Whether trying again later can't help, e.g. a malformed address or a
+    /// provider refusing the message outright.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            EmailError::Invalid(_) | EmailError::Config(_) => true,
+            EmailError::Rejected { permanent, .. } => *permanent,
+            EmailError::Transport(_) => false,
+        }
+    }
+}
+
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), EmailError>;
+}
+
+/// Sends through the Mailgun messages API. `api_url` is the regional base,
+/// e.g. `https://api.eu.mailgun.net/v3`.
+pub struct MailgunTransport {
+    client: Client,
+    api_url: String,
+    domain: String,
+    key: String,
+}
+
+impl MailgunTransport {
+    pub fn new(api_url: &str, domain: &str, key: &str) -> Self {
+        Self {
+            client: Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            domain: domain.to_string(),
+            key: key.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailTransport for MailgunTransport {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        let params = [
+            ("from", email.from.as_str()),
+            ("to", email.to.as_str()),
+            ("subject", email.subject.as_str()),
+            ("text", email.text.as_str()),
+            ("html", email.html.as_str()),
+        ];
+
+        let res = self
+            .client
+            .post(format!("{}/{}/messages", self.api_url, self.domain))
+            .basic_auth("api", Some(&self.key))
+            .form(&params)
+            .send()
+            .await
+            .map_err(|err| EmailError::Transport(err.to_string()))?;
+
+        check_response(res).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Implicit TLS, usually port 465.
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587.
+    StartTls,
+    /// No encryption at all. Only for local relays such as Mailpit.
+    None,
+}
+
+pub struct SmtpTransport {
+    inner: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        security: SmtpSecurity,
+        credentials: Option<(String, String)>,
+    ) -> Result<Self, EmailError> {
+        let mut builder = match security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+            SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                host,
+            )),
+        }
+        .map_err(|err| EmailError::Config(err.to_string()))?;
+
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            inner: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        let message = to_message(email)?;
+
+        match self.inner.send(message).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.is_permanent() => Err(EmailError::Rejected {
+                status: err.status().map(|code| code.into()).unwrap_or_default(),
+                body: err.to_string(),
+                permanent: true,
+            }),
+            Err(err) => Err(EmailError::Transport(err.to_string())),
+        }
+    }
+}
+
+/// Writes every message to `dir` as an `.eml` file instead of sending it,
+/// so development setups can read their mail without a provider.
+pub struct FileTransport {
+    dir: PathBuf,
+}
+
+impl FileTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl EmailTransport for FileTransport {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        let message = to_message(email)?;
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|err| EmailError::Transport(err.to_string()))?;
+
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4()
+        ));
+
+        tokio::fs::write(path, message.formatted())
+            .await
+            .map_err(|err| EmailError::Transport(err.to_string()))
+    }
+}
+
+/// Keeps messages in memory instead of sending them. Tests can hold on to a
+/// clone and assert on what was sent, or make it fail to exercise error
+/// handling.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    sent: Arc<Mutex<Vec<Email>>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything sent so far, oldest first.
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    #[cfg(test)]
+    pub fn sent_to(&self, address: &str) -> Vec<Email> {
+        self.sent()
+            .into_iter()
+            .filter(|email| email.to.eq_ignore_ascii_case(address))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+
+    /// Makes every send fail with `reason` until called again with `None`.
+    #[cfg(test)]
+    pub fn fail_with(&self, reason: Option<&str>) {
+        *self.failure.lock().unwrap() = reason.map(str::to_string);
+    }
+}
+
+#[async_trait]
+impl EmailTransport for MemoryTransport {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        if let Some(reason) = self.failure.lock().unwrap().clone() {
+            return Err(EmailError::Transport(reason));
+        }
+
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+/// Which transport to use and how to reach it, read from the secrets.
+pub struct EmailConfig {
+    /// One of `mailgun`, `smtp`, `file` or `memory`.
+    pub transport: String,
+    pub from: String,
+    pub mailgun_api_url: String,
+    pub mailgun_domain: String,
+    pub mailgun_key: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    /// One of `tls`, `starttls` or `none`.
+    pub smtp_security: String,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub file_dir: String,
+}
+
+pub struct Receipt {
+    pub name: String,
+    pub reference: String,
+    pub description: String,
+    /// Amount in the currency's smallest unit, as Stripe reports it.
+    pub amount: i64,
+    pub currency: String,
+    pub date: DateTime<Utc>,
+}
+
+/// Renders the transactional templates and hands them to the configured
+/// transport.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Arc<dyn EmailTransport>,
+    from: String,
+    domain: String,
+}
+
+impl Mailer {
+    pub fn new(transport: Arc<dyn EmailTransport>, from: &str, domain: &str) -> Self {
+        Self {
+            transport,
+            from: from.to_string(),
+            domain: domain.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn from_config(config: EmailConfig, domain: &str) -> Result<Self, EmailError> {
+        let transport: Arc<dyn EmailTransport> = match config.transport.as_str() {
+            "mailgun" => Arc::new(MailgunTransport::new(
+                &config.mailgun_api_url,
+                &config.mailgun_domain,
+                &config.mailgun_key,
+            )),
+            "smtp" => {
+                let Some(host) = config.smtp_host else {
+                    return Err(EmailError::Config("SMTP_HOST must be set".to_string()));
+                };
+                let security = match config.smtp_security.as_str() {
+                    "tls" => SmtpSecurity::Tls,
+                    "starttls" => SmtpSecurity::StartTls,
+                    "none" => SmtpSecurity::None,
+                    other => {
+                        return Err(EmailError::Config(format!("unknown SMTP security {other}")))
+                    }
+                };
+                let credentials = config.smtp_username.zip(config.smtp_password);
+                Arc::new(SmtpTransport::new(
+                    &host,
+                    config.smtp_port,
+                    security,
+                    credentials,
+                )?)
+            }
+            "file" => Arc::new(FileTransport::new(config.file_dir)),
+            "memory" => Arc::new(MemoryTransport::new()),
+            other => {
+                return Err(EmailError::Config(format!(
+                    "unknown email transport {other}"
+                )))
+            }
+        };
+
+        Ok(Self::new(transport, &config.from, domain))
+    }
+
+    pub async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        self.transport.send(email).await
+    }
+
+    pub fn welcome(&self, to: &str) -> Email {
+        let dashboard_url = format!("{}/dashboard", self.domain);
+        self.render(
+            to,
+            "Welcome to your new CRM",
+            WELCOME_HTML,
+            WELCOME_TEXT,
+            &[("email", to), ("dashboard_url", &dashboard_url)],
+        )
+    }
+
+    /// Asks `to` to confirm their address by opening `link`.
+    pub fn verification(&self, to: &str, link: &str, expires_in: &str) -> Email {
+        self.render(
+            to,
+            "Confirm your email address",
+            VERIFICATION_HTML,
+            VERIFICATION_TEXT,
+            &[("email", to), ("link", link), ("expires_in", expires_in)],
+        )
+    }
+
+    pub fn password_reset(&self, to: &str, link: &str, expires_in: &str) -> Email {
+        self.render(
+            to,
+            "Reset your password",
+            RESET_HTML,
+            RESET_TEXT,
+            &[("email", to), ("link", link), ("expires_in", expires_in)],
+        )
+    }
+
+    /// Asks `to` to confirm their newsletter subscription by opening `link`.
+    pub fn newsletter_confirmation(&self, to: &str, link: &str, expires_in: &str) -> Email {
+        self.render(
+            to,
+            "Confirm your newsletter subscription",
+            NEWSLETTER_CONFIRMATION_HTML,
+            NEWSLETTER_CONFIRMATION_TEXT,
+            &[("email", to), ("link", link), ("expires_in", expires_in)],
+        )
+    }
+
+    pub fn receipt(&self, to: &str, receipt: &Receipt) -> Email {
+        let amount = format!(
+            "{:.2} {}",
+            receipt.amount as f64 / 100.0,
+            receipt.currency.to_uppercase()
+        );
+        let date = receipt.date.format("%-d %B %Y").to_string();
+        self.render(
+            to,
+            &format!("Your receipt {}", receipt.reference),
+            RECEIPT_HTML,
+            RECEIPT_TEXT,
+            &[
+                ("name", &receipt.name),
+                ("reference", &receipt.reference),
+                ("description", &receipt.description),
+                ("amount", &amount),
+                ("date", &date),
+            ],
+        )
+    }
+
+    fn render(
+        &self,
+        to: &str,
+        subject: &str,
+        html: &str,
+        text: &str,
+        vars: &[(&str, &str)],
+    ) -> Email {
+        let html_vars: Vec<(&str, String)> = vars
+            .iter()
+            .map(|(key, value)| (*key, escape_html(value)))
+            .collect();
+        let html = fill(
+            LAYOUT_HTML,
+            &[
+                ("subject", escape_html(subject)),
+                ("domain", escape_html(&self.domain)),
+                ("content", fill(html, &html_vars)),
+            ],
+        );
+
+        let text_vars: Vec<(&str, String)> = vars
+            .iter()
+            .map(|(key, value)| (*key, value.to_string()))
+            .collect();
+        let text = fill(
+            LAYOUT_TEXT,
+            &[
+                ("domain", self.domain.clone()),
+                ("content", fill(text, &text_vars).trim_end().to_string()),
+            ],
+        );
+
+        Email {
+            from: self.from.clone(),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            html,
+            text,
+        }
+    }
+}
+
+/// Replaces every `{{key}}` in `template`. Values are inserted as given.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    vars.iter().fold(template.to_string(), |out, (key, value)| {
+        out.replace(&format!("{{{{{key}}}}}"), value)
+    })
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Turns a Mailgun API response into an error unless it was a success.
+/// Client errors are permanent, except for timeouts and rate limiting.
+pub async fn check_response(res: reqwest::Response) -> Result<(), EmailError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let status = status.as_u16();
+    Err(EmailError::Rejected {
+        status,
+        body: res.text().await.unwrap_or_default(),
+        permanent: (400..500).contains(&status) && status != 408 && status != 429,
+    })
+}
+
+fn to_message(email: &Email) -> Result<Message, EmailError> {
+    let from: Mailbox = email
+        .from
+        .parse()
+        .map_err(|err| EmailError::Invalid(format!("from {}: {err}", email.from)))?;
+    let to: Mailbox = email
+        .to
+        .parse()
+        .map_err(|err| EmailError::Invalid(format!("to {}: {err}", email.to)))?;
+
+    Message::builder()
+        .from(from)
+        .to(to)
+        .subject(&email.subject)
+        .multipart(MultiPart::alternative_plain_html(
+            email.text.clone(),
+            email.html.clone(),
+        ))
+        .map_err(|err| EmailError::Invalid(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn mailer(transport: &MemoryTransport) -> Mailer {
+        Mailer::new(
+            Arc::new(transport.clone()),
+            "CRM <hello@example.com>",
+            "https://crm.example.com/",
+        )
+    }
+
+    #[test]
+    fn escapes_html_special_characters() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn fills_every_occurrence_of_a_placeholder() {
+        let out = fill("{{a}} and {{a}}, not {{b}}", &[("a", "one".to_string())]);
+        assert_eq!(out, "one and one, not {{b}}");
+    }
+
+    #[test]
+    fn welcome_links_to_the_dashboard() {
+        let email = mailer(&MemoryTransport::new()).welcome("jane@example.com");
+        assert_eq!(email.from, "CRM <hello@example.com>");
+        assert_eq!(email.to, "jane@example.com");
+        assert!(email.html.contains("https://crm.example.com/dashboard"));
+        assert!(email.text.contains("https://crm.example.com/dashboard"));
+        assert!(email
+            .text
+            .contains("Your account for jane@example.com is ready"));
+        assert!(email
+            .text
+            .contains("your account at https://crm.example.com."));
+        assert!(!email.html.contains("{{"));
+        assert!(!email.text.contains("{{"));
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_only() {
+        let email = mailer(&MemoryTransport::new()).verification(
+            "jane@example.com",
+            "https://crm.example.com/verify?token=a&b=<c>",
+            "24 hours",
+        );
+        assert!(email
+            .html
+            .contains("https://crm.example.com/verify?token=a&amp;b=&lt;c&gt;"));
+        assert!(email
+            .text
+            .contains("https://crm.example.com/verify?token=a&b=<c>"));
+        assert!(email.text.contains("24 hours"));
+    }
+
+    #[test]
+    fn password_reset_includes_link_and_expiry() {
+        let email = mailer(&MemoryTransport::new()).password_reset(
+            "jane@example.com",
+            "https://crm.example.com/reset/abc",
+            "1 hour",
+        );
+        assert_eq!(email.subject, "Reset your password");
+        for part in [&email.html, &email.text] {
+            assert!(part.contains("https://crm.example.com/reset/abc"));
+            assert!(part.contains("1 hour"));
+        }
+    }
+
+    #[test]
+    fn receipt_formats_amount_in_major_units() {
+        let receipt = Receipt {
+            name: "Jane".to_string(),
+            reference: "INV-42".to_string(),
+            description: "Pro plan".to_string(),
+            amount: 1999,
+            currency: "eur".to_string(),
+            date: Utc.with_ymd_and_hms(2025, 3, 7, 12, 0, 0).unwrap(),
+        };
+        let email = mailer(&MemoryTransport::new()).receipt("jane@example.com", &receipt);
+        assert_eq!(email.subject, "Your receipt INV-42");
+        assert!(email.text.contains("Pro plan: 19.99 EUR"));
+        assert!(email.text.contains("Date: 7 March 2025"));
+    }
+
+    #[test]
+    fn memory_transport_records_sent_mail() {
+        let transport = MemoryTransport::new();
+        let mailer = mailer(&transport);
+
+        block_on(mailer.send(&mailer.welcome("jane@example.com"))).unwrap();
+        block_on(mailer.send(&mailer.welcome("john@example.com"))).unwrap();
+
+        assert_eq!(transport.sent().len(), 2);
+        let to_jane = transport.sent_to("JANE@example.com");
+        assert_eq!(to_jane.len(), 1);
+        assert_eq!(to_jane[0].subject, "Welcome to your new CRM");
+
+        transport.clear();
+        assert!(transport.sent().is_empty());
+    }
+
+    #[test]
+    fn memory_transport_can_fail_on_demand() {
+        let transport = MemoryTransport::new();
+        let mailer = mailer(&transport);
+        let email = mailer.welcome("jane@example.com");
+
+        transport.fail_with(Some("provider down"));
+        let err = block_on(mailer.send(&email)).unwrap_err();
+        assert!(matches!(err, EmailError::Transport(ref reason) if reason == "provider down"));
+        assert!(!err.is_permanent());
+        assert!(transport.sent().is_empty());
+
+        transport.fail_with(None);
+        block_on(mailer.send(&email)).unwrap();
+        assert_eq!(transport.sent().len(), 1);
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(EmailError::Invalid("bad".to_string()).is_permanent());
+        assert!(EmailError::Config("bad".to_string()).is_permanent());
+        let rejected = |permanent| EmailError::Rejected {
+            status: 400,
+            body: String::new(),
+            permanent,
+        };
+        assert!(rejected(true).is_permanent());
+        assert!(!rejected(false).is_permanent());
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected_before_sending() {
+        let mut email = mailer(&MemoryTransport::new()).welcome("jane@example.com");
+        assert!(to_message(&email).is_ok());
+
+        email.to = "not an address".to_string();
+        assert!(matches!(to_message(&email), Err(EmailError::Invalid(_))));
+    }
+}
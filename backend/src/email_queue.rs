@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::auth::{require_admin, SessionUser};
+use crate::email::{Email, EmailError, Mailer};
+use crate::mail::MailingLists;
+use crate::AppState;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 20;
+
+/// First retry delay. Each further attempt waits twice as long, up to
+/// `MAX_BACKOFF_SECS`.
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+/// Jobs claimed longer ago than this are assumed to belong to a worker that
+/// died mid-send and are handed out again.
+const STALE_LOCK_MINUTES: i32 = 10;
+
+/// Something for the worker to send, stored as the job's payload.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    Email(Email),
+    ListMember {
+        list: String,
+        address: String,
+        subscribed: bool,
+    },
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::Email(_) => "email",
+            Job::ListMember { .. } => "list_member",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct QueuedJob {
+    pub id: i32,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct QueueFilter {
+    /// Defaults to `dead`.
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RetriedJobs {
+    pub retried: u64,
+}
+
+/// Adds a job to the queue. Takes any executor so callers can queue mail in
+/// the same transaction as the change that triggers it.
+pub async fn enqueue<'e, E>(executor: E, job: &Job) -> Result<i32, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    sqlx::query_scalar("INSERT INTO email_queue (kind, payload) VALUES ($1, $2) RETURNING id")
+        .bind(job.kind())
+        .bind(sqlx::types::Json(job))
+        .fetch_one(executor)
+        .await
+}
+
+/// Queues a rendered email, logging instead of failing when it can't.
+/// For mail that shouldn't hold up the request that triggered it.
+pub async fn enqueue_email(postgres: &PgPool, email: Email) {
+    let to = email.to.clone();
+    if let Err(err) = enqueue(postgres, &Job::Email(email)).await {
+        eprintln!("Couldn't queue an email to {to}: {err}");
+    }
+}
+
+/// Starts the background task that sends queued jobs.
+pub fn spawn_worker(postgres: PgPool, mailer: Mailer, lists: MailingLists) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = process_due(&postgres, &mailer, &lists).await {
+                eprintln!("Error processing the email queue: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Sends every due job, a batch at a time.
+async fn process_due(
+    postgres: &PgPool,
+    mailer: &Mailer,
+    lists: &MailingLists,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE email_queue SET status = 'pending', locked_at = NULL WHERE status = 'sending' AND locked_at < NOW() - make_interval(mins => $1)")
+        .bind(STALE_LOCK_MINUTES)
+        .execute(postgres)
+        .await?;
+
+    loop {
+        let jobs = sqlx::query_as::<_, QueuedJob>(
+            "UPDATE email_queue SET status = 'sending', locked_at = NOW()
+            WHERE id IN (
+                SELECT id FROM email_queue
+                WHERE status = 'pending' AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, payload, status, attempts, max_attempts, next_attempt_at, last_error, created_at, sent_at",
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(postgres)
+        .await?;
+
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        for job in jobs {
+            let result = match serde_json::from_value::<Job>(job.payload.clone()) {
+                Ok(payload) => run(&payload, mailer, lists).await,
+                Err(err) => Err(EmailError::Invalid(format!("unreadable payload: {err}"))),
+            };
+            record_result(postgres, &job, result).await?;
+        }
+    }
+}
+
+async fn run(job: &Job, mailer: &Mailer, lists: &MailingLists) -> Result<(), EmailError> {
+    match job {
+        Job::Email(email) => mailer.send(email).await,
+        Job::ListMember {
+            list,
+            address,
+            subscribed,
+        } => lists.set_member(list, address, *subscribed).await,
+    }
+}
+
+async fn record_result(
+    postgres: &PgPool,
+    job: &QueuedJob,
+    result: Result<(), EmailError>,
+) -> Result<(), sqlx::Error> {
+    let err = match result {
+        Ok(()) => {
+            sqlx::query("UPDATE email_queue SET status = 'sent', attempts = attempts + 1, sent_at = NOW(), locked_at = NULL, last_error = NULL WHERE id = $1")
+                .bind(job.id)
+                .execute(postgres)
+                .await?;
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+
+    let attempts = job.attempts + 1;
+    if err.is_permanent() || attempts >= job.max_attempts {
+        eprintln!(
+            "Giving up on {} job {} after {attempts} attempts: {err}",
+            job.kind, job.id
+        );
+        sqlx::query("UPDATE email_queue SET status = 'dead', attempts = $1, last_error = $2, locked_at = NULL WHERE id = $3")
+            .bind(attempts)
+            .bind(err.to_string())
+            .bind(job.id)
+            .execute(postgres)
+            .await?;
+    } else {
+        sqlx::query("UPDATE email_queue SET status = 'pending', attempts = $1, last_error = $2, locked_at = NULL, next_attempt_at = NOW() + make_interval(secs => $3) WHERE id = $4")
+            .bind(attempts)
+            .bind(err.to_string())
+            .bind(backoff_secs(attempts) as f64)
+            .bind(job.id)
+            .execute(postgres)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Exponential backoff with up to 10% jitter, so jobs that failed together
+/// don't all retry at the same moment.
+fn backoff_secs(attempts: i32) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    let delay = BASE_BACKOFF_SECS
+        .saturating_mul(2i64.saturating_pow(exponent))
+        .min(MAX_BACKOFF_SECS);
+    delay + fastrand::i64(0..=delay / 10)
+}
+
+/// Lists queued jobs, dead ones by default, newest first. Admins only.
+pub async fn get_queue(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(filter): Query<QueueFilter>,
+) -> Result<Json<Vec<QueuedJob>>, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    let status = filter.status.unwrap_or_else(|| "dead".to_string());
+    if !["pending", "sending", "sent", "dead"].contains(&status.as_str()) {
+        return Err((StatusCode::BAD_REQUEST, format!("Unknown status {status}")));
+    }
+    let limit = filter.limit.unwrap_or(100).clamp(1, 500);
+
+    match sqlx::query_as::<_, QueuedJob>("SELECT id, kind, payload, status, attempts, max_attempts, next_attempt_at, last_error, created_at, sent_at FROM email_queue WHERE status = $1 ORDER BY created_at DESC LIMIT $2")
+        .bind(status)
+        .bind(limit)
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Puts a dead job back in the queue with a fresh set of attempts.
+pub async fn retry_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<Json<QueuedJob>, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    match sqlx::query_as::<_, QueuedJob>("UPDATE email_queue SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE id = $1 AND status = 'dead' RETURNING id, kind, payload, status, attempts, max_attempts, next_attempt_at, last_error, created_at, sent_at")
+        .bind(id)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(job)) => Ok(Json(job)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            "No dead job with that id".to_string(),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Retries every dead job, e.g. after a provider outage.
+pub async fn retry_dead_jobs(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<RetriedJobs>, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    match sqlx::query("UPDATE email_queue SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE status = 'dead'")
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(RetriedJobs {
+            retried: res.rows_affected(),
+        })),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within_jitter(attempts: i32, base: i64) {
+        let delay = backoff_secs(attempts);
+        assert!(
+            (base..=base + base / 10).contains(&delay),
+            "attempt {attempts}: {delay} not in {base}..={}",
+            base + base / 10
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_after_every_attempt() {
+        assert_within_jitter(1, BASE_BACKOFF_SECS);
+        assert_within_jitter(2, BASE_BACKOFF_SECS * 2);
+        assert_within_jitter(3, BASE_BACKOFF_SECS * 4);
+        assert_within_jitter(6, BASE_BACKOFF_SECS * 32);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_within_jitter(12, MAX_BACKOFF_SECS);
+        assert_within_jitter(i32::MAX, MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn backoff_treats_unattempted_jobs_as_first_attempts() {
+        assert_within_jitter(0, BASE_BACKOFF_SECS);
+        assert_within_jitter(-3, BASE_BACKOFF_SECS);
+    }
+}
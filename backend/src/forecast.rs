@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{Datelike, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::auth::SessionUser;
+use crate::deals::DealStatus;
+use crate::AppState;
+
+const DEFAULT_MONTHS: u32 = 6;
+const MAX_MONTHS: u32 = 24;
+
+/// Often enough that the snapshot frozen when a month begins is at most this
+/// old.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Statuses an open deal can be in, with the win probability used when the
+/// user hasn't configured one.
+const DEFAULT_PROBABILITIES: [(DealStatus, f64); 3] = [
+    (DealStatus::Open, 0.1),
+    (DealStatus::AwaitingResponse, 0.3),
+    (DealStatus::Ready, 0.7),
+];
+
+#[derive(Deserialize, Default)]
+pub struct ForecastQuery {
+    pub months: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct ForecastBucket {
+    pub deals: i64,
+    /// Estimated worth of the deals, unweighted.
+    pub pipeline_value: i64,
+    /// Estimated worth weighted by each deal's win probability.
+    pub weighted_value: f64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ForecastMonth {
+    /// First day of the month.
+    pub month: NaiveDate,
+    #[serde(flatten)]
+    pub bucket: ForecastBucket,
+}
+
+/// How last month's forecast, as it stood before the month began, compares
+/// with what was actually closed in it.
+#[derive(Deserialize, Serialize)]
+pub struct PeriodComparison {
+    pub month: NaiveDate,
+    pub forecast: Option<f64>,
+    pub actual: i64,
+    pub difference: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Forecast {
+    pub probabilities: BTreeMap<String, f64>,
+    /// Overdue deals are counted in the current month, deals expected after
+    /// the last month are left out.
+    pub months: Vec<ForecastMonth>,
+    /// Open deals without an expected close date.
+    pub unscheduled: ForecastBucket,
+    pub previous_period: PeriodComparison,
+}
+
+#[derive(sqlx::FromRow)]
+struct OpenDeal {
+    status: String,
+    estimate_worth: i32,
+    expected_close_date: Option<NaiveDate>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Probability {
+    status: String,
+    probability: f64,
+}
+
+pub async fn get_forecast(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Json<Forecast>, impl IntoResponse> {
+    let months = query.months.unwrap_or(DEFAULT_MONTHS).clamp(1, MAX_MONTHS);
+
+    let current = month_start(Utc::now().date_naive());
+    let (probabilities, months, unscheduled) =
+        match project(&state.postgres, user.id, current, months).await {
+            Ok(res) => res,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        };
+
+    let previous = current
+        .checked_sub_months(Months::new(1))
+        .unwrap_or(current);
+
+    let forecast = match sqlx::query_scalar::<_, f64>(
+        "SELECT weighted_value FROM forecast_snapshots WHERE owner_id = $1 AND period = $2",
+    )
+    .bind(user.id)
+    .bind(previous)
+    .fetch_optional(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let actual = match sqlx::query_scalar::<_, i64>(
+        "SELECT COALESCE(SUM(COALESCE(actual_worth, estimate_worth)), 0)::bigint FROM deals
+        WHERE visible_to($1, owner_id, organization_id) AND status = 'closed' AND deleted_at IS NULL
+        AND (closed_at AT TIME ZONE 'UTC')::date >= $2 AND (closed_at AT TIME ZONE 'UTC')::date < $3",
+    )
+    .bind(user.id)
+    .bind(previous)
+    .bind(current)
+    .fetch_one(&state.postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    Ok(Json(Forecast {
+        probabilities,
+        months,
+        unscheduled,
+        previous_period: PeriodComparison {
+            month: previous,
+            forecast,
+            actual,
+            difference: forecast.map(|forecast| actual as f64 - forecast),
+        },
+    }))
+}
+
+pub async fn get_probabilities(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<BTreeMap<String, f64>>, impl IntoResponse> {
+    match fetch_probabilities(&state.postgres, user.id).await {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Sets the win probability of one or more open statuses. Statuses left out
+/// keep their current probability.
+pub async fn set_probabilities(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<BTreeMap<String, f64>>,
+) -> Result<Json<BTreeMap<String, f64>>, impl IntoResponse> {
+    let mut changes = Vec::new();
+    for (status, probability) in &req {
+        let status = match DealStatus::from_str(status) {
+            Ok(DealStatus::Closed) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Closed deals are always counted at full value".to_string(),
+                ))
+            }
+            Ok(status) => status,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err)),
+        };
+        if !(0.0..=1.0).contains(probability) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "probabilities must be between 0 and 1".to_string(),
+            ));
+        }
+        changes.push((status, *probability));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    for (status, probability) in changes {
+        if let Err(err) = sqlx::query(
+            "INSERT INTO forecast_probabilities (owner_id, status, probability) VALUES ($1, $2, $3)
+            ON CONFLICT (owner_id, status) DO UPDATE SET probability = EXCLUDED.probability",
+        )
+        .bind(user.id)
+        .bind(status.as_str())
+        .bind(probability)
+        .execute(&mut *tx)
+        .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match fetch_probabilities(&state.postgres, user.id).await {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Starts the background task that records every user's forecast, so each
+/// month's comparison has a snapshot whether or not anyone opened the
+/// forecast before it began.
+pub fn spawn_snapshot_task(postgres: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = snapshot_all(&postgres).await {
+                eprintln!("Error recording forecast snapshots: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Snapshots the users who can see an open deal, and those whose snapshots of
+/// coming months still need to catch up with their last deal closing.
+async fn snapshot_all(postgres: &PgPool) -> Result<(), sqlx::Error> {
+    let current = month_start(Utc::now().date_naive());
+    let users = sqlx::query_scalar::<_, i32>(
+        "SELECT owner_id FROM deals
+        WHERE status <> 'closed' AND NOT is_archived AND deleted_at IS NULL
+        UNION
+        SELECT m.user_id FROM organization_members m WHERE EXISTS (
+            SELECT 1 FROM deals d
+            WHERE d.organization_id = m.organization_id AND d.status <> 'closed' AND NOT d.is_archived AND d.deleted_at IS NULL
+        )
+        UNION
+        SELECT owner_id FROM forecast_snapshots WHERE period > $1",
+    )
+    .bind(current)
+    .fetch_all(postgres)
+    .await?;
+
+    for user_id in users {
+        let (_, months, _) = project(postgres, user_id, current, DEFAULT_MONTHS).await?;
+        record_snapshots(postgres, user_id, current, &months).await?;
+    }
+
+    Ok(())
+}
+
+/// The user's win probabilities and their open deals' forecast for `months`
+/// months from `current` on, plus the deals without a date.
+async fn project(
+    postgres: &PgPool,
+    user_id: i32,
+    current: NaiveDate,
+    months: u32,
+) -> Result<(BTreeMap<String, f64>, Vec<ForecastMonth>, ForecastBucket), sqlx::Error> {
+    let probabilities = fetch_probabilities(postgres, user_id).await?;
+
+    let deals = sqlx::query_as::<_, OpenDeal>("SELECT status, COALESCE(estimate_worth, 0) AS estimate_worth, expected_close_date FROM deals WHERE visible_to($1, owner_id, organization_id) AND status <> 'closed' AND NOT is_archived AND deleted_at IS NULL")
+        .bind(user_id)
+        .fetch_all(postgres)
+        .await?;
+
+    let (months, unscheduled) = bucket_deals(&deals, &probabilities, current, months);
+    Ok((probabilities, months, unscheduled))
+}
+
+/// Sorts deals into the month they are expected to close in. Overdue deals
+/// count in `current`, and deals past the last month are left out.
+fn bucket_deals(
+    deals: &[OpenDeal],
+    probabilities: &BTreeMap<String, f64>,
+    current: NaiveDate,
+    months: u32,
+) -> (Vec<ForecastMonth>, ForecastBucket) {
+    let periods: Vec<NaiveDate> = (0..months)
+        .filter_map(|n| current.checked_add_months(Months::new(n)))
+        .collect();
+
+    let mut buckets: Vec<ForecastBucket> =
+        periods.iter().map(|_| ForecastBucket::default()).collect();
+    let mut unscheduled = ForecastBucket::default();
+    for deal in deals {
+        let probability = probabilities.get(&deal.status).copied().unwrap_or(0.0);
+        let bucket = match deal.expected_close_date {
+            None => &mut unscheduled,
+            Some(date) => {
+                let month = month_start(date).max(current);
+                match periods.iter().position(|p| *p == month) {
+                    Some(i) => &mut buckets[i],
+                    None => continue,
+                }
+            }
+        };
+        bucket.deals += 1;
+        bucket.pipeline_value += i64::from(deal.estimate_worth);
+        bucket.weighted_value += f64::from(deal.estimate_worth) * probability;
+    }
+
+    let months = periods
+        .into_iter()
+        .zip(buckets)
+        .map(|(month, bucket)| ForecastMonth { month, bucket })
+        .collect();
+    (months, unscheduled)
+}
+
+/// The user's win probability for each open status, falling back to the
+/// defaults for statuses they haven't configured.
+async fn fetch_probabilities(
+    postgres: &PgPool,
+    owner_id: i32,
+) -> Result<BTreeMap<String, f64>, sqlx::Error> {
+    let mut probabilities: BTreeMap<String, f64> = DEFAULT_PROBABILITIES
+        .iter()
+        .map(|(status, probability)| (status.as_str().to_string(), *probability))
+        .collect();
+
+    let configured = sqlx::query_as::<_, Probability>(
+        "SELECT status, probability FROM forecast_probabilities WHERE owner_id = $1",
+    )
+    .bind(owner_id)
+    .fetch_all(postgres)
+    .await?;
+    for row in configured {
+        probabilities.insert(row.status, row.probability);
+    }
+
+    Ok(probabilities)
+}
+
+/// Keeps the stored forecast of every future month up to date, and freezes
+/// the current month's the first time it is seen, so next month's comparison
+/// is against a forecast made before the month's deals closed.
+async fn record_snapshots(
+    postgres: &PgPool,
+    owner_id: i32,
+    current: NaiveDate,
+    months: &[ForecastMonth],
+) -> Result<(), sqlx::Error> {
+    let mut tx = postgres.begin().await?;
+    for month in months {
+        let conflict = if month.month == current {
+            "DO NOTHING"
+        } else {
+            "DO UPDATE SET pipeline_value = EXCLUDED.pipeline_value, weighted_value = EXCLUDED.weighted_value, taken_at = NOW()"
+        };
+        sqlx::query(&format!(
+            "INSERT INTO forecast_snapshots (owner_id, period, pipeline_value, weighted_value) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (owner_id, period) {conflict}"
+        ))
+        .bind(owner_id)
+        .bind(month.month)
+        .bind(month.bucket.pipeline_value)
+        .bind(month.bucket.weighted_value)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn deal(status: DealStatus, worth: i32, expected: Option<NaiveDate>) -> OpenDeal {
+        OpenDeal {
+            status: status.as_str().to_string(),
+            estimate_worth: worth,
+            expected_close_date: expected,
+        }
+    }
+
+    fn defaults() -> BTreeMap<String, f64> {
+        DEFAULT_PROBABILITIES
+            .iter()
+            .map(|(status, probability)| (status.as_str().to_string(), *probability))
+            .collect()
+    }
+
+    #[test]
+    fn months_start_on_the_first() {
+        assert_eq!(month_start(date(2025, 2, 28)), date(2025, 2, 1));
+    }
+
+    #[test]
+    fn deals_land_in_the_month_they_are_expected_to_close() {
+        let deals = [
+            deal(DealStatus::Ready, 1000, Some(date(2025, 3, 20))),
+            deal(DealStatus::Open, 500, Some(date(2025, 4, 2))),
+            deal(DealStatus::AwaitingResponse, 200, Some(date(2025, 4, 30))),
+        ];
+        let (months, unscheduled) = bucket_deals(&deals, &defaults(), date(2025, 3, 1), 3);
+
+        let starts: Vec<_> = months.iter().map(|month| month.month).collect();
+        assert_eq!(
+            starts,
+            [date(2025, 3, 1), date(2025, 4, 1), date(2025, 5, 1)]
+        );
+        assert_eq!(months[0].bucket.deals, 1);
+        assert_eq!(months[0].bucket.pipeline_value, 1000);
+        assert!((months[0].bucket.weighted_value - 700.0).abs() < 1e-9);
+        assert_eq!(months[1].bucket.deals, 2);
+        assert!((months[1].bucket.weighted_value - 110.0).abs() < 1e-9);
+        assert_eq!(months[2].bucket.deals, 0);
+        assert_eq!(unscheduled.deals, 0);
+    }
+
+    #[test]
+    fn overdue_deals_count_now_and_later_ones_are_left_out() {
+        let deals = [
+            deal(DealStatus::Open, 100, Some(date(2024, 11, 5))),
+            deal(DealStatus::Open, 100, Some(date(2025, 9, 1))),
+            deal(DealStatus::Open, 300, None),
+        ];
+        let (months, unscheduled) = bucket_deals(&deals, &defaults(), date(2025, 3, 1), 2);
+
+        assert_eq!(months[0].bucket.deals, 1);
+        assert_eq!(months[1].bucket.deals, 0);
+        assert_eq!(unscheduled.deals, 1);
+        assert_eq!(unscheduled.pipeline_value, 300);
+    }
+
+    #[test]
+    fn statuses_without_a_probability_weigh_nothing() {
+        let deals = [OpenDeal {
+            status: "legacy".to_string(),
+            estimate_worth: 100,
+            expected_close_date: None,
+        }];
+        let (_, unscheduled) = bucket_deals(&deals, &defaults(), date(2025, 3, 1), 1);
+
+        assert_eq!(unscheduled.pipeline_value, 100);
+        assert_eq!(unscheduled.weighted_value, 0.0);
+    }
+}
@@ -0,0 +1,328 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::Deserialize;
+
+use crate::auth::SessionUser;
+use crate::media::{media_type, mime_type_for_url, uploaded_media};
+use crate::user::{require_profile_owner, Media};
+use crate::AppState;
+
+const MAX_ITEMS: usize = 50;
+const MAX_CAPTION_CHARS: usize = 500;
+
+#[derive(Deserialize)]
+pub struct NewGalleryItem {
+    /// A file uploaded with `purpose=gallery`, or any other page to link to.
+    pub url: String,
+    #[serde(default)]
+    pub caption: String,
+}
+
+#[derive(Deserialize)]
+pub struct GalleryOrder {
+    /// Every item's id, in the new order.
+    pub ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GalleryCaption {
+    pub caption: String,
+}
+
+pub async fn get_gallery(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Vec<Media>>, (StatusCode, String)> {
+    let media = match sqlx::query_scalar::<_, Option<serde_json::Value>>(
+        "SELECT media::jsonb FROM users WHERE username = $1",
+    )
+    .persistent(false)
+    .bind(&username)
+    .fetch_optional(&state.supabase_postgres)
+    .await
+    {
+        Ok(Some(media)) => media,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let (_, gallery) = split_media(media)?;
+    Ok(Json(gallery))
+}
+
+/// Adds an item to the end of the gallery. Files uploaded here are shown as
+/// the type they were detected as when they were uploaded; any other URL is
+/// shown as a link. Like every change to the gallery, only the owner of
+/// the profile can make it.
+pub async fn add_gallery_item(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(username): Path<String>,
+    Json(req): Json<NewGalleryItem>,
+) -> Result<(StatusCode, Json<Vec<Media>>), (StatusCode, String)> {
+    require_profile_owner(&state, user.id, &username).await?;
+
+    let url = req.url.trim();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Gallery items need an http or https URL".to_string(),
+        ));
+    }
+    check_caption(&req.caption)?;
+
+    let upload = match uploaded_media(&state, url).await {
+        Ok(upload) => upload,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let mut item = Media {
+        id: Some(uuid::Uuid::new_v4().to_string()),
+        info: req.caption.trim().to_string(),
+        r#type: "link".to_string(),
+        media: url.to_string(),
+        variants: None,
+        video: None,
+    };
+    match upload {
+        Some(upload) => {
+            let Some(r#type) = media_type(&upload.mime_type) else {
+                return Err((
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    format!("{} files can't be shown in a gallery", upload.mime_type),
+                ));
+            };
+            item.r#type = r#type.to_string();
+            item.variants = upload.variants.map(|variants| variants.0);
+            item.video = upload.video.map(|video| video.0);
+        }
+        // Files uploaded before types were recorded are judged by their name.
+        None if state.storage.key_for_url(url).is_some() => {
+            item.r#type = media_type(mime_type_for_url(url))
+                .unwrap_or("link")
+                .to_string();
+        }
+        None => {}
+    }
+
+    let gallery = edit_gallery(&state, &username, |gallery| {
+        if gallery.len() >= MAX_ITEMS {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("A gallery can have at most {MAX_ITEMS} items"),
+            ));
+        }
+        gallery.push(item);
+        Ok(())
+    })
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(gallery)))
+}
+
+/// Puts the gallery in the order given, which has to list every item once.
+pub async fn reorder_gallery(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(username): Path<String>,
+    Json(req): Json<GalleryOrder>,
+) -> Result<Json<Vec<Media>>, (StatusCode, String)> {
+    require_profile_owner(&state, user.id, &username).await?;
+
+    let gallery = edit_gallery(&state, &username, |gallery| {
+        let mut ordered = Vec::with_capacity(gallery.len());
+        for id in &req.ids {
+            match gallery.iter().position(|item| item.id.as_ref() == Some(id)) {
+                Some(index) => ordered.push(gallery.swap_remove(index)),
+                None => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("No gallery item {id}, or it's listed twice"),
+                    ))
+                }
+            }
+        }
+        if !gallery.is_empty() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Every gallery item has to be listed".to_string(),
+            ));
+        }
+        *gallery = ordered;
+        Ok(())
+    })
+    .await?;
+
+    Ok(Json(gallery))
+}
+
+pub async fn update_gallery_caption(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path((username, id)): Path<(String, String)>,
+    Json(req): Json<GalleryCaption>,
+) -> Result<Json<Vec<Media>>, (StatusCode, String)> {
+    require_profile_owner(&state, user.id, &username).await?;
+    check_caption(&req.caption)?;
+
+    let gallery = edit_gallery(&state, &username, |gallery| {
+        match gallery
+            .iter_mut()
+            .find(|item| item.id.as_deref() == Some(id.as_str()))
+        {
+            Some(item) => {
+                item.info = req.caption.trim().to_string();
+                Ok(())
+            }
+            None => Err((StatusCode::NOT_FOUND, "Gallery item not found".to_string())),
+        }
+    })
+    .await?;
+
+    Ok(Json(gallery))
+}
+
+/// Takes an item out of the gallery. An uploaded file is removed from
+/// storage by the media collector once nothing links to it.
+pub async fn remove_gallery_item(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path((username, id)): Path<(String, String)>,
+) -> Result<Json<Vec<Media>>, (StatusCode, String)> {
+    require_profile_owner(&state, user.id, &username).await?;
+
+    let gallery = edit_gallery(&state, &username, |gallery| {
+        let before = gallery.len();
+        gallery.retain(|item| item.id.as_deref() != Some(id.as_str()));
+        if gallery.len() == before {
+            return Err((StatusCode::NOT_FOUND, "Gallery item not found".to_string()));
+        }
+        Ok(())
+    })
+    .await?;
+
+    Ok(Json(gallery))
+}
+
+/// Applies `change` to the gallery with the profile locked, so concurrent
+/// edits don't overwrite each other, and returns the gallery as saved. The
+/// cover stays first.
+async fn edit_gallery<F>(
+    state: &AppState,
+    username: &str,
+    change: F,
+) -> Result<Vec<Media>, (StatusCode, String)>
+where
+    F: FnOnce(&mut Vec<Media>) -> Result<(), (StatusCode, String)>,
+{
+    let mut tx = match state.supabase_postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let media = match sqlx::query_scalar::<_, Option<serde_json::Value>>(
+        "SELECT media::jsonb FROM users WHERE username = $1 FOR UPDATE",
+    )
+    .persistent(false)
+    .bind(username)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(media)) => media,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let (mut media, mut gallery) = split_media(media)?;
+    change(&mut gallery)?;
+    media.extend(gallery.iter().cloned());
+
+    let media = match serde_json::to_value(&media) {
+        Ok(media) => media,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    if let Err(err) = sqlx::query("UPDATE users SET media = $1::jsonb WHERE username = $2")
+        .persistent(false)
+        .bind(media)
+        .bind(username)
+        .execute(&mut *tx)
+        .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(gallery),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Separates a profile's cover from its gallery items.
+fn split_media(
+    media: Option<serde_json::Value>,
+) -> Result<(Vec<Media>, Vec<Media>), (StatusCode, String)> {
+    let media: Vec<Media> = match media {
+        Some(media) => match serde_json::from_value(media) {
+            Ok(media) => media,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        },
+        None => Vec::new(),
+    };
+
+    Ok(media.into_iter().partition(|item| item.id.is_none()))
+}
+
+fn check_caption(caption: &str) -> Result<(), (StatusCode, String)> {
+    if caption.trim().chars().count() > MAX_CAPTION_CHARS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Captions can be at most {MAX_CAPTION_CHARS} characters"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_cover_is_kept_apart_from_gallery_items() {
+        let media = serde_json::json!([
+            { "info": "", "type": "image", "media": "https://cdn.example.com/cover.jpeg" },
+            { "id": "a", "info": "Brochure", "type": "pdf", "media": "https://cdn.example.com/a.pdf" },
+            { "id": "b", "info": "", "type": "link", "media": "https://example.com" },
+        ]);
+        let (cover, gallery) = split_media(Some(media)).unwrap();
+
+        assert_eq!(cover.len(), 1);
+        assert_eq!(cover[0].media, "https://cdn.example.com/cover.jpeg");
+        let ids: Vec<_> = gallery.iter().map(|item| item.id.as_deref()).collect();
+        assert_eq!(ids, [Some("a"), Some("b")]);
+    }
+
+    #[test]
+    fn profiles_without_media_have_an_empty_gallery() {
+        let (cover, gallery) = split_media(None).unwrap();
+        assert!(cover.is_empty());
+        assert!(gallery.is_empty());
+    }
+
+    #[test]
+    fn malformed_media_is_an_error() {
+        let err = split_media(Some(serde_json::json!({ "media": "x" }))).unwrap_err();
+        assert_eq!(err.0, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn captions_are_limited_in_characters_not_bytes() {
+        assert!(check_caption("").is_ok());
+        assert!(check_caption(&"é".repeat(MAX_CAPTION_CHARS)).is_ok());
+        assert!(check_caption(&format!("  {}  ", "a".repeat(MAX_CAPTION_CHARS))).is_ok());
+
+        let err = check_caption(&"a".repeat(MAX_CAPTION_CHARS + 1)).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader, ImageResult, Limits};
+use serde::{Deserialize, Serialize};
+
+/// What the `full` JPEG, which processed uploads are linked by, is stored as.
+pub const STORED_MIME_TYPE: &str = "image/jpeg";
+
+/// Larger images are refused before they are decoded.
+const MAX_DIMENSION: u32 = 10_000;
+const MAX_DECODED_BYTES: u64 = 256 * 1024 * 1024;
+
+const JPEG_QUALITY: u8 = 82;
+const WEBP_QUALITY: f32 = 80.0;
+
+/// Each variant's longest side in pixels. Smaller images are never enlarged.
+const SIZES: [(&str, u32); 3] = [("thumbnail", 256), ("card", 960), ("full", 2048)];
+
+/// One stored size and format of a processed image.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageVariant {
+    /// `thumbnail`, `card` or `full`.
+    pub name: String,
+    /// `webp` or `jpeg`.
+    pub format: String,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct EncodedImage {
+    pub name: &'static str,
+    pub format: &'static str,
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Whether uploads of `mime_type` are resized and re-encoded. GIFs are kept
+/// as they are so animations survive.
+pub fn is_processable(mime_type: &str) -> bool {
+    matches!(mime_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// Decodes `bytes`, turns the image upright according to its EXIF
+/// orientation and encodes every variant. Only the pixels are re-encoded, so
+/// EXIF data, GPS position included, is left behind. This is CPU-bound, so
+/// async code should call it with `spawn_blocking`.
+pub fn process(bytes: &[u8]) -> ImageResult<Vec<EncodedImage>> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut encoded = Vec::with_capacity(SIZES.len() * 2);
+    for (name, size) in SIZES {
+        let resized = if image.width() > size || image.height() > size {
+            image.resize(size, size, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        let (width, height) = (resized.width(), resized.height());
+
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(resized.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY))?;
+        encoded.push(EncodedImage {
+            name,
+            format: "jpeg",
+            mime_type: "image/jpeg",
+            width,
+            height,
+            bytes: jpeg,
+        });
+
+        let rgba = resized.to_rgba8();
+        let webp = webp::Encoder::from_rgba(&rgba, width, height).encode(WEBP_QUALITY);
+        encoded.push(EncodedImage {
+            name,
+            format: "webp",
+            mime_type: "image/webp",
+            width,
+            height,
+            bytes: webp.to_vec(),
+        });
+    }
+
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn sizes(encoded: &[EncodedImage]) -> Vec<(&str, &str, u32, u32)> {
+        encoded
+            .iter()
+            .map(|image| (image.name, image.format, image.width, image.height))
+            .collect()
+    }
+
+    #[test]
+    fn only_still_formats_we_can_reencode_are_processed() {
+        assert!(is_processable("image/jpeg"));
+        assert!(is_processable("image/png"));
+        assert!(is_processable("image/webp"));
+        assert!(!is_processable("image/gif"));
+        assert!(!is_processable("video/mp4"));
+    }
+
+    #[test]
+    fn large_images_are_scaled_down_keeping_their_shape() {
+        let encoded = process(&png(1200, 900)).unwrap();
+        assert_eq!(
+            sizes(&encoded),
+            [
+                ("thumbnail", "jpeg", 256, 192),
+                ("thumbnail", "webp", 256, 192),
+                ("card", "jpeg", 960, 720),
+                ("card", "webp", 960, 720),
+                ("full", "jpeg", 1200, 900),
+                ("full", "webp", 1200, 900),
+            ]
+        );
+    }
+
+    #[test]
+    fn portrait_images_are_bounded_by_their_height() {
+        let encoded = process(&png(600, 1200)).unwrap();
+        let thumbnail = &encoded[0];
+        assert_eq!((thumbnail.width, thumbnail.height), (128, 256));
+        let card = &encoded[2];
+        assert_eq!((card.width, card.height), (480, 960));
+    }
+
+    #[test]
+    fn small_images_are_never_enlarged() {
+        let encoded = process(&png(100, 50)).unwrap();
+        assert!(encoded
+            .iter()
+            .all(|image| (image.width, image.height) == (100, 50)));
+    }
+
+    #[test]
+    fn variants_are_encoded_as_labelled() {
+        for image in process(&png(300, 200)).unwrap() {
+            match image.format {
+                "jpeg" => {
+                    assert_eq!(image.mime_type, "image/jpeg");
+                    assert!(image.bytes.starts_with(&[0xFF, 0xD8]));
+                }
+                "webp" => {
+                    assert_eq!(image.mime_type, "image/webp");
+                    assert!(image.bytes.starts_with(b"RIFF"));
+                    assert_eq!(&image.bytes[8..12], b"WEBP");
+                }
+                other => panic!("unexpected format {other}"),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_or_broken_images_are_refused() {
+        assert!(process(&png(MAX_DIMENSION + 1, 1)).is_err());
+        assert!(process(b"not an image").is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{FromRequest, Multipart, Path, Request, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    Extension, Form, Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Postgres, Transaction};
+
+use crate::auth::{require_admin, SessionUser};
+use crate::AppState;
+
+/// How far a webhook's signed timestamp may be from our clock. Older
+/// requests are refused, so a captured one can't be replayed later on.
+pub const SIGNATURE_MAX_AGE_MINUTES: i32 = 15;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: usize,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct InboundEmail {
+    pub id: i32,
+    pub message_id: Option<String>,
+    pub sender: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: Option<String>,
+    pub attachments: serde_json::Value,
+    pub status: String,
+    pub customer_ids: Vec<i32>,
+    pub received_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct AssignInboundEmail {
+    pub customer_id: i32,
+}
+
+/// Takes a message forwarded by a Mailgun inbound route, posted as multipart
+/// (when it has attachments) or as a urlencoded form. Mailgun retries
+/// anything but a 2xx or 406, so a bad signature gets a 401: if our key is
+/// wrong, messages are retried until it is fixed instead of being dropped. A
+/// token that was already used gets a 406, since that request is a replay.
+pub async fn receive_inbound_email(
+    State(state): State<AppState>,
+    request: Request,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let (fields, attachments) = if content_type.starts_with("multipart/form-data") {
+        match Multipart::from_request(request, &state).await {
+            Ok(multipart) => read_multipart(multipart).await?,
+            Err(err) => return Err((err.status(), err.body_text())),
+        }
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        match Form::<HashMap<String, String>>::from_request(request, &state).await {
+            Ok(Form(fields)) => (fields, Vec::new()),
+            Err(err) => return Err((err.status(), err.body_text())),
+        }
+    } else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected multipart/form-data or application/x-www-form-urlencoded".to_string(),
+        ));
+    };
+
+    let field = |name: &str| fields.get(name).map(|value| value.trim()).unwrap_or("");
+
+    if !verify_signature(
+        &state.mailgun_webhook_key,
+        field("timestamp"),
+        field("token"),
+        field("signature"),
+        Utc::now().timestamp(),
+    ) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid signature".to_string()));
+    }
+
+    let sender = match fields.get("sender").or_else(|| fields.get("from")) {
+        Some(sender) => bare_address(sender),
+        None => return Err((StatusCode::BAD_REQUEST, "sender is missing".to_string())),
+    };
+    let subject = match field("subject") {
+        "" => "(no subject)",
+        subject => subject,
+    };
+    // The reply without the quoted conversation below it, when Mailgun could
+    // tell them apart.
+    let body = [field("stripped-text"), field("body-plain")]
+        .into_iter()
+        .find(|body| !body.is_empty());
+    let message_id = [field("Message-Id"), field("message-id")]
+        .into_iter()
+        .find(|id| !id.is_empty());
+
+    let attachments = match serde_json::to_value(&attachments) {
+        Ok(attachments) => attachments,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match sqlx::query(
+        "INSERT INTO inbound_webhook_tokens (token) VALUES ($1) ON CONFLICT DO NOTHING",
+    )
+    .bind(field("token"))
+    .execute(&mut *tx)
+    .await
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            return Err((
+                StatusCode::NOT_ACCEPTABLE,
+                "This request was already received".to_string(),
+            ))
+        }
+        Ok(_) => {}
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    let id = match sqlx::query_scalar::<_, i32>(
+        "INSERT INTO inbound_emails (message_id, sender, recipient, subject, body, attachments, status)
+        VALUES ($1, $2, $3, $4, $5, $6, 'triage')
+        ON CONFLICT (message_id) DO NOTHING
+        RETURNING id",
+    )
+    .bind(message_id)
+    .bind(&sender)
+    .bind(field("recipient"))
+    .bind(subject)
+    .bind(body)
+    .bind(attachments)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(id)) => id,
+        // Already received.
+        Ok(None) => return Ok(StatusCode::OK),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let customers = match sqlx::query_scalar::<_, i32>(
+        "SELECT id FROM customers WHERE lower(email) = lower($1) AND deleted_at IS NULL",
+    )
+    .bind(&sender)
+    .fetch_all(&mut *tx)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    // Several reps can have the same person as a customer; each gets the
+    // message on their own record.
+    for customer_id in customers {
+        if let Err(err) = log_on_customer(&mut tx, id, customer_id).await {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// The text fields of a multipart message, and what its attachments are.
+async fn read_multipart(
+    mut multipart: Multipart,
+) -> Result<(HashMap<String, String>, Vec<Attachment>), (StatusCode, String)> {
+    let mut fields = HashMap::new();
+    let mut attachments = Vec::new();
+
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err((err.status(), err.body_text())),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+
+        if field.file_name().is_some() || name.starts_with("attachment-") {
+            // Only what the attachment is gets recorded, not its contents.
+            let mut attachment = Attachment {
+                filename: field.file_name().map(str::to_string),
+                content_type: field.content_type().map(str::to_string),
+                size: 0,
+            };
+            loop {
+                match field.chunk().await {
+                    Ok(Some(chunk)) => attachment.size += chunk.len(),
+                    Ok(None) => break,
+                    Err(err) => return Err((err.status(), err.body_text())),
+                }
+            }
+            attachments.push(attachment);
+        } else {
+            match field.text().await {
+                Ok(value) => {
+                    fields.insert(name, value);
+                }
+                Err(err) => return Err((err.status(), err.body_text())),
+            }
+        }
+    }
+
+    Ok((fields, attachments))
+}
+
+/// Messages that didn't match a customer, newest first. Admins only.
+pub async fn get_triage(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<Vec<InboundEmail>>, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    match sqlx::query_as::<_, InboundEmail>("SELECT id, message_id, sender, recipient, subject, body, attachments, status, customer_ids, received_at FROM inbound_emails WHERE status = 'triage' ORDER BY received_at DESC")
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => Ok(Json(res)),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Logs a triaged message on a customer, as if its sender had matched.
+pub async fn assign_inbound_email(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<AssignInboundEmail>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match sqlx::query_scalar::<_, i32>(
+        "SELECT id FROM inbound_emails WHERE id = $1 AND status = 'triage' FOR UPDATE",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "No message in triage with that id".to_string(),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    match sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM customers WHERE id = $1 AND deleted_at IS NULL)",
+    )
+    .bind(req.customer_id)
+    .fetch_one(&mut *tx)
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err((StatusCode::BAD_REQUEST, "Customer not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    if let Err(err) = log_on_customer(&mut tx, id, req.customer_id).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Takes a message out of triage without logging it anywhere, e.g. spam.
+pub async fn dismiss_inbound_email(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    match sqlx::query(
+        "UPDATE inbound_emails SET status = 'dismissed' WHERE id = $1 AND status = 'triage'",
+    )
+    .bind(id)
+    .execute(&state.postgres)
+    .await
+    {
+        Ok(res) if res.rows_affected() == 0 => Err((
+            StatusCode::NOT_FOUND,
+            "No message in triage with that id".to_string(),
+        )),
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Records the message as a completed email activity for the customer's rep
+/// and marks it as matched.
+async fn log_on_customer(
+    tx: &mut Transaction<'_, Postgres>,
+    inbound_email_id: i32,
+    customer_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO activities (owner_id, customer_id, kind, subject, body, completed, completed_at, created_at)
+        SELECT c.owner_id, c.id, 'email', e.subject, e.body, TRUE, e.received_at, e.received_at
+        FROM inbound_emails e, customers c
+        WHERE e.id = $1 AND c.id = $2",
+    )
+    .bind(inbound_email_id)
+    .bind(customer_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query("UPDATE inbound_emails SET status = 'matched', customer_ids = array_append(customer_ids, $1) WHERE id = $2")
+        .bind(customer_id)
+        .bind(inbound_email_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Mailgun signs each request with an HMAC of its timestamp and token. The
+/// timestamp, in seconds, must also be within `SIGNATURE_MAX_AGE_MINUTES` of
+/// `now`.
+fn verify_signature(key: &str, timestamp: &str, token: &str, signature: &str, now: i64) -> bool {
+    if key == "None" || token.is_empty() {
+        return false;
+    }
+    let Ok(signed_at) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (now - signed_at).abs() > i64::from(SIGNATURE_MAX_AGE_MINUTES) * 60 {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(token.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// `Jane Doe <jane@example.com>` becomes `jane@example.com`.
+fn bare_address(value: &str) -> String {
+    match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].trim().to_string(),
+        _ => value.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "webhook key";
+    const NOW: i64 = 1_736_000_000;
+
+    fn signature(key: &str, timestamp: &str, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_a_fresh_signature() {
+        let timestamp = NOW.to_string();
+        let sig = signature(KEY, &timestamp, "token");
+        assert!(verify_signature(KEY, &timestamp, "token", &sig, NOW));
+        assert!(verify_signature(KEY, &timestamp, "token", &sig, NOW + 60));
+    }
+
+    #[test]
+    fn rejects_signatures_from_another_key_or_request() {
+        let timestamp = NOW.to_string();
+        let sig = signature("other key", &timestamp, "token");
+        assert!(!verify_signature(KEY, &timestamp, "token", &sig, NOW));
+
+        let sig = signature(KEY, &timestamp, "token");
+        assert!(!verify_signature(KEY, &timestamp, "other token", &sig, NOW));
+        assert!(!verify_signature(KEY, &timestamp, "token", "not hex", NOW));
+    }
+
+    #[test]
+    fn rejects_stale_or_future_timestamps() {
+        let max_age = i64::from(SIGNATURE_MAX_AGE_MINUTES) * 60;
+        for signed_at in [NOW - max_age - 1, NOW + max_age + 1] {
+            let timestamp = signed_at.to_string();
+            let sig = signature(KEY, &timestamp, "token");
+            assert!(!verify_signature(KEY, &timestamp, "token", &sig, NOW));
+        }
+
+        let timestamp = (NOW - max_age).to_string();
+        let sig = signature(KEY, &timestamp, "token");
+        assert!(verify_signature(KEY, &timestamp, "token", &sig, NOW));
+    }
+
+    #[test]
+    fn rejects_missing_timestamps_tokens_and_keys() {
+        let sig = signature(KEY, "", "token");
+        assert!(!verify_signature(KEY, "", "token", &sig, NOW));
+
+        let timestamp = NOW.to_string();
+        let sig = signature(KEY, &timestamp, "");
+        assert!(!verify_signature(KEY, &timestamp, "", &sig, NOW));
+
+        let sig = signature("None", &timestamp, "token");
+        assert!(!verify_signature("None", &timestamp, "token", &sig, NOW));
+    }
+
+    #[test]
+    fn bare_addresses_drop_the_display_name() {
+        assert_eq!(
+            bare_address("Jane Doe <jane@example.com>"),
+            "jane@example.com"
+        );
+        assert_eq!(
+            bare_address("\"Doe, Jane\" < jane@example.com >"),
+            "jane@example.com"
+        );
+        assert_eq!(bare_address(" jane@example.com "), "jane@example.com");
+        assert_eq!(bare_address("broken> <"), "broken> <");
+    }
+}
@@ -1,43 +1,62 @@
-use axum::{extract::State, http::StatusCode, Json};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::AppState;
+use crate::email::{check_response, EmailError};
 
-#[derive(Deserialize, Serialize)]
-pub struct EmailRequest {
-    email: String,
+/// Mailgun mailing list members API.
+#[derive(Clone)]
+pub struct MailingLists {
+    client: Client,
+    api_url: String,
+    key: String,
 }
 
-pub async fn subscribe(
-    State(state): State<AppState>,
-    Json(req): Json<EmailRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let ctx = Client::new();
-
-    let api_endpoint = format!(
-        "https://api.mailgun.net/v3/lists/mail@{}/members",
-        &state.mailgun_url
-    );
-
-    let params = sub_params(req.email);
-    let post = ctx
-        .post(api_endpoint)
-        .basic_auth("api", Some(&state.mailgun_key))
-        .form(&params);
+impl MailingLists {
+    pub fn new(api_url: &str, key: &str) -> Self {
+        Self {
+            client: Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            key: key.to_string(),
+        }
+    }
 
-    match post.send().await {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    /// Adds `address` to `list`, or updates its subscription if it is
+    /// already there.
+    pub async fn set_member(
+        &self,
+        list: &str,
+        address: &str,
+        subscribed: bool,
+    ) -> Result<(), EmailError> {
+        if self.key == "None" {
+            return Err(EmailError::Config("MAILGUN_KEY isn't set".to_string()));
+        }
+
+        let api_endpoint = format!("{}/lists/{}/members", self.api_url, list);
+
+        let params = sub_params(address.to_string(), subscribed);
+        let res = self
+            .client
+            .post(api_endpoint)
+            .basic_auth("api", Some(&self.key))
+            .form(&params)
+            .send()
+            .await
+            .map_err(|err| EmailError::Transport(err.to_string()))?;
+
+        check_response(res).await
     }
 }
 
-fn sub_params(recipient: String) -> HashMap<&'static str, String> {
+fn sub_params(recipient: String, subscribed: bool) -> HashMap<&'static str, String> {
     let mut params = HashMap::new();
 
     params.insert("address", recipient);
-    params.insert("subscribed", "True".to_string());
+    params.insert(
+        "subscribed",
+        if subscribed { "True" } else { "False" }.to_string(),
+    );
+    params.insert("upsert", "True".to_string());
 
     params
 }
@@ -1,34 +1,60 @@
 use axum::extract::FromRef;
 use axum::Router;
 use axum_extra::extract::cookie::Key;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
     PgPool,
 };
 use tower_http::services::{ServeDir, ServeFile};
 
+mod activities;
 mod auth;
+mod csv_io;
+mod custom_fields;
 mod customers;
 mod dashboard;
 mod deals;
+mod duplicates;
+mod email;
+mod email_queue;
+mod forecast;
+mod gallery;
+mod images;
+mod inbound_email;
 mod mail;
+mod media;
+mod media_gc;
+mod newsletter;
 mod order;
+mod organizations;
 mod payments;
+mod retention;
 mod router;
+mod storage;
 mod user;
+mod video;
 
+use email::{EmailConfig, Mailer};
+use mail::MailingLists;
 use router::create_api_router;
+use storage::{Storage, StorageConfig};
+use video::VideoConfig;
 
 #[derive(Clone)]
 pub struct AppState {
     pub postgres: PgPool,
     pub supabase_postgres: PgPool,
-    pub supabase_storage_url: String,
-    pub supabase_api_key: String,
     pub stripe_key: String,
     pub stripe_sub_price: String,
     pub mailgun_key: String,
     pub mailgun_url: String,
+    pub mailgun_webhook_key: String,
+    pub mailer: Mailer,
+    pub storage: Storage,
+    pub video: VideoConfig,
+    /// Signs links that work without a session, such as unsubscribe links.
+    pub signing_secret: String,
     pub domain: String,
     pub key: Key,
 }
@@ -48,16 +74,21 @@ async fn main(
         .run(&postgres)
         .await
         .expect("Failed to run migrations");
+    let email_config = grab_email_config(&secrets);
+    let signing_secret = grab_signing_secret(&secrets);
+    let storage_config = grab_storage_config(&secrets);
+    let video = grab_video_config(&secrets);
+    let mailing_lists = MailingLists::new(&email_config.mailgun_api_url, &email_config.mailgun_key);
+
     // Initialize Supabase PostgreSQL Pool
     let (
         stripe_key,
         stripe_sub_price,
         mailgun_key,
         mailgun_url,
+        mailgun_webhook_key,
         domain,
         supabase_url,
-        supabase_storage_url,
-        supabase_api_key,
     ) = grab_secrets(secrets);
 
     // let supabase_postgres = PgPool::connect(&supabase_url)
@@ -77,6 +108,11 @@ async fn main(
         .await
         .expect("Failed to connect to Supabase PostgreSQL");
 
+    let mailer = Mailer::from_config(email_config, &domain).expect("Failed to set up email");
+    let local_storage_dir =
+        (storage_config.backend == "local").then(|| storage_config.local_dir.clone());
+    let storage = Storage::from_config(storage_config, &domain).expect("Failed to set up storage");
+
     let state = AppState {
         postgres,
         supabase_postgres,
@@ -84,15 +120,31 @@ async fn main(
         stripe_sub_price,
         mailgun_key,
         mailgun_url,
+        mailgun_webhook_key,
+        mailer,
+        storage,
+        video,
+        signing_secret,
         domain,
         key: Key::generate(),
-        supabase_api_key,
-        supabase_storage_url,
     };
 
+    retention::spawn_purge_task(state.postgres.clone());
+    forecast::spawn_snapshot_task(state.postgres.clone());
+    media_gc::spawn_collector(
+        state.postgres.clone(),
+        state.supabase_postgres.clone(),
+        state.storage.clone(),
+    );
+    email_queue::spawn_worker(state.postgres.clone(), state.mailer.clone(), mailing_lists);
+
     let api_router = create_api_router(state);
 
-    let router = Router::new().nest("/api", api_router).nest_service(
+    let mut router = Router::new().nest("/api", api_router);
+    if let Some(dir) = local_storage_dir {
+        router = router.nest_service(Storage::LOCAL_PATH, ServeDir::new(dir));
+    }
+    let router = router.nest_service(
         "/",
         ServeDir::new("dist").not_found_service(ServeFile::new("dist/index.html")),
     );
@@ -102,16 +154,7 @@ async fn main(
 
 fn grab_secrets(
     secrets: shuttle_runtime::SecretStore,
-) -> (
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-) {
+) -> (String, String, String, String, String, String, String) {
     let stripe_key = secrets
         .get("STRIPE_KEY")
         .unwrap_or_else(|| "None".to_string());
@@ -128,6 +171,10 @@ fn grab_secrets(
         .get("MAILGUN_URL")
         .unwrap_or_else(|| "None".to_string());
 
+    let mailgun_webhook_key = secrets
+        .get("MAILGUN_WEBHOOK_KEY")
+        .unwrap_or_else(|| "None".to_string());
+
     let domain = secrets
         .get("DOMAIN_URL")
         .unwrap_or_else(|| "http://127.0.0.1:8000".to_string());
@@ -137,22 +184,131 @@ fn grab_secrets(
         .get("SUPABASE_DB_URL")
         .expect("Supabase DB URL must be set");
 
-    let supabase_storage_url = secrets
-        .get("SUPABASE_STORAGE_URL")
-        .unwrap_or_else(|| "".to_string());
-
-    let supabase_api_key = secrets
-        .get("SUPABASE_API_KEY")
-        .unwrap_or_else(|| "".to_string());
-
     (
         stripe_key,
         stripe_sub_price,
         mailgun_key,
         mailgun_url,
+        mailgun_webhook_key,
         domain,
         supabase_url,
-        supabase_storage_url,
-        supabase_api_key,
     )
 }
+
+/// Email goes through Mailgun when a key is set and is written to `emails/`
+/// otherwise, unless `EMAIL_TRANSPORT` says differently.
+fn grab_email_config(secrets: &shuttle_runtime::SecretStore) -> EmailConfig {
+    let mailgun_key = secrets
+        .get("MAILGUN_KEY")
+        .unwrap_or_else(|| "None".to_string());
+
+    let mailgun_domain = secrets
+        .get("MAILGUN_URL")
+        .unwrap_or_else(|| "None".to_string());
+
+    let transport = secrets.get("EMAIL_TRANSPORT").unwrap_or_else(|| {
+        if mailgun_key == "None" {
+            "file".to_string()
+        } else {
+            "mailgun".to_string()
+        }
+    });
+
+    let from = secrets.get("EMAIL_FROM").unwrap_or_else(|| {
+        if mailgun_domain == "None" {
+            "no-reply@localhost".to_string()
+        } else {
+            format!("no-reply@{mailgun_domain}")
+        }
+    });
+
+    EmailConfig {
+        transport,
+        from,
+        mailgun_api_url: secrets
+            .get("MAILGUN_API_URL")
+            .unwrap_or_else(|| "https://api.mailgun.net/v3".to_string()),
+        mailgun_domain,
+        mailgun_key,
+        smtp_host: secrets.get("SMTP_HOST"),
+        smtp_port: secrets
+            .get("SMTP_PORT")
+            .map(|port| port.parse().expect("SMTP_PORT must be a port number")),
+        smtp_security: secrets
+            .get("SMTP_SECURITY")
+            .unwrap_or_else(|| "starttls".to_string()),
+        smtp_username: secrets.get("SMTP_USERNAME"),
+        smtp_password: secrets.get("SMTP_PASSWORD"),
+        file_dir: secrets
+            .get("EMAIL_FILE_DIR")
+            .unwrap_or_else(|| "emails".to_string()),
+    }
+}
+
+/// Media goes to Supabase Storage when it is configured and to a local
+/// directory otherwise, unless `STORAGE_BACKEND` says differently.
+fn grab_storage_config(secrets: &shuttle_runtime::SecretStore) -> StorageConfig {
+    let supabase_url = secrets.get("SUPABASE_STORAGE_URL").unwrap_or_default();
+
+    let backend = secrets.get("STORAGE_BACKEND").unwrap_or_else(|| {
+        if supabase_url.is_empty() {
+            "local".to_string()
+        } else {
+            "supabase".to_string()
+        }
+    });
+
+    StorageConfig {
+        backend,
+        supabase_url,
+        supabase_bucket: secrets
+            .get("SUPABASE_STORAGE_BUCKET")
+            .unwrap_or_else(|| "biz_touch".to_string()),
+        supabase_api_key: secrets.get("SUPABASE_API_KEY").unwrap_or_default(),
+        s3_endpoint: secrets.get("S3_ENDPOINT"),
+        s3_region: secrets
+            .get("S3_REGION")
+            .unwrap_or_else(|| "us-east-1".to_string()),
+        s3_bucket: secrets.get("S3_BUCKET"),
+        s3_access_key_id: secrets.get("S3_ACCESS_KEY_ID"),
+        s3_secret_access_key: secrets.get("S3_SECRET_ACCESS_KEY"),
+        s3_public_url: secrets.get("S3_PUBLIC_URL"),
+        local_dir: secrets
+            .get("STORAGE_DIR")
+            .unwrap_or_else(|| "uploads".to_string()),
+    }
+}
+
+/// Video covers are checked with ffprobe and given a poster with ffmpeg,
+/// which have to be installed unless their paths are set.
+fn grab_video_config(secrets: &shuttle_runtime::SecretStore) -> VideoConfig {
+    let number = |key: &str, default: u32| {
+        secrets
+            .get(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{key} must be a number"))
+            })
+            .unwrap_or(default)
+    };
+
+    VideoConfig {
+        max_duration_secs: number("VIDEO_MAX_SECONDS", 60) as f64,
+        max_long_side: number("VIDEO_MAX_LONG_SIDE", 1920),
+        max_short_side: number("VIDEO_MAX_SHORT_SIDE", 1080),
+        ffprobe: secrets
+            .get("FFPROBE_PATH")
+            .unwrap_or_else(|| "ffprobe".to_string()),
+        ffmpeg: secrets
+            .get("FFMPEG_PATH")
+            .unwrap_or_else(|| "ffmpeg".to_string()),
+    }
+}
+
+fn grab_signing_secret(secrets: &shuttle_runtime::SecretStore) -> String {
+    secrets.get("SIGNING_SECRET").unwrap_or_else(|| {
+        println!("SIGNING_SECRET isn't set, so signed links will stop working on restart");
+        URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+    })
+}
@@ -0,0 +1,702 @@
+use std::error::Error;
+
+use axum::{
+    body::Bytes,
+    extract::{multipart::Field, Multipart, Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+
+use crate::auth::SessionUser;
+use crate::images::{self, ImageVariant};
+use crate::user::require_profile_owner;
+use crate::video::{self, VideoError, VideoInfo};
+use crate::AppState;
+
+/// Largest image accepted for a profile photo, cover or gallery.
+pub const IMAGE_LIMIT: u64 = 10 * 1024 * 1024;
+/// Largest video accepted for a cover or gallery.
+pub const VIDEO_LIMIT: u64 = 100 * 1024 * 1024;
+/// Largest PDF accepted for a gallery.
+pub const DOCUMENT_LIMIT: u64 = 20 * 1024 * 1024;
+
+/// How much of the upload is read before its type is checked.
+const SNIFF_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaPurpose {
+    Profile,
+    Cover,
+    Gallery,
+}
+
+impl MediaPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaPurpose::Profile => "profile",
+            MediaPurpose::Cover => "cover",
+            MediaPurpose::Gallery => "gallery",
+        }
+    }
+
+    /// Where this purpose's uploads are kept in storage.
+    fn folder(&self) -> &'static str {
+        match self {
+            MediaPurpose::Profile => "profile_media",
+            MediaPurpose::Cover => "cover_media",
+            MediaPurpose::Gallery => "gallery_media",
+        }
+    }
+
+    /// The size limit for a file of `mime_type`, or `None` if files of that
+    /// type aren't accepted here. Profile photos can't be videos, and only
+    /// galleries take PDFs.
+    fn limit(&self, mime_type: &str) -> Option<u64> {
+        match (self, media_type(mime_type)) {
+            (_, Some("image")) => Some(IMAGE_LIMIT),
+            (MediaPurpose::Cover | MediaPurpose::Gallery, Some("video")) => Some(VIDEO_LIMIT),
+            (MediaPurpose::Gallery, Some("pdf")) => Some(DOCUMENT_LIMIT),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    pub purpose: MediaPurpose,
+    /// The URL of an earlier upload to replace in place.
+    pub replace: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UploadedMedia {
+    pub url: String,
+    pub mime_type: String,
+    /// `image`, `video` or `pdf`.
+    pub r#type: String,
+    pub size: u64,
+    /// The resized copies of a processed image. `url` is the `full` JPEG.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Vec<ImageVariant>>,
+    /// What a video was probed to be, and its poster.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoInfo>,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct UploadRecord {
+    pub mime_type: String,
+    pub variants: Option<sqlx::types::Json<Vec<ImageVariant>>>,
+    pub video: Option<sqlx::types::Json<VideoInfo>>,
+}
+
+/// Takes a `multipart/form-data` upload with the file in a field called
+/// `file` and streams it to storage as it arrives. The type is checked
+/// against the first bytes of the file rather than what the client claims.
+/// JPEG, PNG and WebP images are read whole instead and stored as resized
+/// variants with their metadata stripped; see `images::process`. Videos are
+/// spooled to disk and probed before they are stored; see `store_video`.
+/// Profile updates then reference the returned URL. Passing `replace` with
+/// the URL of an earlier upload of the same type writes over it instead.
+/// Only the owner of the profile can upload to it; see
+/// `require_profile_owner`.
+pub async fn upload_media(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(username): Path<String>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<UploadedMedia>), (StatusCode, String)> {
+    require_profile_owner(&state, user.id, &username).await?;
+
+    let mut field = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("file") => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Expected the file in a field called file".to_string(),
+                ))
+            }
+            Err(err) => return Err((err.status(), err.body_text())),
+        }
+    };
+
+    let mut head = Vec::new();
+    while head.len() < SNIFF_LEN {
+        match field.chunk().await {
+            Ok(Some(chunk)) => head.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(err) => return Err((err.status(), err.body_text())),
+        }
+    }
+    if head.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "The file is empty".to_string()));
+    }
+
+    let mime_type = match detect_mime_type(&head) {
+        Ok(mime_type) => mime_type,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let Some(limit) = query.purpose.limit(&mime_type) else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "{mime_type} files can't be used as {} media",
+                query.purpose.as_str()
+            ),
+        ));
+    };
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "{mime_type} files can be at most {} MB",
+                limit / 1024 / 1024
+            ),
+        )
+    };
+
+    if head.len() as u64 > limit {
+        return Err(too_large());
+    }
+
+    let processed = images::is_processable(&mime_type);
+    let stored_mime_type = if processed {
+        images::STORED_MIME_TYPE.to_string()
+    } else {
+        mime_type.clone()
+    };
+
+    // Replacing keeps the old URL, so profiles that link to it show the new
+    // file without being updated.
+    let replace = match &query.replace {
+        Some(url) => match replaceable(&state, &username, query.purpose, url).await {
+            Ok(Some((key, old)))
+                if old.mime_type == stored_mime_type && old.variants.is_some() == processed =>
+            {
+                Some(key)
+            }
+            Ok(Some((_, old))) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "A {} upload can only be replaced with the same kind of file",
+                        old.mime_type
+                    ),
+                ))
+            }
+            Ok(None) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Only this user's own uploads can be replaced".to_string(),
+                ))
+            }
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        },
+        None => None,
+    };
+    let replaced = replace.is_some();
+
+    let new_key = || {
+        format!(
+            "{}/{}.{}",
+            query.purpose.folder(),
+            uuid::Uuid::new_v4(),
+            get_file_extension(&mime_type)
+        )
+    };
+
+    let (key, size, variants, video) = if processed {
+        // Images have to be whole to be decoded, and are small enough to be.
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    head.extend_from_slice(&chunk);
+                    if head.len() as u64 > limit {
+                        return Err(too_large());
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => return Err((err.status(), err.body_text())),
+            }
+        }
+        let size = head.len() as u64;
+
+        // Each variant sits next to the `full` JPEG the upload is linked by.
+        let dir = match &replace {
+            Some(key) => key.rsplit_once('/').map_or("", |(dir, _)| dir).to_string(),
+            None => format!("{}/{}", query.purpose.folder(), uuid::Uuid::new_v4()),
+        };
+        let variants = store_image(&state, &dir, head, replaced).await?;
+        (format!("{dir}/full.jpeg"), size, Some(variants), None)
+    } else if media_type(&mime_type) == Some("video") {
+        let key = replace.unwrap_or_else(new_key);
+        // Videos are probed before they are stored, which needs them on disk.
+        let spool = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+        let stored = match spool_field(&spool, head, field, limit, too_large).await {
+            Ok(size) => store_video(&state, &key, &mime_type, &spool, replaced)
+                .await
+                .map(|video| (size, video)),
+            Err(err) => Err(err),
+        };
+        let _ = tokio::fs::remove_file(&spool).await;
+        let (size, video) = stored?;
+        (key, size, None, Some(video))
+    } else {
+        let key = replace.unwrap_or_else(new_key);
+        let size = store_stream(
+            &state, &key, &mime_type, head, field, limit, replaced, too_large,
+        )
+        .await?;
+        (key, size, None, None)
+    };
+    let url = state.storage.public_url(&key);
+
+    let recorded = if replaced {
+        sqlx::query("UPDATE media_uploads SET size = $1, variants = $2, video = $3 WHERE url = $4")
+            .bind(size as i64)
+            .bind(variants.as_ref().map(sqlx::types::Json))
+            .bind(video.as_ref().map(sqlx::types::Json))
+            .bind(&url)
+            .execute(&state.postgres)
+            .await
+    } else {
+        sqlx::query("INSERT INTO media_uploads (url, username, purpose, mime_type, size, variants, video) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+            .bind(&url)
+            .bind(&username)
+            .bind(query.purpose.as_str())
+            .bind(&stored_mime_type)
+            .bind(size as i64)
+            .bind(variants.as_ref().map(sqlx::types::Json))
+            .bind(video.as_ref().map(sqlx::types::Json))
+            .execute(&state.postgres)
+            .await
+    };
+    if let Err(err) = recorded {
+        // Nothing knows about new objects that weren't recorded, so they're
+        // removed rather than left behind.
+        if !replaced {
+            for url in stored_urls(&url, variants.as_deref(), video.as_ref()) {
+                let Some(key) = state.storage.key_for_url(&url) else {
+                    continue;
+                };
+                if let Err(err) = state.storage.delete(&key).await {
+                    eprintln!("Couldn't remove unrecorded upload {key}: {err}");
+                }
+            }
+        }
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadedMedia {
+            url,
+            r#type: media_type(&stored_mime_type).unwrap_or("image").to_string(),
+            mime_type: stored_mime_type,
+            size,
+            variants,
+            video,
+        }),
+    ))
+}
+
+/// Streams the rest of `field` to `key` after `head`, the part already read,
+/// and returns the file's size.
+#[allow(clippy::too_many_arguments)]
+async fn store_stream(
+    state: &AppState,
+    key: &str,
+    mime_type: &str,
+    head: Vec<u8>,
+    mut field: Field<'_>,
+    limit: u64,
+    replaced: bool,
+    too_large: impl Fn() -> (StatusCode, String),
+) -> Result<u64, (StatusCode, String)> {
+    // The upload is fed from this channel while the rest of the request is
+    // still arriving, so the file is never held in memory whole.
+    let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(8);
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+    .boxed();
+    let upload = {
+        let storage = state.storage.clone();
+        let key = key.to_string();
+        let mime_type = mime_type.to_string();
+        tokio::spawn(async move {
+            if replaced {
+                storage.overwrite(&key, &mime_type, body).await
+            } else {
+                storage.upload(&key, &mime_type, body).await
+            }
+        })
+    };
+
+    let mut size = head.len() as u64;
+    let mut failure = None;
+    if tx.send(Ok(Bytes::from(head))).await.is_ok() {
+        loop {
+            let chunk = match field.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(err) => {
+                    failure = Some((err.status(), err.body_text()));
+                    break;
+                }
+            };
+            size += chunk.len() as u64;
+            if size > limit {
+                failure = Some(too_large());
+                break;
+            }
+            // Storage gave up; its error is picked up below.
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    if let Some(failure) = failure {
+        let _ = tx
+            .send(Err(std::io::Error::other("upload cancelled")))
+            .await;
+        upload.abort();
+        return Err(failure);
+    }
+    drop(tx);
+
+    match upload.await {
+        Ok(Ok(())) => Ok(size),
+        Ok(Err(err)) => Err((StatusCode::BAD_GATEWAY, err.to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Writes `head`, the part already read, and the rest of `field` to `path`
+/// and returns the file's size.
+async fn spool_field(
+    path: &std::path::Path,
+    head: Vec<u8>,
+    mut field: Field<'_>,
+    limit: u64,
+    too_large: impl Fn() -> (StatusCode, String),
+) -> Result<u64, (StatusCode, String)> {
+    let io_error = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+
+    let mut file = tokio::fs::File::create(path).await.map_err(io_error)?;
+    let mut size = head.len() as u64;
+    file.write_all(&head).await.map_err(io_error)?;
+    loop {
+        match field.chunk().await {
+            Ok(Some(chunk)) => {
+                size += chunk.len() as u64;
+                if size > limit {
+                    return Err(too_large());
+                }
+                file.write_all(&chunk).await.map_err(io_error)?;
+            }
+            Ok(None) => break,
+            Err(err) => return Err((err.status(), err.body_text())),
+        }
+    }
+    file.flush().await.map_err(io_error)?;
+
+    Ok(size)
+}
+
+/// Probes the video at `path` against the configured limits, stores it at
+/// `key` and takes a poster frame, stored as image variants next to it.
+/// A video whose frame can't be taken is still kept, without a poster.
+async fn store_video(
+    state: &AppState,
+    key: &str,
+    mime_type: &str,
+    path: &std::path::Path,
+    replaced: bool,
+) -> Result<VideoInfo, (StatusCode, String)> {
+    let mut info = match video::probe(&state.video, path).await {
+        Ok(info) => info,
+        Err(err @ (VideoError::Unreadable(_) | VideoError::TooLarge(_))) => {
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+        }
+        // Without ffprobe no video can be checked, so say so plainly rather
+        // than failing as if something went wrong with this one.
+        Err(err @ VideoError::Tool(_)) => {
+            eprintln!("Couldn't probe {key}: {err}");
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Video uploads are unavailable right now".to_string(),
+            ));
+        }
+    };
+
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let body = ReaderStream::new(file).boxed();
+    let stored = if replaced {
+        state.storage.overwrite(key, mime_type, body).await
+    } else {
+        state.storage.upload(key, mime_type, body).await
+    };
+    if let Err(err) = stored {
+        return Err((StatusCode::BAD_GATEWAY, err.to_string()));
+    }
+
+    let frame = match video::poster_frame(&state.video, path, info.duration_secs).await {
+        Ok(frame) => frame,
+        Err(err) => {
+            eprintln!("Couldn't take a poster frame for {key}: {err}");
+            return Ok(info);
+        }
+    };
+    let dir = format!(
+        "{}-poster",
+        key.rsplit_once('.').map_or(key, |(stem, _)| stem)
+    );
+    match store_image(state, &dir, frame, replaced).await {
+        Ok(variants) => {
+            info.poster = Some(state.storage.public_url(&format!("{dir}/full.jpeg")));
+            info.poster_variants = variants;
+        }
+        Err((_, err)) => eprintln!("Couldn't store the poster for {key}: {err}"),
+    }
+
+    Ok(info)
+}
+
+/// Strips, orients and resizes an image, stores every variant under `dir`
+/// and returns where they ended up.
+async fn store_image(
+    state: &AppState,
+    dir: &str,
+    bytes: Vec<u8>,
+    replaced: bool,
+) -> Result<Vec<ImageVariant>, (StatusCode, String)> {
+    let encoded = match tokio::task::spawn_blocking(move || images::process(&bytes)).await {
+        Ok(Ok(encoded)) => encoded,
+        Ok(Err(err)) => {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The image couldn't be read: {err}"),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let mut variants: Vec<ImageVariant> = Vec::with_capacity(encoded.len());
+    for image in encoded {
+        let key = format!("{dir}/{}.{}", image.name, image.format);
+        let body = futures_util::stream::once(async move { Ok(Bytes::from(image.bytes)) }).boxed();
+        let stored = if replaced {
+            state.storage.overwrite(&key, image.mime_type, body).await
+        } else {
+            state.storage.upload(&key, image.mime_type, body).await
+        };
+        if let Err(err) = stored {
+            if !replaced {
+                for variant in &variants {
+                    if let Some(key) = state.storage.key_for_url(&variant.url) {
+                        let _ = state.storage.delete(&key).await;
+                    }
+                }
+            }
+            return Err((StatusCode::BAD_GATEWAY, err.to_string()));
+        }
+
+        variants.push(ImageVariant {
+            name: image.name.to_string(),
+            format: image.format.to_string(),
+            url: state.storage.public_url(&key),
+            width: image.width,
+            height: image.height,
+        });
+    }
+
+    Ok(variants)
+}
+
+/// The storage key and record of an earlier upload `username` made for
+/// `purpose`, if `url` is one.
+async fn replaceable(
+    state: &AppState,
+    username: &str,
+    purpose: MediaPurpose,
+    url: &str,
+) -> Result<Option<(String, UploadRecord)>, sqlx::Error> {
+    let Some(key) = state.storage.key_for_url(url) else {
+        return Ok(None);
+    };
+
+    let record = sqlx::query_as::<_, UploadRecord>(
+        "SELECT mime_type, variants, video FROM media_uploads WHERE url = $1 AND username = $2 AND purpose = $3",
+    )
+    .bind(url)
+    .bind(username)
+    .bind(purpose.as_str())
+    .fetch_optional(&state.postgres)
+    .await?;
+
+    Ok(record.map(|record| (key, record)))
+}
+
+/// Every object stored for an upload: the file itself, or each variant of a
+/// processed image, plus a video's poster.
+pub fn stored_urls(
+    url: &str,
+    variants: Option<&[ImageVariant]>,
+    video: Option<&VideoInfo>,
+) -> Vec<String> {
+    let mut urls = match variants {
+        Some(variants) => variants.iter().map(|variant| variant.url.clone()).collect(),
+        None => vec![url.to_string()],
+    };
+    if let Some(video) = video {
+        urls.extend(
+            video
+                .poster_variants
+                .iter()
+                .map(|variant| variant.url.clone()),
+        );
+    }
+    urls
+}
+
+/// What was recorded about a file when it was uploaded.
+pub async fn uploaded_media(
+    state: &AppState,
+    url: &str,
+) -> Result<Option<UploadRecord>, sqlx::Error> {
+    sqlx::query_as("SELECT mime_type, variants, video FROM media_uploads WHERE url = $1")
+        .bind(url)
+        .fetch_optional(&state.postgres)
+        .await
+}
+
+/// `image`, `video` or `pdf` for the types profiles accept, `None`
+/// otherwise.
+pub fn media_type(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" => Some("image"),
+        "video/mp4" | "video/quicktime" | "video/webm" => Some("video"),
+        "application/pdf" => Some("pdf"),
+        _ => None,
+    }
+}
+
+// Function to detect MIME type (image/video) based on the first few bytes of the file
+pub fn detect_mime_type(bytes: &[u8]) -> Result<String, Box<dyn Error>> {
+    // Use infer to detect the MIME type based on file content
+    if let Some(kind) = infer::get(bytes) {
+        Ok(kind.mime_type().to_string())
+    } else {
+        Ok("application/octet-stream".to_string()) // Default to octet-stream if not recognized
+    }
+}
+
+/// A best guess at the type of a file uploaded before types were recorded,
+/// from its extension.
+pub fn mime_type_for_url(url: &str) -> &'static str {
+    let extension = url.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "jpeg" | "jpg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn get_file_extension(mime_type: &str) -> &str {
+    match mime_type {
+        "image/jpeg" => "jpeg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        "application/pdf" => "pdf",
+        _ => "unknown", // Handle unknown types gracefully
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_purpose_takes_images() {
+        for purpose in [
+            MediaPurpose::Profile,
+            MediaPurpose::Cover,
+            MediaPurpose::Gallery,
+        ] {
+            assert_eq!(purpose.limit("image/jpeg"), Some(IMAGE_LIMIT));
+            assert_eq!(purpose.limit("image/gif"), Some(IMAGE_LIMIT));
+        }
+    }
+
+    #[test]
+    fn profile_photos_cant_be_videos_or_documents() {
+        assert_eq!(MediaPurpose::Profile.limit("video/mp4"), None);
+        assert_eq!(MediaPurpose::Profile.limit("application/pdf"), None);
+        assert_eq!(MediaPurpose::Cover.limit("video/webm"), Some(VIDEO_LIMIT));
+        assert_eq!(MediaPurpose::Cover.limit("application/pdf"), None);
+    }
+
+    #[test]
+    fn galleries_take_documents() {
+        assert_eq!(
+            MediaPurpose::Gallery.limit("application/pdf"),
+            Some(DOCUMENT_LIMIT)
+        );
+        assert_eq!(
+            MediaPurpose::Gallery.limit("video/quicktime"),
+            Some(VIDEO_LIMIT)
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_refused() {
+        for purpose in [
+            MediaPurpose::Profile,
+            MediaPurpose::Cover,
+            MediaPurpose::Gallery,
+        ] {
+            assert_eq!(purpose.limit("application/octet-stream"), None);
+            assert_eq!(purpose.limit("text/html"), None);
+        }
+    }
+
+    #[test]
+    fn legacy_uploads_are_typed_by_extension() {
+        assert_eq!(
+            mime_type_for_url("https://cdn.example.com/a/b.JPG"),
+            "image/jpeg"
+        );
+        assert_eq!(
+            mime_type_for_url("https://cdn.example.com/a/b.mov"),
+            "video/quicktime"
+        );
+        assert_eq!(
+            mime_type_for_url("https://cdn.example.com/a/b"),
+            "application/octet-stream"
+        );
+    }
+}
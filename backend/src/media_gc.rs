@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::auth::{require_admin, SessionUser};
+use crate::images::ImageVariant;
+use crate::media::{mime_type_for_url, stored_urls};
+use crate::storage::Storage;
+use crate::video::VideoInfo;
+use crate::AppState;
+
+/// Days an upload stays in storage after the last profile stops linking to
+/// it, so media that is only briefly unlinked, or uploaded but not saved
+/// yet, isn't lost.
+pub const GRACE_PERIOD_DAYS: i32 = 7;
+
+const COLLECT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(sqlx::FromRow)]
+struct TrackedMedia {
+    id: i32,
+    url: String,
+    username: String,
+    purpose: String,
+    size: i64,
+    variants: Option<sqlx::types::Json<Vec<ImageVariant>>>,
+    video: Option<sqlx::types::Json<VideoInfo>>,
+    unreferenced_since: Option<DateTime<Utc>>,
+}
+
+impl TrackedMedia {
+    fn objects(&self) -> Vec<String> {
+        stored_urls(
+            &self.url,
+            self.variants.as_ref().map(|variants| variants.0.as_slice()),
+            self.video.as_ref().map(|video| &video.0),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OrphanedMedia {
+    pub url: String,
+    pub username: String,
+    pub purpose: String,
+    pub size: i64,
+    pub unreferenced_since: DateTime<Utc>,
+    pub removable_at: DateTime<Utc>,
+    /// Every stored object that goes with it.
+    pub objects: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OrphanReport {
+    pub grace_period_days: i32,
+    /// Removed the next time the collector runs.
+    pub due: Vec<OrphanedMedia>,
+    pub due_bytes: i64,
+    /// Not linked from any profile, but still within the grace period.
+    pub waiting: Vec<OrphanedMedia>,
+}
+
+/// A profile that links to one of our uploads.
+struct Reference {
+    username: String,
+    purpose: &'static str,
+}
+
+/// Starts the background task that removes uploads no profile links to.
+pub fn spawn_collector(postgres: PgPool, supabase_postgres: PgPool, storage: Storage) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = collect(&postgres, &supabase_postgres, &storage).await {
+                eprintln!("Error collecting orphaned media: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Records which uploads profiles link to, then removes those that nothing
+/// has linked to for the whole grace period. Profiles can change between
+/// the two steps, so links are looked up again right before removing.
+async fn collect(
+    postgres: &PgPool,
+    supabase_postgres: &PgPool,
+    storage: &Storage,
+) -> Result<(), sqlx::Error> {
+    let parents = upload_objects(postgres).await?;
+    // Earlier runs recorded links to a variant or poster as uploads of their
+    // own. Dropping those rows leaves the objects to the upload they belong
+    // to.
+    let strays: Vec<&str> = parents
+        .iter()
+        .filter(|(object, parent)| object != parent)
+        .map(|(object, _)| object.as_str())
+        .collect();
+    sqlx::query(
+        "DELETE FROM media_uploads WHERE url = ANY($1) AND variants IS NULL AND video IS NULL",
+    )
+    .bind(&strays)
+    .execute(postgres)
+    .await?;
+
+    let references = find_references(supabase_postgres, storage, &parents).await?;
+    let urls: Vec<&str> = references.keys().map(String::as_str).collect();
+
+    // Media linked before uploads were tracked is picked up here. Its size
+    // isn't known without fetching it.
+    for (url, reference) in &references {
+        sqlx::query("INSERT INTO media_uploads (url, username, purpose, mime_type, size, unreferenced_since) VALUES ($1, $2, $3, $4, 0, NULL) ON CONFLICT (url) DO NOTHING")
+            .bind(url)
+            .bind(&reference.username)
+            .bind(reference.purpose)
+            .bind(mime_type_for_url(url))
+            .execute(postgres)
+            .await?;
+    }
+
+    sqlx::query("UPDATE media_uploads SET unreferenced_since = NULL WHERE url = ANY($1) AND unreferenced_since IS NOT NULL")
+        .bind(&urls)
+        .execute(postgres)
+        .await?;
+    sqlx::query("UPDATE media_uploads SET unreferenced_since = NOW() WHERE unreferenced_since IS NULL AND NOT url = ANY($1)")
+        .bind(&urls)
+        .execute(postgres)
+        .await?;
+
+    let due = sqlx::query_as::<_, TrackedMedia>("SELECT id, url, username, purpose, size, variants, video, unreferenced_since FROM media_uploads WHERE unreferenced_since < NOW() - make_interval(days => $1)")
+        .bind(GRACE_PERIOD_DAYS)
+        .fetch_all(postgres)
+        .await?;
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    let references = find_references(supabase_postgres, storage, &parents).await?;
+    let mut removed = 0;
+    'media: for media in due {
+        // Uploads kept by another storage backend, e.g. production media in
+        // a development database, are left alone.
+        if references.contains_key(&media.url) || storage.key_for_url(&media.url).is_none() {
+            continue;
+        }
+
+        for url in media.objects() {
+            let Some(key) = storage.key_for_url(&url) else {
+                continue;
+            };
+            if let Err(err) = storage.delete(&key).await {
+                // Kept, so the next run tries again.
+                eprintln!("Couldn't remove orphaned media {key}: {err}");
+                continue 'media;
+            }
+        }
+
+        sqlx::query("DELETE FROM media_uploads WHERE id = $1")
+            .bind(media.id)
+            .execute(postgres)
+            .await?;
+        removed += 1;
+    }
+
+    if removed > 0 {
+        println!("Removed {removed} uploads no profile links to");
+    }
+
+    Ok(())
+}
+
+/// Every stored object of a processed image or video, mapped to the URL of
+/// the upload it belongs to. Plain uploads are a single object and aren't
+/// listed.
+async fn upload_objects(postgres: &PgPool) -> Result<HashMap<String, String>, sqlx::Error> {
+    let uploads = sqlx::query_as::<_, TrackedMedia>("SELECT id, url, username, purpose, size, variants, video, unreferenced_since FROM media_uploads WHERE variants IS NOT NULL OR video IS NOT NULL")
+        .fetch_all(postgres)
+        .await?;
+
+    Ok(parent_urls(&uploads))
+}
+
+fn parent_urls(uploads: &[TrackedMedia]) -> HashMap<String, String> {
+    uploads
+        .iter()
+        .flat_map(|media| {
+            media
+                .objects()
+                .into_iter()
+                .map(|object| (object, media.url.clone()))
+        })
+        .collect()
+}
+
+/// Every upload of ours that a profile links to, as a photo, a cover or a
+/// gallery item. A link to one of an upload's variants or its poster counts
+/// as a link to the upload; see `upload_objects`.
+async fn find_references(
+    supabase_postgres: &PgPool,
+    storage: &Storage,
+    parents: &HashMap<String, String>,
+) -> Result<HashMap<String, Reference>, sqlx::Error> {
+    let profiles = sqlx::query_as::<_, (String, Option<String>, Option<serde_json::Value>)>(
+        "SELECT username, photo, media::jsonb FROM users WHERE username IS NOT NULL",
+    )
+    .persistent(false)
+    .fetch_all(supabase_postgres)
+    .await?;
+
+    let mut references = HashMap::new();
+    for (username, photo, media) in profiles {
+        for (url, purpose) in profile_links(photo.as_deref(), media.as_ref()) {
+            if storage.key_for_url(url).is_some() {
+                references.insert(
+                    upload_url(url, parents).to_string(),
+                    Reference {
+                        username: username.clone(),
+                        purpose,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(references)
+}
+
+/// Every URL a profile links to, with what it is used as.
+fn profile_links<'a>(
+    photo: Option<&'a str>,
+    media: Option<&'a serde_json::Value>,
+) -> Vec<(&'a str, &'static str)> {
+    let items = media
+        .and_then(|media| media.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let url = item.get("media").and_then(|url| url.as_str())?;
+            // Only gallery items have an id.
+            let purpose = if item.get("id").is_some() {
+                "gallery"
+            } else {
+                "cover"
+            };
+            Some((url, purpose))
+        });
+
+    photo
+        .map(|url| (url, "profile"))
+        .into_iter()
+        .chain(items)
+        .collect()
+}
+
+/// The upload that `url` is stored for: its parent if it is a variant or a
+/// poster, or `url` itself.
+fn upload_url<'a>(url: &'a str, parents: &'a HashMap<String, String>) -> &'a str {
+    parents.get(url).map_or(url, String::as_str)
+}
+
+/// What the collector would remove, without removing anything. Links are
+/// checked as they are now, so media that a profile has started linking to
+/// again since the last run isn't listed. Admins only.
+pub async fn get_orphan_report(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<OrphanReport>, (StatusCode, String)> {
+    require_admin(&state.postgres, user.id).await?;
+
+    let parents = match upload_objects(&state.postgres).await {
+        Ok(parents) => parents,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let references = match find_references(&state.supabase_postgres, &state.storage, &parents).await
+    {
+        Ok(references) => references,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    // Uploads the collector hasn't seen unlinked yet count from now.
+    let unlinked = match sqlx::query_as::<_, TrackedMedia>("SELECT id, url, username, purpose, size, variants, video, COALESCE(unreferenced_since, NOW()) AS unreferenced_since FROM media_uploads ORDER BY unreferenced_since")
+        .fetch_all(&state.postgres)
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let grace_period = chrono::Duration::days(GRACE_PERIOD_DAYS as i64);
+    let now = Utc::now();
+    let mut report = OrphanReport {
+        grace_period_days: GRACE_PERIOD_DAYS,
+        due: Vec::new(),
+        due_bytes: 0,
+        waiting: Vec::new(),
+    };
+    for media in unlinked {
+        if references.contains_key(&media.url)
+            || state.storage.key_for_url(&media.url).is_none()
+            || upload_url(&media.url, &parents) != media.url
+        {
+            continue;
+        }
+
+        let unreferenced_since = media.unreferenced_since.unwrap_or(now);
+        let orphan = OrphanedMedia {
+            objects: media.objects(),
+            url: media.url,
+            username: media.username,
+            purpose: media.purpose,
+            size: media.size,
+            unreferenced_since,
+            removable_at: unreferenced_since + grace_period,
+        };
+        if orphan.removable_at < now {
+            report.due_bytes += orphan.size;
+            report.due.push(orphan);
+        } else {
+            report.waiting.push(orphan);
+        }
+    }
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CDN: &str = "https://cdn.example.com";
+
+    fn variants(dir: &str) -> Vec<ImageVariant> {
+        ["thumbnail", "card", "full"]
+            .into_iter()
+            .flat_map(|name| {
+                ["webp", "jpeg"]
+                    .into_iter()
+                    .map(move |format| ImageVariant {
+                        name: name.to_string(),
+                        format: format.to_string(),
+                        url: format!("{CDN}/{dir}/{name}.{format}"),
+                        width: 1,
+                        height: 1,
+                    })
+            })
+            .collect()
+    }
+
+    fn tracked(
+        url: &str,
+        variants: Option<Vec<ImageVariant>>,
+        video: Option<VideoInfo>,
+    ) -> TrackedMedia {
+        TrackedMedia {
+            id: 1,
+            url: url.to_string(),
+            username: "jane".to_string(),
+            purpose: "cover".to_string(),
+            size: 0,
+            variants: variants.map(sqlx::types::Json),
+            video: video.map(sqlx::types::Json),
+            unreferenced_since: None,
+        }
+    }
+
+    #[test]
+    fn image_variants_map_to_their_upload() {
+        let photo = format!("{CDN}/profile_media/a/full.jpeg");
+        let parents = parent_urls(&[tracked(&photo, Some(variants("profile_media/a")), None)]);
+
+        assert_eq!(parents.len(), 6);
+        let card = format!("{CDN}/profile_media/a/card.webp");
+        assert_eq!(upload_url(&card, &parents), photo);
+        assert_eq!(upload_url(&photo, &parents), photo);
+    }
+
+    #[test]
+    fn posters_map_to_their_video() {
+        let video = format!("{CDN}/cover_media/b.mp4");
+        let info = VideoInfo {
+            duration_secs: 5.0,
+            width: 1280,
+            height: 720,
+            codec: "h264".to_string(),
+            poster: Some(format!("{CDN}/cover_media/b-poster/full.jpeg")),
+            poster_variants: variants("cover_media/b-poster"),
+        };
+        let parents = parent_urls(&[tracked(&video, None, Some(info))]);
+
+        let poster = format!("{CDN}/cover_media/b-poster/full.jpeg");
+        assert_eq!(upload_url(&poster, &parents), video);
+        assert_eq!(upload_url(&video, &parents), video);
+    }
+
+    #[test]
+    fn other_urls_are_their_own_upload() {
+        let parents = parent_urls(&[]);
+        let url = format!("{CDN}/gallery_media/c.pdf");
+        assert_eq!(upload_url(&url, &parents), url);
+    }
+
+    #[test]
+    fn profiles_link_their_photo_cover_and_gallery() {
+        let media = serde_json::json!([
+            { "media": "https://cdn.example.com/cover.jpeg", "type": "image" },
+            { "id": "x", "media": "https://cdn.example.com/brochure.pdf" },
+            { "id": "y", "link": "no media here" },
+        ]);
+        assert_eq!(
+            profile_links(Some("https://cdn.example.com/me.jpeg"), Some(&media)),
+            [
+                ("https://cdn.example.com/me.jpeg", "profile"),
+                ("https://cdn.example.com/cover.jpeg", "cover"),
+                ("https://cdn.example.com/brochure.pdf", "gallery"),
+            ]
+        );
+        assert!(profile_links(None, Some(&serde_json::json!({}))).is_empty());
+        assert!(profile_links(None, None).is_empty());
+    }
+}
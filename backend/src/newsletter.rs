@@ -0,0 +1,395 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Html,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::email::escape_html;
+use crate::email_queue::{enqueue, Job};
+use crate::AppState;
+
+/// How long a confirmation link stays valid.
+const CONFIRMATION_HOURS: i32 = 48;
+
+/// A pending address isn't sent another confirmation within this window, so
+/// the form can't be used to flood someone's inbox.
+const RESEND_AFTER_MINUTES: i32 = 10;
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeQuery {
+    email: String,
+    sig: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct Subscription {
+    id: i32,
+    email: String,
+    status: String,
+}
+
+/// Starts a double opt-in subscription: the address is stored as pending and
+/// sent a confirmation link. It only joins the mailing list once confirmed.
+/// Answers the same way whether or not the address was already subscribed.
+pub async fn subscribe(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<EmailRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let email = req.email.trim();
+    if email.parse::<lettre::Address>().is_err() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Please enter a valid email address".to_string(),
+        ));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let recently_requested = match sqlx::query_scalar::<_, bool>(
+        "SELECT status = 'subscribed' OR (status = 'pending' AND requested_at > NOW() - make_interval(mins => $2))
+        FROM newsletter_subscriptions WHERE lower(email) = lower($1) FOR UPDATE",
+    )
+    .bind(email)
+    .bind(RESEND_AFTER_MINUTES)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(res) => res.unwrap_or(false),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    if recently_requested {
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+
+    let id = match sqlx::query_scalar::<_, i32>(
+        "INSERT INTO newsletter_subscriptions (email, status, token_hash, token_expires_at)
+        VALUES ($1, 'pending', $2, NOW() + make_interval(hours => $3))
+        ON CONFLICT ((lower(email))) DO UPDATE SET
+        status = 'pending', token_hash = EXCLUDED.token_hash, token_expires_at = EXCLUDED.token_expires_at, requested_at = NOW()
+        RETURNING id",
+    )
+    .bind(email)
+    .bind(hash_token(&token))
+    .bind(CONFIRMATION_HOURS)
+    .fetch_one(&mut *tx)
+    .await
+    {
+        Ok(id) => id,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Err(err) = record_event(&mut tx, id, "requested", &headers).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    let link = match Url::parse_with_params(
+        &format!(
+            "{}/api/subscribe/confirm",
+            state.domain.trim_end_matches('/')
+        ),
+        &[("token", &token)],
+    ) {
+        Ok(link) => link,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let confirmation = state.mailer.newsletter_confirmation(
+        email,
+        link.as_str(),
+        &format!("{CONFIRMATION_HOURS} hours"),
+    );
+    if let Err(err) = enqueue(&mut *tx, &Job::Email(confirmation)).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::ACCEPTED),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Where the confirmation email links to. Confirms the subscription and
+/// queues adding the address to the mailing list.
+pub async fn confirm_subscription(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ConfirmQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let subscription = match sqlx::query_as::<_, Subscription>(
+        "UPDATE newsletter_subscriptions SET status = 'subscribed', confirmed_at = NOW(), unsubscribed_at = NULL, token_hash = NULL, token_expires_at = NULL
+        WHERE token_hash = $1 AND status = 'pending' AND token_expires_at > NOW()
+        RETURNING id, email, status",
+    )
+    .bind(hash_token(&query.token))
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(subscription)) => subscription,
+        Ok(None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "This link is invalid or has expired. Please subscribe again.".to_string(),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Err(err) = record_event(&mut tx, subscription.id, "confirmed", &headers).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+    if let Err(err) = enqueue(&mut *tx, &list_member(&state, &subscription.email, true)).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    let unsubscribe = unsubscribe_url(&state, &subscription.email)?;
+    Ok(page(
+        "You're subscribed",
+        &format!(
+            "<p>Thanks for confirming. {} will get our newsletter from now on.</p><p>Changed your mind? <a href=\"{}\">Unsubscribe</a>.</p>",
+            escape_html(&subscription.email),
+            escape_html(&unsubscribe)
+        ),
+    ))
+}
+
+/// Shows a button that unsubscribes, rather than unsubscribing straight
+/// away, so link scanners that open every URL in an email don't unsubscribe
+/// people.
+pub async fn unsubscribe_page(
+    State(state): State<AppState>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    verify_signature(&state.signing_secret, &query.email, &query.sig)?;
+
+    Ok(page(
+        "Unsubscribe",
+        &format!(
+            "<p>Stop sending our newsletter to {}?</p><form method=\"post\"><button type=\"submit\">Unsubscribe</button></form>",
+            escape_html(&query.email)
+        ),
+    ))
+}
+
+/// Unsubscribes the signed address. Also serves as the one-click
+/// `List-Unsubscribe-Post` target from RFC 8058, so it ignores the body.
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    verify_signature(&state.signing_secret, &query.email, &query.sig)?;
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let existing = match sqlx::query_as::<_, Subscription>(
+        "SELECT id, email, status FROM newsletter_subscriptions WHERE lower(email) = lower($1) FOR UPDATE",
+    )
+    .bind(&query.email)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if existing.as_ref().map(|s| s.status.as_str()) != Some("unsubscribed") {
+        // Addresses that joined the list before double opt-in have no row yet.
+        let id = match sqlx::query_scalar::<_, i32>(
+            "INSERT INTO newsletter_subscriptions (email, status, unsubscribed_at)
+            VALUES ($1, 'unsubscribed', NOW())
+            ON CONFLICT ((lower(email))) DO UPDATE SET
+            status = 'unsubscribed', unsubscribed_at = NOW(), token_hash = NULL, token_expires_at = NULL
+            RETURNING id",
+        )
+        .bind(&query.email)
+        .fetch_one(&mut *tx)
+        .await
+        {
+            Ok(id) => id,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        };
+
+        if let Err(err) = record_event(&mut tx, id, "unsubscribed", &headers).await {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+        if let Err(err) = enqueue(&mut *tx, &list_member(&state, &query.email, false)).await {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    Ok(page(
+        "You're unsubscribed",
+        &format!(
+            "<p>{} won't get our newsletter any more.</p>",
+            escape_html(&query.email)
+        ),
+    ))
+}
+
+/// A signed link that unsubscribes `email` without logging in.
+pub fn unsubscribe_url(state: &AppState, email: &str) -> Result<String, (StatusCode, String)> {
+    match Url::parse_with_params(
+        &format!("{}/api/unsubscribe", state.domain.trim_end_matches('/')),
+        &[
+            ("email", email),
+            ("sig", &sign(&state.signing_secret, email)),
+        ],
+    ) {
+        Ok(url) => Ok(url.to_string()),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+fn list_member(state: &AppState, email: &str, subscribed: bool) -> Job {
+    Job::ListMember {
+        list: format!("mail@{}", state.mailgun_url),
+        address: email.to_string(),
+        subscribed,
+    }
+}
+
+async fn record_event(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    subscription_id: i32,
+    event: &str,
+    headers: &HeaderMap,
+) -> Result<(), sqlx::Error> {
+    // Behind the proxy the client is the first X-Forwarded-For entry.
+    let ip_address = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string());
+    let user_agent = headers
+        .get("user-agent")
+        .and_then(|value| value.to_str().ok());
+
+    sqlx::query("INSERT INTO newsletter_consent_events (subscription_id, event, ip_address, user_agent) VALUES ($1, $2, $3, $4)")
+        .bind(subscription_id)
+        .bind(event)
+        .bind(ip_address)
+        .bind(user_agent)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+fn mac(secret: &str, email: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(email.trim().to_lowercase().as_bytes());
+    mac
+}
+
+fn sign(secret: &str, email: &str) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, email).finalize().into_bytes())
+}
+
+fn verify_signature(secret: &str, email: &str, sig: &str) -> Result<(), (StatusCode, String)> {
+    let valid = URL_SAFE_NO_PAD
+        .decode(sig)
+        .is_ok_and(|sig| mac(secret, email).verify_slice(&sig).is_ok());
+
+    if valid {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            "This unsubscribe link is invalid".to_string(),
+        ))
+    }
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\" /><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" /><title>{title}</title></head><body style=\"font-family: Helvetica, Arial, sans-serif; max-width: 560px; margin: 48px auto; padding: 0 24px; color: #0f172a\"><h1>{title}</h1>{body}</body></html>"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    #[test]
+    fn signatures_verify_for_the_signed_address() {
+        let sig = sign(SECRET, "jane@example.com");
+        assert!(verify_signature(SECRET, "jane@example.com", &sig).is_ok());
+    }
+
+    #[test]
+    fn signatures_ignore_case_and_surrounding_space() {
+        let sig = sign(SECRET, "Jane@Example.com");
+        assert_eq!(sig, sign(SECRET, " jane@example.com "));
+        assert!(verify_signature(SECRET, "JANE@example.com", &sig).is_ok());
+    }
+
+    #[test]
+    fn signatures_are_rejected_for_other_addresses_or_secrets() {
+        let sig = sign(SECRET, "jane@example.com");
+        assert!(verify_signature(SECRET, "john@example.com", &sig).is_err());
+        assert!(verify_signature("another secret", "jane@example.com", &sig).is_err());
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        let err = verify_signature(SECRET, "jane@example.com", "not base64!").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert!(verify_signature(SECRET, "jane@example.com", "").is_err());
+
+        let mut sig = sign(SECRET, "jane@example.com");
+        sig.pop();
+        assert!(verify_signature(SECRET, "jane@example.com", &sig).is_err());
+    }
+
+    #[test]
+    fn tokens_are_stored_hashed() {
+        let hash = hash_token("token");
+        assert_ne!(hash, "token");
+        assert_eq!(hash, hash_token("token"));
+        assert_ne!(hash, hash_token("other token"));
+    }
+}
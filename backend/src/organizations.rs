@@ -0,0 +1,857 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::auth::SessionUser;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl MemberRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberRole::Owner => "owner",
+            MemberRole::Admin => "admin",
+            MemberRole::Member => "member",
+        }
+    }
+
+    /// Owners and admins see the whole organization unless told otherwise;
+    /// members see their team.
+    fn default_visibility(&self) -> Visibility {
+        match self {
+            MemberRole::Owner | MemberRole::Admin => Visibility::Org,
+            MemberRole::Member => Visibility::Team,
+        }
+    }
+}
+
+/// Whose customers and deals a member can see besides the ones assigned to
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Own,
+    Team,
+    Org,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Own => "own",
+            Visibility::Team => "team",
+            Visibility::Org => "org",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OrganizationDetail {
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// The session user's role.
+    pub role: String,
+    pub members: Vec<Member>,
+    pub teams: Vec<Team>,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct Member {
+    pub user_id: i32,
+    pub email: String,
+    pub role: String,
+    pub visibility: String,
+    pub team_id: Option<i32>,
+    pub team_name: Option<String>,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Membership {
+    organization_id: i32,
+    role: String,
+}
+
+impl Membership {
+    fn is_admin(&self) -> bool {
+        self.role == MemberRole::Owner.as_str() || self.role == MemberRole::Admin.as_str()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewOrganization {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewMember {
+    pub email: String,
+    pub role: Option<MemberRole>,
+    pub visibility: Option<Visibility>,
+    pub team_id: Option<i32>,
+}
+
+/// Partial update of a member. `team_id: null` takes them out of their team.
+#[derive(Deserialize)]
+pub struct MemberUpdate {
+    pub role: Option<MemberRole>,
+    pub visibility: Option<Visibility>,
+    #[serde(default, deserialize_with = "present")]
+    pub team_id: Option<Option<i32>>,
+}
+
+#[derive(Deserialize)]
+pub struct NewTeam {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct AssignRequest {
+    pub user_id: i32,
+}
+
+/// Tells a field sent as `null` apart from one that was left out.
+pub(crate) fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Creates an organization with the session user as its owner. Their
+/// existing customers and deals move into it.
+pub async fn create_organization(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<NewOrganization>,
+) -> Result<(StatusCode, Json<OrganizationDetail>), (StatusCode, String)> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "name must not be empty".to_string(),
+        ));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match fetch_membership(&mut *tx, user.id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "You already belong to an organization".to_string(),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    let organization_id = match sqlx::query_scalar::<_, i32>(
+        "INSERT INTO organizations (name) VALUES ($1) RETURNING id",
+    )
+    .bind(name)
+    .fetch_one(&mut *tx)
+    .await
+    {
+        Ok(id) => id,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if let Err(err) = join(
+        &mut tx,
+        organization_id,
+        user.id,
+        MemberRole::Owner,
+        MemberRole::Owner.default_visibility(),
+        None,
+    )
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    let detail = fetch_detail(&state.postgres, user.id).await?;
+    Ok((StatusCode::CREATED, Json(detail)))
+}
+
+pub async fn get_organization(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+) -> Result<Json<OrganizationDetail>, (StatusCode, String)> {
+    fetch_detail(&state.postgres, user.id).await.map(Json)
+}
+
+/// Adds an existing user to the session user's organization. Admins only.
+pub async fn add_member(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<NewMember>,
+) -> Result<(StatusCode, Json<OrganizationDetail>), (StatusCode, String)> {
+    let admin = require_admin(&state.postgres, user.id).await?;
+    let role = req.role.unwrap_or(MemberRole::Member);
+    if role == MemberRole::Owner && admin.role != MemberRole::Owner.as_str() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only owners can add owners".to_string(),
+        ));
+    }
+    check_team(&state.postgres, admin.organization_id, req.team_id).await?;
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let member_id = match sqlx::query_scalar::<_, i32>(
+        "SELECT id FROM users WHERE lower(email) = lower($1) ORDER BY id LIMIT 1",
+    )
+    .bind(req.email.trim())
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    match fetch_membership(&mut *tx, member_id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "That user already belongs to an organization".to_string(),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    if let Err(err) = join(
+        &mut tx,
+        admin.organization_id,
+        member_id,
+        role,
+        req.visibility.unwrap_or(role.default_visibility()),
+        req.team_id,
+    )
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    if let Err(err) = tx.commit().await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    let detail = fetch_detail(&state.postgres, user.id).await?;
+    Ok((StatusCode::CREATED, Json(detail)))
+}
+
+/// Changes a member's role, visibility or team. Admins only, and only owners
+/// can make or unmake owners.
+pub async fn update_member(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(member_id): Path<i32>,
+    Json(req): Json<MemberUpdate>,
+) -> Result<Json<OrganizationDetail>, (StatusCode, String)> {
+    let admin = require_admin(&state.postgres, user.id).await?;
+    let member = match fetch_membership(&state.postgres, member_id).await {
+        Ok(Some(member)) if member.organization_id == admin.organization_id => member,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, "Member not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let is_owner = member.role == MemberRole::Owner.as_str();
+    if let Some(role) = req.role {
+        let touches_owner = is_owner || role == MemberRole::Owner;
+        if touches_owner && admin.role != MemberRole::Owner.as_str() {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Only owners can change who is an owner".to_string(),
+            ));
+        }
+        if is_owner && role != MemberRole::Owner {
+            check_other_owner(&state.postgres, admin.organization_id, member_id).await?;
+        }
+    }
+    if let Some(team_id) = req.team_id {
+        check_team(&state.postgres, admin.organization_id, team_id).await?;
+    }
+
+    if let Err(err) = sqlx::query(
+        "UPDATE organization_members SET
+        role = COALESCE($1, role),
+        visibility = COALESCE($2, visibility),
+        team_id = CASE WHEN $3 THEN $4 ELSE team_id END
+        WHERE user_id = $5",
+    )
+    .bind(req.role.map(|role| role.as_str()))
+    .bind(req.visibility.map(|visibility| visibility.as_str()))
+    .bind(req.team_id.is_some())
+    .bind(req.team_id.flatten())
+    .bind(member_id)
+    .execute(&state.postgres)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    fetch_detail(&state.postgres, user.id).await.map(Json)
+}
+
+/// Removes a member, or lets a member leave. Their customers and deals stay
+/// with the organization and are reassigned to whoever removed them, or to
+/// the most senior remaining member when they left. The last member leaving
+/// dissolves the organization and takes their records back.
+pub async fn remove_member(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(member_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let actor = match fetch_membership(&state.postgres, user.id).await {
+        Ok(Some(actor)) => actor,
+        Ok(None) => return Err(not_in_organization()),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    if member_id != user.id && !actor.is_admin() {
+        return Err(admin_only());
+    }
+    let member = match fetch_membership(&state.postgres, member_id).await {
+        Ok(Some(member)) if member.organization_id == actor.organization_id => member,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, "Member not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    if member.role == MemberRole::Owner.as_str()
+        && member_id != user.id
+        && actor.role != MemberRole::Owner.as_str()
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only owners can remove an owner".to_string(),
+        ));
+    }
+
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let successor = if member_id != user.id {
+        Some(user.id)
+    } else {
+        match sqlx::query_scalar::<_, i32>(
+            "SELECT user_id FROM organization_members
+            WHERE organization_id = $1 AND user_id <> $2
+            ORDER BY CASE role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END, joined_at
+            LIMIT 1",
+        )
+        .bind(member.organization_id)
+        .bind(member_id)
+        .fetch_optional(&mut *tx)
+        .await
+        {
+            Ok(successor) => successor,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        }
+    };
+
+    let Some(successor) = successor else {
+        // Nobody is left, so the records go back to being the member's own,
+        // and so do the custom fields, whoever created them.
+        if let Err(err) = sqlx::query(
+            "UPDATE custom_field_definitions SET owner_id = $1 WHERE organization_id = $2",
+        )
+        .bind(member_id)
+        .bind(member.organization_id)
+        .execute(&mut *tx)
+        .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+        if let Err(err) = sqlx::query("DELETE FROM organizations WHERE id = $1")
+            .bind(member.organization_id)
+            .execute(&mut *tx)
+            .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+        return match tx.commit().await {
+            Ok(_) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        };
+    };
+
+    if member.role == MemberRole::Owner.as_str() {
+        if let Err(err) = sqlx::query(
+            "UPDATE organization_members SET role = 'owner', visibility = 'org'
+            WHERE user_id = $1 AND NOT EXISTS (
+                SELECT 1 FROM organization_members WHERE organization_id = $2 AND role = 'owner' AND user_id <> $3
+            )",
+        )
+        .bind(successor)
+        .bind(member.organization_id)
+        .bind(member_id)
+        .execute(&mut *tx)
+        .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    for table in ["customers", "deals"] {
+        if let Err(err) = sqlx::query(&format!(
+            "UPDATE {table} SET owner_id = $1 WHERE owner_id = $2 AND organization_id = $3"
+        ))
+        .bind(successor)
+        .bind(member_id)
+        .bind(member.organization_id)
+        .execute(&mut *tx)
+        .await
+        {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+    }
+
+    if let Err(err) = sqlx::query("DELETE FROM organization_members WHERE user_id = $1")
+        .bind(member_id)
+        .execute(&mut *tx)
+        .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn create_team(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Json(req): Json<NewTeam>,
+) -> Result<(StatusCode, Json<Team>), (StatusCode, String)> {
+    let admin = require_admin(&state.postgres, user.id).await?;
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "name must not be empty".to_string(),
+        ));
+    }
+
+    match sqlx::query_as::<_, Team>("INSERT INTO teams (organization_id, name) VALUES ($1, $2) ON CONFLICT (organization_id, name) DO NOTHING RETURNING id, name, created_at")
+        .bind(admin.organization_id)
+        .bind(name)
+        .fetch_optional(&state.postgres)
+        .await
+    {
+        Ok(Some(team)) => Ok((StatusCode::CREATED, Json(team))),
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            format!("A team called {name} already exists"),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Deletes a team. Its members stay in the organization without a team.
+pub async fn destroy_team(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let admin = require_admin(&state.postgres, user.id).await?;
+
+    match sqlx::query("DELETE FROM teams WHERE organization_id = $1 AND id = $2")
+        .bind(admin.organization_id)
+        .bind(id)
+        .execute(&state.postgres)
+        .await
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            Err((StatusCode::NOT_FOUND, "Team not found".to_string()))
+        }
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+pub async fn assign_customer(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<AssignRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    assign(&state, user, "customers", "Customer", id, req.user_id).await
+}
+
+pub async fn assign_deal(
+    State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<AssignRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    assign(&state, user, "deals", "Deal", id, req.user_id).await
+}
+
+/// Hands a record over to another rep in its organization. Its current rep
+/// and the organization's admins can do this.
+async fn assign(
+    state: &AppState,
+    user: SessionUser,
+    table: &str,
+    name: &str,
+    id: i32,
+    assignee: i32,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = match state.postgres.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let (owner_id, organization_id) = match sqlx::query_as::<_, (i32, Option<i32>)>(&format!(
+        "SELECT owner_id, organization_id FROM {table} WHERE visible_to($1, owner_id, organization_id) AND id = $2 AND deleted_at IS NULL FOR UPDATE"
+    ))
+    .bind(user.id)
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(record)) => record,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, format!("{name} not found"))),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let Some(organization_id) = organization_id else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{name} doesn't belong to an organization"),
+        ));
+    };
+
+    if owner_id != user.id {
+        match fetch_membership(&mut *tx, user.id).await {
+            Ok(Some(actor)) if actor.organization_id == organization_id && actor.is_admin() => {}
+            Ok(_) => return Err(admin_only()),
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        }
+    }
+
+    match fetch_membership(&mut *tx, assignee).await {
+        Ok(Some(member)) if member.organization_id == organization_id => {}
+        Ok(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The assignee isn't a member of the organization".to_string(),
+            ))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+
+    let touch = if table == "deals" {
+        ", last_updated = NOW()"
+    } else {
+        ""
+    };
+    if let Err(err) = sqlx::query(&format!(
+        "UPDATE {table} SET owner_id = $1{touch} WHERE id = $2"
+    ))
+    .bind(assignee)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    }
+
+    match tx.commit().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Adds `user_id` to the organization and moves their private customers,
+/// deals and custom fields into it.
+async fn join(
+    tx: &mut Transaction<'_, Postgres>,
+    organization_id: i32,
+    user_id: i32,
+    role: MemberRole,
+    visibility: Visibility,
+    team_id: Option<i32>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO organization_members (user_id, organization_id, team_id, role, visibility) VALUES ($1, $2, $3, $4, $5)")
+        .bind(user_id)
+        .bind(organization_id)
+        .bind(team_id)
+        .bind(role.as_str())
+        .bind(visibility.as_str())
+        .execute(&mut **tx)
+        .await?;
+
+    for table in ["customers", "deals"] {
+        sqlx::query(&format!(
+            "UPDATE {table} SET organization_id = $1 WHERE owner_id = $2 AND organization_id IS NULL"
+        ))
+        .bind(organization_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    sqlx::query("SELECT move_field_definitions($1, $2)")
+        .bind(user_id)
+        .bind(organization_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn fetch_membership<'e, E>(
+    executor: E,
+    user_id: i32,
+) -> Result<Option<Membership>, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    sqlx::query_as::<_, Membership>(
+        "SELECT organization_id, role FROM organization_members WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await
+}
+
+async fn require_admin(
+    postgres: &PgPool,
+    user_id: i32,
+) -> Result<Membership, (StatusCode, String)> {
+    match fetch_membership(postgres, user_id).await {
+        Ok(Some(membership)) if membership.is_admin() => Ok(membership),
+        Ok(Some(_)) => Err(admin_only()),
+        Ok(None) => Err(not_in_organization()),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+async fn check_team(
+    postgres: &PgPool,
+    organization_id: i32,
+    team_id: Option<i32>,
+) -> Result<(), (StatusCode, String)> {
+    let Some(team_id) = team_id else {
+        return Ok(());
+    };
+    match sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM teams WHERE id = $1 AND organization_id = $2)",
+    )
+    .bind(team_id)
+    .bind(organization_id)
+    .fetch_one(postgres)
+    .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::BAD_REQUEST, "Team not found".to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// An organization must keep at least one owner.
+async fn check_other_owner(
+    postgres: &PgPool,
+    organization_id: i32,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    match sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM organization_members WHERE organization_id = $1 AND role = 'owner' AND user_id <> $2)",
+    )
+    .bind(organization_id)
+    .bind(user_id)
+    .fetch_one(postgres)
+    .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::CONFLICT,
+            "The organization needs at least one other owner first".to_string(),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+async fn fetch_detail(
+    postgres: &PgPool,
+    user_id: i32,
+) -> Result<OrganizationDetail, (StatusCode, String)> {
+    let membership = match fetch_membership(postgres, user_id).await {
+        Ok(Some(membership)) => membership,
+        Ok(None) => return Err(not_in_organization()),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let (id, name, created_at) = match sqlx::query_as::<_, (i32, String, DateTime<Utc>)>(
+        "SELECT id, name, created_at FROM organizations WHERE id = $1",
+    )
+    .bind(membership.organization_id)
+    .fetch_one(postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let members = match sqlx::query_as::<_, Member>(
+        "SELECT m.user_id, u.email, m.role, m.visibility, m.team_id, t.name AS team_name, m.joined_at
+        FROM organization_members m
+        JOIN users u ON u.id = m.user_id
+        LEFT JOIN teams t ON t.id = m.team_id
+        WHERE m.organization_id = $1
+        ORDER BY m.joined_at, m.user_id",
+    )
+    .bind(id)
+    .fetch_all(postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    let teams = match sqlx::query_as::<_, Team>(
+        "SELECT id, name, created_at FROM teams WHERE organization_id = $1 ORDER BY name",
+    )
+    .bind(id)
+    .fetch_all(postgres)
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    Ok(OrganizationDetail {
+        id,
+        name,
+        created_at,
+        role: membership.role,
+        members,
+        teams,
+    })
+}
+
+fn not_in_organization() -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        "You don't belong to an organization".to_string(),
+    )
+}
+
+/// The answer to a change to a record in `table` that matched nothing.
+/// Members who can see the record but may not change it are told so; anyone
+/// else gets `not_found`.
+pub(crate) async fn write_refused(
+    postgres: &PgPool,
+    table: &str,
+    user_id: i32,
+    id: i32,
+    not_found: (StatusCode, String),
+) -> (StatusCode, String) {
+    match sqlx::query_scalar::<_, bool>(&format!(
+        "SELECT EXISTS(SELECT 1 FROM {table} WHERE visible_to($1, owner_id, organization_id) AND NOT editable_by($1, owner_id, organization_id) AND id = $2)"
+    ))
+    .bind(user_id)
+    .bind(id)
+    .fetch_one(postgres)
+    .await
+    {
+        Ok(true) => (
+            StatusCode::FORBIDDEN,
+            "Only the rep it's assigned to and organization owners and admins can change it"
+                .to_string(),
+        ),
+        Ok(false) => not_found,
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+fn admin_only() -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        "Only organization owners and admins can do that".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_see_the_organization_and_members_their_team() {
+        assert_eq!(MemberRole::Owner.default_visibility(), Visibility::Org);
+        assert_eq!(MemberRole::Admin.default_visibility(), Visibility::Org);
+        assert_eq!(MemberRole::Member.default_visibility(), Visibility::Team);
+    }
+
+    #[test]
+    fn roles_and_visibilities_serialize_as_stored() {
+        for role in [MemberRole::Owner, MemberRole::Admin, MemberRole::Member] {
+            assert_eq!(serde_json::to_value(role).unwrap(), role.as_str());
+        }
+        for visibility in [Visibility::Own, Visibility::Team, Visibility::Org] {
+            assert_eq!(
+                serde_json::to_value(visibility).unwrap(),
+                visibility.as_str()
+            );
+        }
+    }
+
+    #[test]
+    fn only_owners_and_admins_manage_the_organization() {
+        let membership = |role: MemberRole| Membership {
+            organization_id: 1,
+            role: role.as_str().to_string(),
+        };
+        assert!(membership(MemberRole::Owner).is_admin());
+        assert!(membership(MemberRole::Admin).is_admin());
+        assert!(!membership(MemberRole::Member).is_admin());
+    }
+
+    #[test]
+    fn member_updates_tell_a_null_team_from_a_missing_one() {
+        let update: MemberUpdate = serde_json::from_str(r#"{"team_id": 4}"#).unwrap();
+        assert_eq!(update.team_id, Some(Some(4)));
+        let update: MemberUpdate = serde_json::from_str(r#"{"team_id": null}"#).unwrap();
+        assert_eq!(update.team_id, Some(None));
+        let update: MemberUpdate = serde_json::from_str(r#"{"role": "admin"}"#).unwrap();
+        assert_eq!(update.team_id, None);
+        assert_eq!(update.role, Some(MemberRole::Admin));
+    }
+}
@@ -1,4 +1,5 @@
 use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use stripe::{
     AttachPaymentMethod, CardDetailsParams, CreateCustomer, CreatePaymentMethod,
@@ -6,6 +7,8 @@ use stripe::{
     CustomerId, PaymentMethod, PaymentMethodTypeFilter, Subscription,
 };
 
+use crate::email::Receipt;
+use crate::email_queue::enqueue_email;
 use crate::AppState;
 
 #[derive(Deserialize, Serialize)]
@@ -77,13 +80,37 @@ pub async fn create_checkout(
 
     params.default_payment_method = Some(&payment_method.id);
 
-    let Ok(_) = Subscription::create(&ctx, params).await else {
+    let Ok(subscription) = Subscription::create(&ctx, params).await else {
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     };
 
+    let receipt = subscription_receipt(&req.name, &subscription);
+    enqueue_email(&state.postgres, state.mailer.receipt(&req.email, &receipt)).await;
+
     Ok(StatusCode::OK)
 }
 
+fn subscription_receipt(name: &str, subscription: &Subscription) -> Receipt {
+    let amount = subscription
+        .items
+        .data
+        .iter()
+        .filter_map(|item| {
+            let unit_amount = item.price.as_ref()?.unit_amount?;
+            Some(unit_amount * item.quantity.unwrap_or(1) as i64)
+        })
+        .sum();
+
+    Receipt {
+        name: name.to_string(),
+        reference: subscription.id.to_string(),
+        description: "Subscription".to_string(),
+        amount,
+        currency: subscription.currency.to_string(),
+        date: DateTime::from_timestamp(subscription.created, 0).unwrap_or_else(Utc::now),
+    }
+}
+
 fn create_checkout_params(customer_id: CustomerId, price: String) -> CreateSubscription<'static> {
     let mut params = CreateSubscription::new(customer_id);
     params.items = Some(vec![CreateSubscriptionItems {
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::inbound_email::SIGNATURE_MAX_AGE_MINUTES;
+
+/// Days a deleted deal or customer stays in the trash before it is purged.
+pub const RESTORE_WINDOW_DAYS: i32 = 30;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Starts the background task that permanently removes records whose restore
+/// window has passed.
+pub fn spawn_purge_task(postgres: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_deleted(&postgres).await {
+                eprintln!("Error purging deleted records: {:?}", e);
+            }
+        }
+    });
+}
+
+async fn purge_deleted(postgres: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = postgres.begin().await?;
+
+    let deals =
+        sqlx::query("DELETE FROM deals WHERE deleted_at < NOW() - make_interval(days => $1)")
+            .bind(RESTORE_WINDOW_DAYS)
+            .execute(&mut *tx)
+            .await?;
+
+    // Deals have no foreign key to customers, so remove a purged customer's
+    // remaining deals alongside it.
+    sqlx::query(
+        "DELETE FROM deals WHERE customer_id IN (SELECT id FROM customers WHERE deleted_at < NOW() - make_interval(days => $1))",
+    )
+    .bind(RESTORE_WINDOW_DAYS)
+    .execute(&mut *tx)
+    .await?;
+
+    let customers =
+        sqlx::query("DELETE FROM customers WHERE deleted_at < NOW() - make_interval(days => $1)")
+            .bind(RESTORE_WINDOW_DAYS)
+            .execute(&mut *tx)
+            .await?;
+
+    // Sent mail only matters for troubleshooting, so it follows the same
+    // window. Dead jobs stay until someone retries them.
+    sqlx::query("DELETE FROM email_queue WHERE status = 'sent' AND sent_at < NOW() - make_interval(days => $1)")
+        .bind(RESTORE_WINDOW_DAYS)
+        .execute(&mut *tx)
+        .await?;
+
+    // Requests signed before then are refused on their timestamp alone, so
+    // their tokens can't be replayed anyway. Keep them a little longer to
+    // allow for clock skew.
+    sqlx::query(
+        "DELETE FROM inbound_webhook_tokens WHERE seen_at < NOW() - make_interval(mins => $1)",
+    )
+    .bind(SIGNATURE_MAX_AGE_MINUTES * 2)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if deals.rows_affected() > 0 || customers.rows_affected() > 0 {
+        println!(
+            "Purged {} deals and {} customers past the restore window",
+            deals.rows_affected(),
+            customers.rows_affected()
+        );
+    }
+
+    Ok(())
+}
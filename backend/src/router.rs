@@ -6,7 +6,7 @@ use axum::{
     extract::DefaultBodyLimit,
     http::{self},
     middleware::{self},
-    routing::{delete, get, post, put},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use http::header::{ACCEPT, AUTHORIZATION, ORIGIN};
@@ -15,13 +15,51 @@ use http::Method;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::activities::{
+    create_activity, destroy_activity, get_activities, get_activity, get_upcoming_tasks,
+    update_activity,
+};
 use crate::auth::{login, logout, register, validate_session};
-use crate::dashboard::get_dashboard_data;
-use crate::deals::{create_deal, destroy_deal, edit_deal, get_all_deals, get_one_deal};
-use crate::mail::subscribe;
+use crate::csv_io::{export_customers, export_deals, import_customers, import_deals};
+use crate::custom_fields::{
+    create_field_definition, destroy_field_definition, get_field_definitions, set_customer_fields,
+    set_customer_tags, set_deal_fields, set_deal_tags, update_field_definition,
+};
+use crate::customers::{
+    archive_customer, create_customer, destroy_customer, edit_customer, get_all_customers,
+    get_customer_names, get_one_customer, restore_customer, unarchive_customer,
+};
+use crate::dashboard::{get_dashboard_data, get_metrics, get_recent_sales, get_top_customers};
+use crate::deals::{
+    add_deal_note, archive_deal, create_deal, destroy_deal, edit_deal, get_all_deals,
+    get_deal_history, get_deleted_deals, get_one_deal, restore_deal, unarchive_deal, update_deal,
+};
+use crate::duplicates::{find_duplicates, merge_customers};
+use crate::email_queue::{get_queue, retry_dead_jobs, retry_job};
+use crate::forecast::{get_forecast, get_probabilities, set_probabilities};
+use crate::gallery::{
+    add_gallery_item, get_gallery, remove_gallery_item, reorder_gallery, update_gallery_caption,
+};
+use crate::inbound_email::{
+    assign_inbound_email, dismiss_inbound_email, get_triage, receive_inbound_email,
+};
+use crate::media::{upload_media, VIDEO_LIMIT};
+use crate::media_gc::get_orphan_report;
+use crate::newsletter::{confirm_subscription, subscribe, unsubscribe, unsubscribe_page};
+use crate::organizations::{
+    add_member, assign_customer, assign_deal, create_organization, create_team, destroy_team,
+    get_organization, remove_member, update_member,
+};
 use crate::payments::create_checkout;
 use crate::user;
 
+/// Mailgun's own limit on message size, attachments included.
+const INBOUND_EMAIL_LIMIT: usize = 25 * 1024 * 1024;
+
+/// Per-type limits are enforced while the upload streams in; this only
+/// stops requests that couldn't be valid at all.
+const MEDIA_UPLOAD_LIMIT: usize = VIDEO_LIMIT as usize + 1024 * 1024;
+
 pub fn create_api_router(state: AppState) -> Router {
     // let cors = CorsLayer::new()
     //     .allow_credentials(true)
@@ -37,25 +75,100 @@ pub fn create_api_router(state: AppState) -> Router {
 
     let payments_router = Router::new().route("/pay", post(create_checkout));
 
-    // let customers_router = Router::new()
-    //     .route("/", post(get_all_customers))
-    //     .route("/names", post(get_customer_names))
-    //     .route(
-    //         "/:id",
-    //         post(get_one_customer)
-    //             .put(edit_customer)
-    //             .delete(destroy_customer),
-    //     )
-    //     .route("/create", post(create_customer));
+    let customers_router = Router::new()
+        .route("/", post(get_all_customers))
+        .route("/names", post(get_customer_names))
+        .route("/duplicates", get(find_duplicates))
+        .route(
+            "/:id",
+            get(get_one_customer)
+                .post(get_one_customer)
+                .patch(edit_customer)
+                .delete(destroy_customer),
+        )
+        .route("/:id/archive", post(archive_customer))
+        .route("/:id/unarchive", post(unarchive_customer))
+        .route("/:id/restore", post(restore_customer))
+        .route("/:id/merge", post(merge_customers))
+        .route("/:id/fields", put(set_customer_fields))
+        .route("/:id/tags", put(set_customer_tags))
+        .route("/:id/assign", post(assign_customer))
+        .route("/create", post(create_customer))
+        .route("/import", post(import_customers))
+        .route("/export", get(export_customers));
 
     let deals_router = Router::new()
         .route("/", post(get_all_deals))
         .route(
             "/:id",
-            post(get_one_deal).put(edit_deal).delete(destroy_deal),
+            post(get_one_deal)
+                .put(edit_deal)
+                .patch(update_deal)
+                .delete(destroy_deal),
         )
+        .route("/:id/history", post(get_deal_history))
+        .route("/:id/notes", post(add_deal_note))
+        .route("/:id/archive", post(archive_deal))
+        .route("/:id/unarchive", post(unarchive_deal))
+        .route("/:id/restore", post(restore_deal))
+        .route("/:id/fields", put(set_deal_fields))
+        .route("/:id/tags", put(set_deal_tags))
+        .route("/:id/assign", post(assign_deal))
+        .route("/trash", post(get_deleted_deals))
+        .route("/import", post(import_deals))
+        .route("/export", get(export_deals))
         .route("/create", post(create_deal));
 
+    let activities_router = Router::new()
+        .route("/", get(get_activities).post(create_activity))
+        .route("/upcoming", get(get_upcoming_tasks))
+        .route(
+            "/:id",
+            get(get_activity)
+                .patch(update_activity)
+                .delete(destroy_activity),
+        );
+
+    let custom_fields_router = Router::new()
+        .route(
+            "/",
+            get(get_field_definitions).post(create_field_definition),
+        )
+        .route(
+            "/:id",
+            patch(update_field_definition).delete(destroy_field_definition),
+        );
+
+    let forecast_router = Router::new().route("/", get(get_forecast)).route(
+        "/probabilities",
+        get(get_probabilities).put(set_probabilities),
+    );
+
+    let organizations_router = Router::new()
+        .route("/", get(get_organization).post(create_organization))
+        .route("/members", post(add_member))
+        .route(
+            "/members/:user_id",
+            patch(update_member).delete(remove_member),
+        )
+        .route("/teams", post(create_team))
+        .route("/teams/:id", delete(destroy_team));
+
+    let admin_router = Router::new()
+        .route("/email-queue", get(get_queue))
+        .route("/email-queue/retry", post(retry_dead_jobs))
+        .route("/email-queue/:id/retry", post(retry_job))
+        .route("/inbound-emails", get(get_triage))
+        .route("/inbound-emails/:id/assign", post(assign_inbound_email))
+        .route("/inbound-emails/:id/dismiss", post(dismiss_inbound_email))
+        .route("/media/orphans", get(get_orphan_report));
+
+    let dashboard_router = Router::new()
+        .route("/", post(get_dashboard_data))
+        .route("/top-customers", get(get_top_customers))
+        .route("/recent-sales", get(get_recent_sales))
+        .route("/metrics", get(get_metrics));
+
     let auth_router = Router::new()
         .route("/register", post(register))
         .route("/login", post(login))
@@ -65,18 +178,48 @@ pub fn create_api_router(state: AppState) -> Router {
         .route("/create", post(create))
         .route("/get", get(get_all));
 
+    // Profiles are public, but only their owner can change or delete them,
+    // their media and their gallery.
+    let session = middleware::from_fn_with_state(state.clone(), validate_session);
     let user_router = Router::new()
         .route("/create", post(user::create))
-        .route("/update/:username", put(user::update))
-        .layer(DefaultBodyLimit::max(50 * 1024 * 1024))
+        .route(
+            "/update/:username",
+            put(user::update).layer(session.clone()),
+        )
+        .route(
+            "/media/:username",
+            post(upload_media)
+                .layer(DefaultBodyLimit::max(MEDIA_UPLOAD_LIMIT))
+                .layer(session.clone()),
+        )
+        .route(
+            "/gallery/:username",
+            get(get_gallery).merge(post(add_gallery_item).layer(session.clone())),
+        )
+        .route(
+            "/gallery/:username/order",
+            put(reorder_gallery).layer(session.clone()),
+        )
+        .route(
+            "/gallery/:username/:id",
+            patch(update_gallery_caption)
+                .delete(remove_gallery_item)
+                .layer(session.clone()),
+        )
         .route("/get", get(user::get))
-        .route("/delete/:username", delete(user::delete));
+        .route("/delete/:username", delete(user::delete).layer(session));
 
     Router::new()
-        // .nest("/customers", customers_router)
+        .nest("/customers", customers_router)
+        .nest("/activities", activities_router)
+        .nest("/custom-fields", custom_fields_router)
         .nest("/deals", deals_router)
         .nest("/payments", payments_router)
-        .route("/dashboard", post(get_dashboard_data))
+        .nest("/dashboard", dashboard_router)
+        .nest("/forecast", forecast_router)
+        .nest("/organizations", organizations_router)
+        .nest("/admin", admin_router)
         .layer(middleware::from_fn_with_state(
             state.clone(),
             validate_session,
@@ -84,6 +227,12 @@ pub fn create_api_router(state: AppState) -> Router {
         .nest("/auth", auth_router)
         .nest("/order", order_router)
         .route("/subscribe", post(subscribe))
+        .route(
+            "/inbound/mailgun",
+            post(receive_inbound_email).layer(DefaultBodyLimit::max(INBOUND_EMAIL_LIMIT)),
+        )
+        .route("/subscribe/confirm", get(confirm_subscription))
+        .route("/unsubscribe", get(unsubscribe_page).post(unsubscribe))
         .route("/health", get(hello_world))
         .nest("/user", user_router)
         .with_state(state)
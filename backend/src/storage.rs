@@ -0,0 +1,710 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::Utc;
+use futures_util::stream::{BoxStream, StreamExt};
+use hmac::{Hmac, Mac};
+use reqwest::{Body, Client, Method, RequestBuilder};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+/// An object's contents as they arrive, so nothing has to hold a whole file
+/// in memory.
+pub type ByteStream = BoxStream<'static, Result<Bytes, std::io::Error>>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// Keys are made of path segments of letters, digits, `.`, `-` and `_`.
+    InvalidKey(String),
+    /// The backend couldn't be reached, or the file couldn't be read or
+    /// written.
+    Transport(String),
+    /// The backend answered, but didn't do what was asked.
+    Rejected {
+        status: u16,
+        body: String,
+    },
+    Config(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::InvalidKey(key) => write!(f, "invalid storage key {key}"),
+            StorageError::Transport(msg) => write!(f, "couldn't reach storage: {msg}"),
+            StorageError::Rejected { status, body } => {
+                write!(f, "storage refused with status {status}: {body}")
+            }
+            StorageError::Config(msg) => write!(f, "storage is misconfigured: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Transport(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(err: reqwest::Error) -> Self {
+        StorageError::Transport(err.to_string())
+    }
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Stores a new object. Fails if `key` is already taken.
+    async fn upload(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError>;
+
+    /// Replaces the object at `key`, keeping its URL.
+    async fn overwrite(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError>;
+
+    /// Removes the object at `key`. Removing one that isn't there succeeds.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Where browsers can load the object at `key` from.
+    fn public_url(&self, key: &str) -> String;
+}
+
+/// Supabase Storage, with every object in one public bucket.
+pub struct SupabaseStorage {
+    client: Client,
+    url: String,
+    bucket: String,
+    api_key: String,
+}
+
+impl SupabaseStorage {
+    pub fn new(url: &str, bucket: &str, api_key: &str) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    fn object_url(&self, key: &str) -> String {
+        format!("{}/storage/v1/object/{}/{}", self.url, self.bucket, key)
+    }
+
+    async fn put(
+        &self,
+        method: Method,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        let res = self
+            .client
+            .request(method, self.object_url(key))
+            .bearer_auth(&self.api_key)
+            .header("Content-Type", content_type)
+            .body(Body::wrap_stream(body))
+            .send()
+            .await?;
+
+        check_response(res).await
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SupabaseStorage {
+    async fn upload(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        self.put(Method::POST, key, content_type, body).await
+    }
+
+    async fn overwrite(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        self.put(Method::PUT, key, content_type, body).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let res = self
+            .client
+            .delete(self.object_url(key))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+
+        match check_response(res).await {
+            // Supabase answers 400 with a not_found error for missing objects.
+            Err(StorageError::Rejected { status, body })
+                if status == 404 || (status == 400 && body.contains("not_found")) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        format!(
+            "{}/storage/v1/object/public/{}/{}",
+            self.url, self.bucket, key
+        )
+    }
+}
+
+/// Any S3-compatible service (AWS, R2, MinIO...), addressed path-style and
+/// signed with Signature Version 4.
+pub struct S3Storage {
+    client: Client,
+    endpoint: reqwest::Url,
+    region: String,
+    bucket: String,
+    access_key_id: String,
+    secret_access_key: String,
+    public_url: String,
+}
+
+impl S3Storage {
+    /// `public_url` is where objects are served from, e.g. a CDN in front of
+    /// the bucket. It defaults to the bucket's own URL.
+    pub fn new(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+        public_url: Option<&str>,
+    ) -> Result<Self, StorageError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let public_url = match public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("{endpoint}/{bucket}"),
+        };
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint: endpoint
+                .parse()
+                .map_err(|err| StorageError::Config(format!("S3_ENDPOINT: {err}")))?,
+            region: region.to_string(),
+            bucket: bucket.to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            public_url,
+        })
+    }
+
+    /// Builds a signed request for `key`. Bodies aren't hashed, which S3
+    /// allows over HTTPS and which lets them be streamed.
+    fn request(&self, method: Method, key: &str, payload_hash: &str) -> RequestBuilder {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut url = self.endpoint.clone();
+        // Keys and bucket names only use characters that need no escaping.
+        url.set_path(&format!(
+            "{}/{}/{}",
+            url.path().trim_end_matches('/'),
+            self.bucket,
+            key
+        ));
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}",
+            url.path()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac_sha256(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        self.client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key_id
+                ),
+            )
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+        only_if_new: bool,
+    ) -> Result<(), StorageError> {
+        // S3 needs the length up front, which streamed uploads don't have,
+        // so the body is spooled to disk first.
+        let spool = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+        let result = async {
+            let length = spool_to(&spool, body).await?;
+            let file = tokio::fs::File::open(&spool).await?;
+
+            let mut req = self
+                .request(Method::PUT, key, "UNSIGNED-PAYLOAD")
+                .header("Content-Type", content_type)
+                .header("Content-Length", length);
+            if only_if_new {
+                req = req.header("If-None-Match", "*");
+            }
+            let res = req.body(Body::from(file)).send().await?;
+
+            check_response(res).await
+        }
+        .await;
+
+        let _ = tokio::fs::remove_file(&spool).await;
+        result
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn upload(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        self.put(key, content_type, body, true).await
+    }
+
+    async fn overwrite(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        self.put(key, content_type, body, false).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let empty_hash = hex::encode(Sha256::digest([]));
+        let res = self
+            .request(Method::DELETE, key, &empty_hash)
+            .send()
+            .await?;
+
+        check_response(res).await
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url, key)
+    }
+}
+
+/// Files in a local directory, served by this server under `base_url`.
+/// For development and tests, where there's no storage service to talk to.
+pub struct LocalStorage {
+    dir: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>, base_url: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Writes next to the destination and renames into place, so a failed
+    /// upload never leaves half a file behind.
+    async fn write(&self, key: &str, body: ByteStream) -> Result<(), StorageError> {
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let partial = path.with_extension(format!("{}.part", uuid::Uuid::new_v4()));
+        if let Err(err) = spool_to(&partial, body).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(err);
+        }
+
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn upload(
+        &self,
+        key: &str,
+        _content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        if tokio::fs::try_exists(self.dir.join(key)).await? {
+            return Err(StorageError::Rejected {
+                status: 409,
+                body: format!("{key} already exists"),
+            });
+        }
+        self.write(key, body).await
+    }
+
+    async fn overwrite(
+        &self,
+        key: &str,
+        _content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        self.write(key, body).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+}
+
+/// Which backend to use and how to reach it, read from the secrets.
+pub struct StorageConfig {
+    /// One of `supabase`, `s3` or `local`.
+    pub backend: String,
+    pub supabase_url: String,
+    pub supabase_bucket: String,
+    pub supabase_api_key: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    pub s3_bucket: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub s3_public_url: Option<String>,
+    pub local_dir: String,
+}
+
+/// Stores uploaded media with the configured backend. Every key is checked
+/// here, so backends can use them as paths as they are.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+}
+
+impl Storage {
+    /// Local files are served under this path.
+    pub const LOCAL_PATH: &'static str = "/uploads";
+
+    pub fn new(backend: Arc<dyn StorageBackend>) -> Self {
+        Self { backend }
+    }
+
+    pub fn from_config(config: StorageConfig, domain: &str) -> Result<Self, StorageError> {
+        let backend: Arc<dyn StorageBackend> = match config.backend.as_str() {
+            "supabase" => {
+                if config.supabase_url.is_empty() {
+                    return Err(StorageError::Config(
+                        "SUPABASE_STORAGE_URL must be set".to_string(),
+                    ));
+                }
+                Arc::new(SupabaseStorage::new(
+                    &config.supabase_url,
+                    &config.supabase_bucket,
+                    &config.supabase_api_key,
+                ))
+            }
+            "s3" => {
+                let (Some(endpoint), Some(bucket), Some(access_key_id), Some(secret_access_key)) = (
+                    config.s3_endpoint,
+                    config.s3_bucket,
+                    config.s3_access_key_id,
+                    config.s3_secret_access_key,
+                ) else {
+                    return Err(StorageError::Config(
+                        "S3_ENDPOINT, S3_BUCKET, S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set"
+                            .to_string(),
+                    ));
+                };
+                Arc::new(S3Storage::new(
+                    &endpoint,
+                    &config.s3_region,
+                    &bucket,
+                    &access_key_id,
+                    &secret_access_key,
+                    config.s3_public_url.as_deref(),
+                )?)
+            }
+            "local" => Arc::new(LocalStorage::new(
+                config.local_dir,
+                &format!("{}{}", domain.trim_end_matches('/'), Self::LOCAL_PATH),
+            )),
+            other => {
+                return Err(StorageError::Config(format!(
+                    "unknown storage backend {other}"
+                )))
+            }
+        };
+
+        Ok(Self::new(backend))
+    }
+
+    pub async fn upload(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        check_key(key)?;
+        self.backend.upload(key, content_type, body).await
+    }
+
+    pub async fn overwrite(
+        &self,
+        key: &str,
+        content_type: &str,
+        body: ByteStream,
+    ) -> Result<(), StorageError> {
+        check_key(key)?;
+        self.backend.overwrite(key, content_type, body).await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        check_key(key)?;
+        self.backend.delete(key).await
+    }
+
+    pub fn public_url(&self, key: &str) -> String {
+        self.backend.public_url(key)
+    }
+
+    /// The key of the object at `url`, or `None` if the URL isn't one of
+    /// ours, e.g. a link to an image hosted elsewhere.
+    pub fn key_for_url(&self, url: &str) -> Option<String> {
+        let key = url.strip_prefix(&self.backend.public_url(""))?;
+        check_key(key).ok()?;
+        Some(key.to_string())
+    }
+}
+
+fn check_key(key: &str) -> Result<(), StorageError> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidKey(key.to_string()))
+    }
+}
+
+/// Writes `body` to `path` and returns how many bytes it had.
+async fn spool_to(path: &Path, mut body: ByteStream) -> Result<u64, StorageError> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut length = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        length += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(length)
+}
+
+async fn check_response(res: reqwest::Response) -> Result<(), StorageError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    Err(StorageError::Rejected {
+        status: status.as_u16(),
+        body: res.text().await.unwrap_or_default(),
+    })
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn body(contents: &'static str) -> ByteStream {
+        futures_util::stream::once(async move { Ok(Bytes::from(contents)) }).boxed()
+    }
+
+    fn local() -> (Storage, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("storage-test-{}", uuid::Uuid::new_v4()));
+        let storage = Storage::new(Arc::new(LocalStorage::new(
+            &dir,
+            "https://crm.example.com/uploads/",
+        )));
+        (storage, dir)
+    }
+
+    #[test]
+    fn keys_are_plain_path_segments() {
+        assert!(check_key("profile_media/abc-123.webp").is_ok());
+        assert!(check_key("gallery_media/a/b/c.jpeg").is_ok());
+    }
+
+    #[test]
+    fn keys_cant_escape_or_hide() {
+        for key in [
+            "",
+            "/profile_media/a.webp",
+            "profile_media//a.webp",
+            "profile_media/",
+            "../secrets",
+            "profile_media/../../etc/passwd",
+            "profile_media/.hidden",
+            "profile_media/a b.webp",
+            "profile_media/a%2F.webp",
+            "profile_media\\a.webp",
+        ] {
+            assert!(
+                matches!(check_key(key), Err(StorageError::InvalidKey(_))),
+                "{key} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn urls_map_back_to_their_keys() {
+        let (storage, _) = local();
+        let url = storage.public_url("profile_media/a.webp");
+        assert_eq!(url, "https://crm.example.com/uploads/profile_media/a.webp");
+        assert_eq!(
+            storage.key_for_url(&url).as_deref(),
+            Some("profile_media/a.webp")
+        );
+    }
+
+    #[test]
+    fn foreign_or_unsafe_urls_have_no_key() {
+        let (storage, _) = local();
+        assert_eq!(
+            storage.key_for_url("https://elsewhere.example.com/a.webp"),
+            None
+        );
+        assert_eq!(
+            storage.key_for_url("https://crm.example.com/uploads/"),
+            None
+        );
+        assert_eq!(
+            storage.key_for_url("https://crm.example.com/uploads/../main.rs"),
+            None
+        );
+    }
+
+    #[test]
+    fn s3_public_urls_default_to_the_bucket() {
+        let s3 = S3Storage::new(
+            "https://s3.example.com/",
+            "eu-west-1",
+            "media",
+            "id",
+            "secret",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            s3.public_url("a/b.webp"),
+            "https://s3.example.com/media/a/b.webp"
+        );
+
+        let s3 = S3Storage::new(
+            "https://s3.example.com",
+            "eu-west-1",
+            "media",
+            "id",
+            "secret",
+            Some("https://cdn.example.com/"),
+        )
+        .unwrap();
+        assert_eq!(
+            s3.public_url("a/b.webp"),
+            "https://cdn.example.com/a/b.webp"
+        );
+    }
+
+    #[test]
+    fn local_uploads_dont_overwrite_unless_asked() {
+        let (storage, dir) = local();
+        let key = "profile_media/a.txt";
+
+        block_on(storage.upload(key, "text/plain", body("first"))).unwrap();
+        let err = block_on(storage.upload(key, "text/plain", body("second"))).unwrap_err();
+        assert!(matches!(err, StorageError::Rejected { status: 409, .. }));
+        assert_eq!(std::fs::read_to_string(dir.join(key)).unwrap(), "first");
+
+        block_on(storage.overwrite(key, "text/plain", body("second"))).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join(key)).unwrap(), "second");
+
+        block_on(storage.delete(key)).unwrap();
+        assert!(!dir.join(key).exists());
+        // Already gone is fine.
+        block_on(storage.delete(key)).unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_keys_never_reach_the_backend() {
+        let (storage, dir) = local();
+        let err = block_on(storage.upload("../escape.txt", "text/plain", body("x"))).unwrap_err();
+        assert!(matches!(err, StorageError::InvalidKey(_)));
+        assert!(!dir.exists());
+    }
+}
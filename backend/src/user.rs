@@ -1,17 +1,17 @@
-use std::error::Error;
-
 use axum::extract::Query;
 use axum::response::IntoResponse;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    Json,
+    Extension, Json,
 };
-use base64::decode;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use crate::auth::SessionUser;
+use crate::images::ImageVariant;
+use crate::media::{media_type, uploaded_media};
+use crate::video::VideoInfo;
 use crate::AppState;
 
 #[derive(Deserialize, sqlx::FromRow, Serialize)]
@@ -64,7 +64,6 @@ pub struct UserResponse {
 pub struct UserRequest {
     first_name: String,
     last_name: String,
-    email: String,
     phone: String,
     title: String,
     bio: String,
@@ -94,8 +93,6 @@ pub struct FeUserRequest {
     #[serde(rename = "lastName")]
     last_name: String,
     phone: String,
-    old_profile_media: Option<String>,
-    old_cover_media: Option<String>,
     email: String,
     title: String,
     password: Option<String>,
@@ -111,11 +108,23 @@ pub struct FeUserRequest {
     social: Vec<Social>, // List of Social media objects
 }
 
-#[derive(Debug, Deserialize, sqlx::FromRow, Serialize)]
-struct Media {
-    info: String,
-    r#type: String,
-    media: String,
+/// An entry in a profile's `media` list: the cover, which `update` sets, or
+/// an item in the gallery, which only gallery items have an `id` for.
+#[derive(Debug, Clone, Deserialize, sqlx::FromRow, Serialize)]
+pub struct Media {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The caption.
+    pub info: String,
+    /// `image`, `video`, `pdf` or `link`.
+    pub r#type: String,
+    pub media: String,
+    /// Resized copies of an uploaded image, for `srcset`s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Vec<ImageVariant>>,
+    /// Duration, size, codec and poster of an uploaded video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoInfo>,
 }
 
 // Struct for social platform information
@@ -217,10 +226,17 @@ pub async fn create(
     }
 }
 
+/// Deletes the session user's own profile. Its uploads are removed later by
+/// `media_gc` once nothing links to them.
 pub async fn delete(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Path(username): Path<String>,
 ) -> impl IntoResponse {
+    if let Err(err) = require_profile_owner(&state, user.id, &username).await {
+        return err.into_response();
+    }
+
     let query = "DELETE FROM users WHERE username = $1";
     match sqlx::query(query)
         .persistent(false)
@@ -242,133 +258,59 @@ pub async fn delete(
     }
 }
 
+/// Updates the session user's own profile. The email address is left alone,
+/// since it's what ties the profile to their account.
 pub async fn update(
     State(state): State<AppState>,
+    Extension(user): Extension<SessionUser>,
     Path(username): Path<String>,
     Json(updated_user): Json<FeUserRequest>,
 ) -> impl IntoResponse {
-    println!("debug 0");
-    // mapping updated_user to struct UserRequest
-    // remove profile photo and upload ......
-    let mut profile_media = String::new();
-    let mut cover_media: Vec<Media> = Vec::new();
-    match updated_user.old_profile_media {
-        Some(x) if !x.is_empty() => {
-            println!("debug 1");
-            // overwrite existing one with new one
-            match overwrite_in_supabase(&state.supabase_api_key, &x, &updated_user.profile_image)
-                .await
-            {
-                Ok((file_link, _mime_type)) => profile_media = file_link,
-                Err(e) => {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-                }
-            }
-            println!("debug 2");
-        }
-        _ => {
-            println!("debug 3");
-            // Check if the cover_image is a base64 string or a link
-            if updated_user.profile_image.starts_with("http://")
-                || updated_user.profile_image.starts_with("https://")
-            {
-                println!("debug 4");
-                // It's a link, no need to upload
-                profile_media = updated_user.profile_image;
-            } else {
-                println!("debug 5");
-                // upload new first profile photo
-                match upload_to_supabase(
-                    &state.supabase_api_key,
-                    &state.supabase_storage_url,
-                    "profile_media",
-                    &updated_user.profile_image,
-                )
-                .await
-                {
-                    Ok((file_link, _mime_type)) => profile_media = file_link,
-                    Err(e) => {
-                        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-                    }
-                }
-            }
-        }
+    if let Err(err) = require_profile_owner(&state, user.id, &username).await {
+        return err.into_response();
     }
-    println!("debug 6");
-    match updated_user.old_cover_media {
-        Some(x) if !x.is_empty() && (x.starts_with("http://") || x.starts_with("https://")) => {
-            println!("debug 7");
-            // overwrite existing one with new one
-            match overwrite_in_supabase(&state.supabase_api_key, &x, &updated_user.cover_image)
-                .await
-            {
-                Ok((file_link, mime_type)) => {
-                    println!("debug 8");
-                    cover_media = vec![Media {
-                        info: "This Is My Cover Media".to_string(),
-                        r#type: match mime_type {
-                            mime if mime.starts_with("image/") => "image".to_string(),
-                            mime if mime.starts_with("video/") => "video".to_string(),
-                            _ => "image".to_string(),
-                        },
-                        media: file_link,
-                    }]
-                }
-                Err(e) => {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-                }
-            }
-        }
-        _ => {
-            println!("debug 9");
-            // Check if the cover_image is a base64 string or a link
-            if updated_user.cover_image.starts_with("http://")
-                || updated_user.cover_image.starts_with("https://")
-            {
-                // It's a link, no need to upload
-                cover_media = vec![Media {
-                    info: "This Is My Cover Media".to_string(),
-                    r#type: "image".to_string(), // Assuming it's an image link
-                    media: updated_user.cover_image.clone(),
-                }];
-            } else {
-                println!("debug 10");
-                // If it's not a link, assume it's base64 and continue with the upload process
-                // upload new first profile photo
-                match upload_to_supabase(
-                    &state.supabase_api_key,
-                    &state.supabase_storage_url,
-                    "cover_media",
-                    &updated_user.cover_image,
-                )
-                .await
-                {
-                    Ok((file_link, mime_type)) => {
-                        println!("debug 11");
-                        cover_media = vec![Media {
-                            info: "This Is My Cover Media".to_string(),
-                            r#type: match mime_type {
-                                mime if mime.starts_with("image/") => "image".to_string(),
-                                mime if mime.starts_with("video/") => "video".to_string(),
-                                _ => "image".to_string(),
-                            },
-                            media: file_link,
-                        }]
-                    }
-                    Err(e) => {
-                        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-                    }
-                }
-            }
+
+    // Media is uploaded through `media::upload_media` first and referenced
+    // here by its URL.
+    for link in [&updated_user.profile_image, &updated_user.cover_image] {
+        if !link.is_empty() && !link.starts_with("http://") && !link.starts_with("https://") {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Upload media first and send its URL",
+            )
+                .into_response();
         }
     }
-    println!("debug 12");
-    println!("this is upload profile response {:?}", profile_media);
-    println!("this is upload cover response {:?}", cover_media);
+
+    let cover_media = if updated_user.cover_image.is_empty() {
+        Vec::new()
+    } else {
+        // Links to files that weren't uploaded here are assumed to be images.
+        let upload = match uploaded_media(&state, &updated_user.cover_image).await {
+            Ok(upload) => upload,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+        let cover_type = upload
+            .as_ref()
+            .and_then(|upload| media_type(&upload.mime_type))
+            .unwrap_or("image")
+            .to_string();
+        vec![Media {
+            id: None,
+            info: "This Is My Cover Media".to_string(),
+            r#type: cover_type,
+            media: updated_user.cover_image.clone(),
+            variants: upload
+                .as_ref()
+                .and_then(|upload| upload.variants.clone().map(|variants| variants.0)),
+            video: upload.and_then(|upload| upload.video.map(|video| video.0)),
+        }]
+    };
+    let profile_media = updated_user.profile_image;
+
     let request = UserRequest {
         first_name: updated_user.first_name,
         last_name: updated_user.last_name,
-        email: updated_user.email,
         phone: updated_user.phone,
         title: updated_user.title,
         bio: updated_user.bio,
@@ -378,13 +320,13 @@ pub async fn update(
         media: cover_media,
         social: updated_user.social,
     };
-    let query = "UPDATE users SET first_name = $1, last_name = $2, email = $3, phone = $4, title = $5, bio = $6, photo = $7, qr_code = $8, theme = $9, media = $10::jsonb, social = $11::jsonb WHERE username = $12 RETURNING *";
+    // Only the cover is replaced; gallery items are kept where they are.
+    let query = "UPDATE users SET first_name = $1, last_name = $2, phone = $3, title = $4, bio = $5, photo = $6, qr_code = $7, theme = $8, media = $9::jsonb || COALESCE((SELECT jsonb_agg(item ORDER BY position) FROM jsonb_array_elements(users.media::jsonb) WITH ORDINALITY AS items(item, position) WHERE item->>'id' IS NOT NULL), '[]'::jsonb), social = $10::jsonb WHERE username = $11 RETURNING *";
     println!("debug 13");
     match sqlx::query(query)
         .persistent(false)
         .bind(request.first_name)
         .bind(request.last_name)
-        .bind(request.email)
         .bind(request.phone)
         .bind(request.title)
         .bind(request.bio)
@@ -438,122 +380,70 @@ pub async fn update(
     }
 }
 
-// Function to overwrite profile photo to Supabase Storage
-pub async fn overwrite_in_supabase(
-    supabase_api_key: &str,
-    file_link: &str,
-    base64_data: &str,
-) -> Result<(String, String), Box<dyn Error>> {
-    let client = Client::new();
-
-    // Decode the base64 string
-    let base64_data = base64_data
-        .trim_start_matches("data:image/jpeg;base64,")
-        .trim_start_matches("data:image/png;base64,")
-        .trim_start_matches("data:image/gif;base64,")
-        .trim_start_matches("data:image/webp;base64,")
-        .trim_start_matches("data:video/mp4;base64,")
-        .trim_start_matches("data:video/quicktime;base64,")
-        .trim_start_matches("data:video/webm;base64,");
-
-    let decoded_bytes = decode(base64_data)?;
-
-    // Detect MIME type from the decoded data
-    let mime_type = detect_mime_type(&decoded_bytes)?;
-
-    // Use PUT request to overwrite the file in Supabase Storage
-    let read_url = file_link;
-    let upload_url = file_link.replace("public/", "");
-    println!("Uploading to: {}", upload_url);
+/// Lets the request through only if the profile `username` belongs to the
+/// session user, i.e. was created with the same email address as their
+/// account. Unknown profiles are a 404.
+pub async fn require_profile_owner(
+    state: &AppState,
+    user_id: i32,
+    username: &str,
+) -> Result<(), (StatusCode, String)> {
+    let account_email =
+        match sqlx::query_scalar::<_, String>("SELECT email FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&state.postgres)
+            .await
+        {
+            Ok(email) => email,
+            Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        };
 
-    let response = client
-        .put(upload_url)
-        .bearer_auth(supabase_api_key) // Supabase API key
-        .header("Content-Type", mime_type.as_str()) // Set the appropriate content type
-        .body(decoded_bytes) // Send the decoded bytes
-        .send()
-        .await?;
+    let profile_email = match sqlx::query_scalar::<_, Option<String>>(
+        "SELECT email FROM users WHERE username = $1",
+    )
+    .persistent(false)
+    .bind(username)
+    .fetch_optional(&state.supabase_postgres)
+    .await
+    {
+        Ok(Some(email)) => email,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
 
-    if response.status().is_success() {
-        println!("File overwritten successfully.");
-        return Ok((read_url.to_string(), mime_type));
+    if owns_profile(&account_email, profile_email.as_deref()) {
+        Ok(())
     } else {
-        return Err(format!("Failed to overwrite file: {}", response.text().await?).into());
+        Err((
+            StatusCode::FORBIDDEN,
+            "You can only change your own profile".to_string(),
+        ))
     }
 }
 
-// Function to upload file to Supabase Storage
-pub async fn upload_to_supabase(
-    supabase_api_key: &str,
-    supabase_url: &str,
-    upload_bucket: &str,
-    base64_data: &str,
-) -> Result<(String, String), Box<dyn Error>> {
-    let client = Client::new();
-    // Decode the base64 string
-    let data = base64_data
-        .trim_start_matches("data:image/jpeg;base64,")
-        .trim_start_matches("data:image/png;base64,")
-        .trim_start_matches("data:image/gif;base64,")
-        .trim_start_matches("data:image/webp;base64,")
-        .trim_start_matches("data:video/mp4;base64,")
-        .trim_start_matches("data:video/quicktime;base64,")
-        .trim_start_matches("data:video/webm;base64,");
-    let decoded_bytes = decode(data)?;
-
-    // Determine if the file is an image or a video based on the first few bytes
-    let mime_type = detect_mime_type(&decoded_bytes)?;
-
-    // Generate a random hash for the file name
-    let file_name = uuid::Uuid::new_v4().to_string();
-    let read_url = format!(
-        "{}/storage/v1/object/public/biz_touch/{}/{}",
-        supabase_url, upload_bucket, file_name
-    );
-    let upload_url = format!(
-        "{}/storage/v1/object/biz_touch/{}/{}",
-        supabase_url, upload_bucket, file_name
-    );
-
-    // Upload to Supabase Storage
-    match client
-        .post(&upload_url)
-        .bearer_auth(supabase_api_key) // Supabase API key
-        .header("Content-Type", mime_type.as_str()) // Set the appropriate content type
-        .body(decoded_bytes) // Send the decoded bytes
-        .send()
-        .await
-    {
-        Ok(x) => {
-            if x.status().is_success() {
-                Ok((read_url, mime_type))
-            } else {
-                Err(format!("Failed to upload file: {}", x.text().await?).into())
-            }
-        }
-        Err(e) => Err(format!("Failed to upload file: {}", e.to_string()).into()),
-    }
+/// Profiles without an email address belong to nobody.
+fn owns_profile(account_email: &str, profile_email: Option<&str>) -> bool {
+    profile_email.is_some_and(|profile_email| {
+        let profile_email = profile_email.trim();
+        !profile_email.is_empty() && profile_email.eq_ignore_ascii_case(account_email.trim())
+    })
 }
 
-// Function to detect MIME type (image/video) based on the first few bytes of the file
-fn detect_mime_type(bytes: &[u8]) -> Result<String, Box<dyn Error>> {
-    // Use infer to detect the MIME type based on file content
-    if let Some(kind) = infer::get(bytes) {
-        Ok(kind.mime_type().to_string())
-    } else {
-        Ok("application/octet-stream".to_string()) // Default to octet-stream if not recognized
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_belong_to_the_account_with_their_email() {
+        assert!(owns_profile("jane@example.com", Some("jane@example.com")));
+        assert!(owns_profile("Jane@Example.com", Some(" jane@example.com ")));
+        assert!(!owns_profile("jane@example.com", Some("john@example.com")));
     }
-}
 
-fn get_file_extension(mime_type: &str) -> &str {
-    match mime_type {
-        "image/jpeg" => "jpeg",
-        "image/png" => "png",
-        "image/gif" => "gif",
-        "image/webp" => "webp",
-        "video/mp4" => "mp4",
-        "video/quicktime" => "mov",
-        "video/webm" => "webm",
-        _ => "unknown", // Handle unknown types gracefully
+    #[test]
+    fn profiles_without_an_email_belong_to_nobody() {
+        assert!(!owns_profile("jane@example.com", None));
+        assert!(!owns_profile("jane@example.com", Some("")));
+        assert!(!owns_profile("", Some(" ")));
     }
 }
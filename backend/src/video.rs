@@ -0,0 +1,330 @@
+use std::fmt;
+use std::path::Path;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::images::ImageVariant;
+
+/// ffprobe only reads the headers, so this is generous.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Seeking to the poster frame can mean decoding up to a second of video.
+const POSTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits for video covers and the tools used to check them, read from the
+/// secrets.
+#[derive(Clone)]
+pub struct VideoConfig {
+    pub max_duration_secs: f64,
+    /// Applies to the longer side, so portrait and landscape videos get the
+    /// same allowance.
+    pub max_long_side: u32,
+    pub max_short_side: u32,
+    pub ffprobe: String,
+    pub ffmpeg: String,
+}
+
+/// What a video cover was found to be when it was uploaded.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VideoInfo {
+    pub duration_secs: f64,
+    /// As displayed, with any rotation applied.
+    pub width: u32,
+    pub height: u32,
+    pub codec: String,
+    /// A still to show before the video plays or where it can't, as the
+    /// `full` JPEG of `poster_variants`. Missing if no frame could be taken.
+    pub poster: Option<String>,
+    #[serde(default)]
+    pub poster_variants: Vec<ImageVariant>,
+}
+
+#[derive(Debug)]
+pub enum VideoError {
+    /// ffprobe or ffmpeg couldn't be run at all, usually because it isn't
+    /// installed.
+    Tool(String),
+    /// The file isn't a video ffprobe can read, or reading it took too long.
+    Unreadable(String),
+    /// The video is readable but over a configured limit.
+    TooLarge(String),
+}
+
+impl fmt::Display for VideoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoError::Tool(msg) => write!(f, "video processing is unavailable: {msg}"),
+            VideoError::Unreadable(msg) => write!(f, "the video couldn't be read: {msg}"),
+            VideoError::TooLarge(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for VideoError {}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    duration: Option<String>,
+    #[serde(default)]
+    side_data_list: Vec<ProbeSideData>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeSideData {
+    rotation: Option<i32>,
+}
+
+/// Reads the first video stream's duration, size and codec with ffprobe and
+/// checks them against the configured limits. The poster isn't filled in.
+pub async fn probe(config: &VideoConfig, path: &Path) -> Result<VideoInfo, VideoError> {
+    let mut command = Command::new(&config.ffprobe);
+    command
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=codec_name,width,height,duration:stream_side_data=rotation:format=duration",
+            "-of",
+            "json",
+        ])
+        .arg(path);
+    let output = run(&config.ffprobe, command, PROBE_TIMEOUT).await?;
+
+    if !output.status.success() {
+        return Err(VideoError::Unreadable(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    read_probe(config, &output.stdout)
+}
+
+/// Turns ffprobe's JSON output into what we keep about the video, if it is
+/// within the limits.
+fn read_probe(config: &VideoConfig, stdout: &[u8]) -> Result<VideoInfo, VideoError> {
+    let probed: ProbeOutput =
+        serde_json::from_slice(stdout).map_err(|err| VideoError::Unreadable(err.to_string()))?;
+    let Some(stream) = probed.streams.into_iter().next() else {
+        return Err(VideoError::Unreadable("it has no video stream".to_string()));
+    };
+
+    // Containers don't always give streams their own duration.
+    let duration_secs = stream
+        .duration
+        .as_deref()
+        .or(probed
+            .format
+            .as_ref()
+            .and_then(|format| format.duration.as_deref()))
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .ok_or_else(|| VideoError::Unreadable("its duration is unknown".to_string()))?;
+    let (Some(mut width), Some(mut height)) = (stream.width, stream.height) else {
+        return Err(VideoError::Unreadable("its size is unknown".to_string()));
+    };
+
+    // Phones record portrait video as landscape with a rotation on top.
+    let rotation = stream
+        .side_data_list
+        .iter()
+        .find_map(|side_data| side_data.rotation)
+        .unwrap_or(0);
+    if rotation.rem_euclid(180) == 90 {
+        std::mem::swap(&mut width, &mut height);
+    }
+
+    if duration_secs > config.max_duration_secs {
+        return Err(VideoError::TooLarge(format!(
+            "Videos can be at most {} seconds long",
+            config.max_duration_secs
+        )));
+    }
+    if width.max(height) > config.max_long_side || width.min(height) > config.max_short_side {
+        return Err(VideoError::TooLarge(format!(
+            "Videos can be at most {}x{} pixels",
+            config.max_long_side, config.max_short_side
+        )));
+    }
+
+    Ok(VideoInfo {
+        duration_secs,
+        width,
+        height,
+        codec: stream.codec_name.unwrap_or_else(|| "unknown".to_string()),
+        poster: None,
+        poster_variants: Vec::new(),
+    })
+}
+
+/// Grabs a frame a second in, or halfway through shorter videos, as a PNG.
+pub async fn poster_frame(
+    config: &VideoConfig,
+    path: &Path,
+    duration_secs: f64,
+) -> Result<Vec<u8>, VideoError> {
+    let at = (duration_secs / 2.0).min(1.0);
+
+    let mut command = Command::new(&config.ffmpeg);
+    command
+        .args(["-v", "error", "-ss", &format!("{at:.3}"), "-i"])
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"]);
+    let output = run(&config.ffmpeg, command, POSTER_TIMEOUT).await?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(VideoError::Unreadable(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Runs `command` to completion, killing it if it takes longer than
+/// `limit` so a crafted file can't keep it busy.
+async fn run(program: &str, mut command: Command, limit: Duration) -> Result<Output, VideoError> {
+    command.stdin(Stdio::null()).kill_on_drop(true);
+
+    match tokio::time::timeout(limit, command.output()).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(VideoError::Tool(format!("{program}: {err}"))),
+        Err(_) => Err(VideoError::Unreadable(format!(
+            "{program} took longer than {} seconds",
+            limit.as_secs()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> VideoConfig {
+        VideoConfig {
+            max_duration_secs: 60.0,
+            max_long_side: 1920,
+            max_short_side: 1080,
+            ffprobe: "ffprobe".to_string(),
+            ffmpeg: "ffmpeg".to_string(),
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn reads_the_first_video_stream() {
+        let info = read_probe(
+            &config(),
+            br#"{"streams": [{"codec_name": "h264", "width": 1280, "height": 720, "duration": "12.5"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(info.duration_secs, 12.5);
+        assert_eq!((info.width, info.height), (1280, 720));
+        assert_eq!(info.codec, "h264");
+        assert!(info.poster.is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_container_duration() {
+        let info = read_probe(
+            &config(),
+            br#"{"streams": [{"codec_name": "vp9", "width": 640, "height": 360}], "format": {"duration": "3.2"}}"#,
+        )
+        .unwrap();
+        assert_eq!(info.duration_secs, 3.2);
+    }
+
+    #[test]
+    fn rotated_videos_are_measured_as_displayed() {
+        let info = read_probe(
+            &config(),
+            br#"{"streams": [{"width": 1920, "height": 1080, "duration": "5", "side_data_list": [{"rotation": -90}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!((info.width, info.height), (1080, 1920));
+        assert_eq!(info.codec, "unknown");
+    }
+
+    #[test]
+    fn refuses_videos_over_the_limits() {
+        let too_long = read_probe(
+            &config(),
+            br#"{"streams": [{"width": 1280, "height": 720, "duration": "60.5"}]}"#,
+        );
+        assert!(matches!(too_long, Err(VideoError::TooLarge(_))));
+
+        let too_wide = read_probe(
+            &config(),
+            br#"{"streams": [{"width": 3840, "height": 1080, "duration": "5"}]}"#,
+        );
+        assert!(matches!(too_wide, Err(VideoError::TooLarge(_))));
+
+        let portrait = read_probe(
+            &config(),
+            br#"{"streams": [{"width": 1080, "height": 1920, "duration": "5"}]}"#,
+        );
+        assert!(portrait.is_ok());
+    }
+
+    #[test]
+    fn refuses_output_without_a_usable_stream() {
+        for stdout in [
+            &br#"{"streams": []}"#[..],
+            br#"{"streams": [{"width": 640, "height": 360}]}"#,
+            br#"{"streams": [{"duration": "5"}]}"#,
+            b"not json",
+        ] {
+            assert!(matches!(
+                read_probe(&config(), stdout),
+                Err(VideoError::Unreadable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn a_missing_tool_is_reported_as_such() {
+        let config = VideoConfig {
+            ffprobe: "/nonexistent/ffprobe".to_string(),
+            ..config()
+        };
+        let err = block_on(probe(&config, Path::new("video.mp4"))).unwrap_err();
+        assert!(matches!(err, VideoError::Tool(_)));
+        assert!(err
+            .to_string()
+            .starts_with("video processing is unavailable"));
+    }
+
+    #[test]
+    fn slow_tools_are_stopped() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let started = std::time::Instant::now();
+        let err = block_on(run("sleep", command, Duration::from_millis(100))).unwrap_err();
+        assert!(matches!(err, VideoError::Unreadable(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,22 +1,59 @@
-import { faker } from '@faker-js/faker';
+import React from 'react';
+
+interface MetricDelta {
+  current: number;
+  previous: number;
+  change: number;
+  change_pct: number | null;
+}
+
+interface PeriodMetrics {
+  leads: MetricDelta;
+  closed_value: MetricDelta;
+  open_deals: number;
+}
+
+const fetchMetrics = async (period: string): Promise<PeriodMetrics | undefined> => {
+  const url = `//${window.location.host}/api/dashboard/metrics?period=${period}`;
+  const res = await fetch(url, { mode: 'cors' });
+
+  if (res.ok) {
+    return await res.json();
+  }
+};
 
 export default function Metrics() {
+  const [week, setWeek] = React.useState<PeriodMetrics>();
+  const [month, setMonth] = React.useState<PeriodMetrics>();
+
+  React.useEffect(() => {
+    const fetchData = async () => {
+      try {
+        setWeek(await fetchMetrics('week'));
+        setMonth(await fetchMetrics('month'));
+      } catch (e: any) {
+        console.log(`Error: ${e}`);
+      }
+    };
+    fetchData();
+  }, []);
+
   return (
     <div className="flex flex-col gap-4 justify-between w-10">
       <div className="flex flex-col justify-center items-center shadow-md rounded-md transition-all px-10 py-4">
-        <p className="text-xl font-semibold text-slate-900">{faker.finance.amount()}</p>
+        <p className="text-xl font-semibold text-slate-900">{month?.leads.current ?? 0}</p>
         <h2 className="text-sm text-slate-400">Leads</h2>
       </div>
       <div className="flex flex-col justify-center items-center shadow-md rounded-md transition-all px-10 py-4">
-        <p className="text-xl font-semibold text-slate-900">{faker.finance.amount()}</p>
+        <p className="text-xl font-semibold text-slate-900">{month?.open_deals ?? 0}</p>
         <h2 className="text-sm text-slate-400">Open Sales Deals</h2>
       </div>
       <div className="flex flex-col justify-center items-center shadow-md rounded-md transition-all px-10 py-4 ">
-        <p className="text-xl font-semibold text-slate-900">£{faker.finance.amount()}</p>
+        <p className="text-xl font-semibold text-slate-900">£{week?.closed_value.current ?? 0}</p>
         <h2 className="text-sm text-slate-400">Closed this week:</h2>
       </div>
       <div className="flex flex-col justify-center items-center shadow-md rounded-md transition-all px-10 py-4">
-        <p className="text-xl font-semibold text-slate-900">£{faker.finance.amount()}</p>
+        <p className="text-xl font-semibold text-slate-900">£{month?.closed_value.current ?? 0}</p>
         <h2 className="text-sm text-slate-400">Closed this month:</h2>
       </div>
     </div>
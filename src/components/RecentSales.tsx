@@ -1,6 +1,33 @@
-import { faker } from '@faker-js/faker';
+import React from 'react';
+
+interface RecentSale {
+  id: number;
+  title: string | null;
+  customer_name: string | null;
+  amount: number;
+  closed_at: string | null;
+}
 
 export default function RecentSales() {
+  const [data, setData] = React.useState<RecentSale[]>([]);
+
+  React.useEffect(() => {
+    const fetchData = async () => {
+      const url = `//${window.location.host}/api/dashboard/recent-sales`;
+
+      try {
+        const res = await fetch(url, { mode: 'cors' });
+
+        if (res.ok) {
+          setData(await res.json());
+        }
+      } catch (e: any) {
+        console.log(`Error: ${e}`);
+      }
+    };
+    fetchData();
+  }, []);
+
   return (
     <div className="rounded-md shadow-md p-6 flex flex-col gap-4 w-full items-start bg-white">
       <h2 className="text-2xl font-bold leading-tight my-2">Recent Sales</h2>
@@ -17,19 +44,17 @@ export default function RecentSales() {
           </tr>
         </thead>
         <tbody>
-          {new Array(7).fill(0).map((_, i) => (
-            <tr key={i}>
+          {data.map((sale) => (
+            <tr key={sale.id}>
               <td className="px-5 py-5 border-b border-gray-200 text-sm">
                 <div className="flex items-center">
                   <div className="ml-3">
-                    <p className="text-gray-900 whitespace-no-wrap">
-                      {faker.name.firstName()} {faker.name.lastName()}
-                    </p>
+                    <p className="text-gray-900 whitespace-no-wrap">{sale.customer_name}</p>
                   </div>
                 </div>
               </td>
               <td className="px-5 py-5 border-b border-gray-200 text-sm">
-                <p className="text-gray-900 whitespace-no-wrap">£{faker.finance.amount()}</p>
+                <p className="text-gray-900 whitespace-no-wrap">£{sale.amount}</p>
               </td>
             </tr>
           ))}
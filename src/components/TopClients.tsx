@@ -1,31 +1,50 @@
-import { faker } from '@faker-js/faker';
-import Image from 'next/image';
+import React from 'react';
+
+interface TopCustomer {
+  id: number;
+  firstname: string;
+  lastname: string;
+  email: string;
+  closed_value: number;
+  deals_closed: number;
+}
 
 export default function TopClients() {
+  const [data, setData] = React.useState<TopCustomer[]>([]);
+
+  React.useEffect(() => {
+    const fetchData = async () => {
+      const url = `//${window.location.host}/api/dashboard/top-customers`;
+
+      try {
+        const res = await fetch(url, { mode: 'cors' });
+
+        if (res.ok) {
+          setData(await res.json());
+        }
+      } catch (e: any) {
+        console.log(`Error: ${e}`);
+      }
+    };
+    fetchData();
+  }, []);
+
   return (
     <div className="rounded-md shadow-md p-6 flex flex-col gap-4 w-full items-start bg-white">
       <h2 className="text-2xl font-bold leading-tight my-2">Top Clients</h2>
       <div className="justify-center items-center w-full">
-        {new Array(7).fill(0).map((_, i) => (
-          <div key={i} className="px-2 py-4 w-full flex justify-between items-center">
+        {data.map((customer) => (
+          <div key={customer.id} className="px-2 py-4 w-full flex justify-between items-center">
             <div className="flex gap-4 items-center">
-              <Image
-                src={faker.image.people(40, 40, true)}
-                width={40}
-                height={40}
-                alt="Avatar"
-                className="rounded-full"
-              />
-
               <p className="flex flex-col gap-1">
                 <span className="text-slate-900 text-xs">
-                  {faker.name.firstName()} {faker.name.lastName()}
+                  {customer.firstname} {customer.lastname}
                 </span>
-                <span className="text-slate-500 text-xs">{faker.internet.email()}</span>
+                <span className="text-slate-500 text-xs">{customer.email}</span>
               </p>
             </div>
 
-            <p className="text-slate-900 text-md font-semibold">£{faker.finance.amount()}</p>
+            <p className="text-slate-900 text-md font-semibold">£{customer.closed_value}</p>
           </div>
         ))}
       </div>
@@ -76,7 +76,7 @@ export default function DealIndex() {
 
         const data = await res.json();
 
-        setData(data);
+        setData(data.deals);
       } catch (e: any) {
         console.log(`Error: ${e}`);
       }